@@ -0,0 +1,81 @@
+//! Times grouping overlapping travel lines for large designs.
+//! Run with `cargo bench --bench group_lines`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+
+// The application is a binary, so the modules under test are included directly.
+#[allow(unused)]
+#[path = "../src/grid_cell.rs"]
+mod grid_cell;
+
+#[allow(unused)]
+#[path = "../src/svg_output"]
+mod svg_output {
+    pub mod line_segment;
+    pub mod line_segment_tree;
+}
+
+use grid_cell::GridCell;
+use svg_output::line_segment::LineSegment;
+use svg_output::line_segment_tree::group_lines;
+
+/// Travel lines from a fixed seed, each up to `max_step` cells from a start in a square.
+fn random_segments(count: usize, size: isize, max_step: isize) -> Vec<LineSegment> {
+    let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut next = |limit: isize| {
+        seed = seed
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        ((seed >> 33) % limit as u64) as isize
+    };
+    (0..count)
+        .map(|i| {
+            let start = GridCell::new(next(size), next(size));
+            let end = GridCell::new(
+                start.x + next(2 * max_step + 1) - max_step,
+                start.y + next(2 * max_step + 1) - max_step,
+            );
+            LineSegment::new(start, end, i)
+        })
+        .collect()
+}
+
+/// A long travel line along each row, then the short lines along it in a jumbled order,
+/// so that every row has one root with a great many children.
+fn nested_segments(count: usize, row_length: isize) -> Vec<LineSegment> {
+    let rows = count as isize / row_length;
+    (0..rows)
+        .flat_map(|y| {
+            let long = (GridCell::new(0, y), GridCell::new(row_length, y));
+            // Stepping by a prime visits every column once, out of order.
+            let short = (0..row_length - 1).map(move |i| {
+                let x = i * 7919 % row_length;
+                (GridCell::new(x, y), GridCell::new(x + 1, y))
+            });
+            [long].into_iter().chain(short)
+        })
+        .enumerate()
+        .map(|(i, (start, end))| LineSegment::new(start, end, i))
+        .collect()
+}
+
+fn bench_group_lines(c: &mut Criterion) {
+    let mut group = c.benchmark_group("group_lines");
+    group.sample_size(10);
+    for count in [25_000, 100_000, 300_000] {
+        // Scatter the lines over a square that grows with the design.
+        let size = ((count / 3) as f64).sqrt() as isize;
+        let scattered = random_segments(count, size, 4);
+        group.bench_with_input(BenchmarkId::new("scattered", count), &scattered, |b, s| {
+            b.iter_batched(|| s.clone(), group_lines, BatchSize::LargeInput)
+        });
+        let nested = nested_segments(count, 1000);
+        group.bench_with_input(BenchmarkId::new("nested", count), &nested, |b, s| {
+            b.iter_batched(|| s.clone(), group_lines, BatchSize::LargeInput)
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_group_lines);
+criterion_main!(benches);
@@ -0,0 +1,446 @@
+use crate::cost_model::IncrementalCost;
+use crate::grid_cell::GridCell;
+use crate::parking::{colour_of, next_of_colour};
+use crate::regions::{connected_components, travel, Connectivity};
+use crate::stitch::{HalfStitch, RestartPolicy};
+use itertools::Itertools;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fmt::Formatter;
+
+/// Islands of up to this many cells are reported as small.
+pub const MAX_SMALL_ISLAND_CELLS: usize = 4;
+
+/// A group of selected cells of one colour that touch each other, and no other cells of that colour.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Island {
+    pub cells: Vec<GridCell>,
+
+    /// The travel on the back charged to the island, in cells.
+    /// Travel within the island is all its own, and travel to or from another island is shared.
+    /// Restarts are charged at the policy's penalty.
+    pub travel: f64,
+}
+
+impl Island {
+    pub fn is_isolated(&self) -> bool {
+        self.cells.len() == 1
+    }
+}
+
+/// The isolated cells and small islands in a design, and how much of the travel they cause.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConfettiReport {
+    /// The islands of at most `MAX_SMALL_ISLAND_CELLS`, the most costly first.
+    pub islands: Vec<Island>,
+
+    /// The travel of the whole sequence, in cells.
+    pub total_travel: f64,
+}
+
+impl ConfettiReport {
+    /// Find the islands of the stitches, charging them the travel of each colour's parked thread.
+    pub fn analyse(
+        stitches: &[HalfStitch],
+        colours: &HashMap<GridCell, usize>,
+        restart_policy: Option<RestartPolicy>,
+    ) -> Self {
+        let cells_by_colour = stitches
+            .iter()
+            .map(|stitch| (colour_of(colours, stitch.cell()), stitch.cell()))
+            .into_group_map();
+        // A colour's cells that share a corner share a hole its thread can pass through.
+        let components = cells_by_colour
+            .into_iter()
+            .sorted_by_key(|(colour, _)| *colour)
+            .flat_map(|(_, cells)| {
+                connected_components(&cells.into_iter().collect(), Connectivity::Eight)
+            })
+            .collect_vec();
+        let component_of: HashMap<GridCell, usize> = components
+            .iter()
+            .enumerate()
+            .flat_map(|(i, component)| component.iter().map(move |cell| (*cell, i)))
+            .collect();
+
+        let mut charged = vec![0.0; components.len()];
+        let mut total_travel = 0.0;
+        for (i, next) in next_of_colour(stitches, colours).into_iter().enumerate() {
+            let Some(j) = next else {
+                continue;
+            };
+            let (first, second) = (stitches[i], stitches[j]);
+            let distance = travel(first.get_end_location(), second.start, restart_policy);
+            total_travel += distance;
+            let (first, second) = (component_of[&first.cell()], component_of[&second.cell()]);
+            if first == second {
+                charged[first] += distance;
+            } else {
+                charged[first] += distance / 2.0;
+                charged[second] += distance / 2.0;
+            }
+        }
+
+        let islands = components
+            .into_iter()
+            .zip(charged)
+            .filter(|(cells, _)| cells.len() <= MAX_SMALL_ISLAND_CELLS)
+            .map(|(cells, travel)| Island { cells, travel })
+            .sorted_by(|a, b| b.travel.total_cmp(&a.travel))
+            .collect();
+        Self {
+            islands,
+            total_travel,
+        }
+    }
+
+    pub fn isolated_cells(&self) -> usize {
+        self.islands
+            .iter()
+            .filter(|island| island.is_isolated())
+            .count()
+    }
+
+    /// The number of islands with more than one cell.
+    pub fn small_islands(&self) -> usize {
+        self.islands.len() - self.isolated_cells()
+    }
+
+    /// The fraction of all travel that is charged to isolated cells and small islands.
+    pub fn travel_share(&self) -> f64 {
+        if self.islands.is_empty() || self.total_travel == 0.0 {
+            return 0.0;
+        }
+        self.islands.iter().map(|island| island.travel).sum::<f64>() / self.total_travel
+    }
+
+    /// Every cell in an isolated cell or small island.
+    pub fn cells(&self) -> impl Iterator<Item = &GridCell> {
+        self.islands.iter().flat_map(|island| island.cells.iter())
+    }
+}
+
+impl fmt::Display for ConfettiReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write_summary(
+            f,
+            self.isolated_cells(),
+            self.small_islands(),
+            self.travel_share(),
+        )
+    }
+}
+
+fn write_summary(f: &mut Formatter<'_>, isolated: usize, small: usize, share: f64) -> fmt::Result {
+    write!(
+        f,
+        "Confetti: {} isolated cell{}, {} small island{}, {:.0}% of travel",
+        isolated,
+        if isolated == 1 { "" } else { "s" },
+        small,
+        if small == 1 { "" } else { "s" },
+        share * 100.0
+    )
+}
+
+/// The isolated cells and small islands of the selection, kept up to date as cells change.
+/// A change only finds the islands again around the changed cell and the stitches either side
+/// of it on its thread, so each click stays local however large the design is.
+#[derive(Debug, Clone, Default)]
+pub struct ConfettiTracker {
+    /// The small islands, by their first cell.
+    islands: HashMap<GridCell, Island>,
+
+    /// The first cell of the island each cell is in.
+    island_of: HashMap<GridCell, GridCell>,
+    isolated: usize,
+
+    /// The travel charged to the islands, and the travel of the whole sequence.
+    island_travel: f64,
+    total_travel: f64,
+}
+
+impl ConfettiTracker {
+    /// Find every island of the stitches in the model.
+    pub fn new(model: &IncrementalCost, restart_policy: Option<RestartPolicy>) -> Self {
+        let mut tracker = ConfettiTracker::default();
+        let cells = model.stitches().map(|stitch| stitch.cell()).collect_vec();
+        tracker.refresh(model, cells, restart_policy);
+        tracker
+    }
+
+    /// Find the islands again around the given cells, after they or the travel to them have changed.
+    pub fn refresh(
+        &mut self,
+        model: &IncrementalCost,
+        cells: impl IntoIterator<Item = GridCell>,
+        restart_policy: Option<RestartPolicy>,
+    ) {
+        let mut seeds: HashSet<GridCell> = cells
+            .into_iter()
+            .flat_map(|cell| cell.neighbours().chain([cell]).collect_vec())
+            .collect();
+        // The rest of an island touching the changed cells may still be an island on its own.
+        let stale = seeds
+            .iter()
+            .filter_map(|seed| self.island_of.get(seed).copied())
+            .collect::<HashSet<_>>();
+        for first in stale {
+            if let Some(island) = self.remove_island(first) {
+                seeds.extend(island.cells);
+            }
+        }
+        for seed in seeds {
+            if self.island_of.contains_key(&seed) {
+                continue;
+            }
+            if let Some(cells) = small_island_at(model, seed) {
+                let travel = island_travel(model, &cells, restart_policy);
+                self.add_island(Island { cells, travel });
+            }
+        }
+        self.total_travel = model.colour_travel().values().sum();
+    }
+
+    /// The cells joined by travel to the stitches in a cell, which is where islands can change
+    /// when it is selected or unselected.
+    pub fn cells_around(model: &IncrementalCost, cell: GridCell) -> Vec<GridCell> {
+        model
+            .travel_at(cell)
+            .into_iter()
+            .flat_map(|((_, first), (_, second))| [first.cell(), second.cell()])
+            .chain([cell])
+            .collect()
+    }
+
+    fn add_island(&mut self, island: Island) {
+        let first = island.cells[0];
+        self.island_of
+            .extend(island.cells.iter().map(|cell| (*cell, first)));
+        self.isolated += usize::from(island.is_isolated());
+        self.island_travel += island.travel;
+        self.islands.insert(first, island);
+    }
+
+    fn remove_island(&mut self, first: GridCell) -> Option<Island> {
+        let island = self.islands.remove(&first)?;
+        for cell in &island.cells {
+            self.island_of.remove(cell);
+        }
+        self.isolated -= usize::from(island.is_isolated());
+        self.island_travel -= island.travel;
+        // Avoid rounding errors building up once there are no islands left.
+        if self.islands.is_empty() {
+            self.island_travel = 0.0;
+        }
+        Some(island)
+    }
+
+    pub fn isolated_cells(&self) -> usize {
+        self.isolated
+    }
+
+    /// The number of islands with more than one cell.
+    pub fn small_islands(&self) -> usize {
+        self.islands.len() - self.isolated
+    }
+
+    /// The fraction of all travel that is charged to isolated cells and small islands.
+    pub fn travel_share(&self) -> f64 {
+        if self.islands.is_empty() || self.total_travel == 0.0 {
+            return 0.0;
+        }
+        self.island_travel / self.total_travel
+    }
+
+    /// Every cell in an isolated cell or small island.
+    pub fn cells(&self) -> impl Iterator<Item = &GridCell> {
+        self.island_of.keys()
+    }
+}
+
+impl fmt::Display for ConfettiTracker {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write_summary(
+            f,
+            self.isolated_cells(),
+            self.small_islands(),
+            self.travel_share(),
+        )
+    }
+}
+
+/// The sorted cells of the island of its colour containing the cell, if it is selected
+/// and the island has no more than `MAX_SMALL_ISLAND_CELLS`.
+/// The search stops as soon as the island is too big, so it doesn't walk large areas.
+fn small_island_at(model: &IncrementalCost, start: GridCell) -> Option<Vec<GridCell>> {
+    let colour = model.colour_at(start)?;
+    let is_selected = |cell: GridCell| model.colour_at(cell) == Some(colour);
+    let mut island = vec![start];
+    let mut to_visit = vec![start];
+    while let Some(cell) = to_visit.pop() {
+        for neighbour in cell.neighbours() {
+            if !island.contains(&neighbour) && is_selected(neighbour) {
+                if island.len() == MAX_SMALL_ISLAND_CELLS {
+                    return None;
+                }
+                island.push(neighbour);
+                to_visit.push(neighbour);
+            }
+        }
+    }
+    island.sort_by_key(|cell| (cell.y, cell.x));
+    Some(island)
+}
+
+/// The travel charged to the island: all of the travel within it,
+/// and half of the travel to or from anywhere else.
+fn island_travel(
+    model: &IncrementalCost,
+    cells: &[GridCell],
+    restart_policy: Option<RestartPolicy>,
+) -> f64 {
+    let mut seen = HashSet::new();
+    let mut total = 0.0;
+    for cell in cells {
+        for ((first_key, first), (second_key, second)) in model.travel_at(*cell) {
+            if !seen.insert((first_key, second_key)) {
+                continue;
+            }
+            let distance = travel(first.get_end_location(), second.start, restart_policy);
+            let both_inside = cells.contains(&first.cell()) && cells.contains(&second.cell());
+            total += if both_inside {
+                distance
+            } else {
+                distance / 2.0
+            };
+        }
+    }
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stitch::StartingStitchCorner;
+
+    fn _stitches(cells: &[(isize, isize)]) -> Vec<HalfStitch> {
+        let cells = cells
+            .iter()
+            .map(|&(x, y)| GridCell::new(x, y))
+            .collect::<Vec<_>>();
+        HalfStitch::convert_grid_cells(
+            cells.iter(),
+            StartingStitchCorner::BottomLeft,
+            StartingStitchCorner::BottomRight,
+        )
+    }
+
+    #[test]
+    fn test_large_areas_are_not_confetti() {
+        let cells = (0..3).cartesian_product(0..3).collect_vec();
+        let report = ConfettiReport::analyse(&_stitches(&cells), &HashMap::new(), None);
+        assert!(report.islands.is_empty());
+        assert_eq!(report.travel_share(), 0.0);
+        assert_eq!(
+            report.to_string(),
+            "Confetti: 0 isolated cells, 0 small islands, 0% of travel"
+        );
+    }
+
+    #[test]
+    fn test_travel_to_an_isolated_cell_is_shared() {
+        // A row of three cells, and a single cell nine cells to its right.
+        let stitches = _stitches(&[(0, 0), (1, 0), (2, 0), (12, 0)]);
+        let report = ConfettiReport::analyse(&stitches, &HashMap::new(), None);
+        assert_eq!(report.isolated_cells(), 1);
+        assert_eq!(report.small_islands(), 1);
+        assert_eq!(report.islands[1].cells, [GridCell::new(12, 0)]);
+
+        // Each stitch in the row travels one hole down to the next,
+        // and the jump to the isolated cell is split between the two islands.
+        let jump = 82.0_f64.sqrt();
+        assert!((report.total_travel - (2.0 + jump)).abs() < 1e-9);
+        assert!((report.islands[0].travel - (2.0 + jump / 2.0)).abs() < 1e-9);
+        assert!((report.islands[1].travel - jump / 2.0).abs() < 1e-9);
+        assert!((report.travel_share() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_restarts_are_charged_at_the_penalty() {
+        let stitches = _stitches(&[(0, 0), (20, 0)]);
+        let report =
+            ConfettiReport::analyse(&stitches, &HashMap::new(), Some(RestartPolicy::default()));
+        assert_eq!(report.isolated_cells(), 2);
+        assert_eq!(report.total_travel, 5.0);
+        assert!(report.islands.iter().all(|island| island.travel == 2.5));
+        assert_eq!(
+            report.to_string(),
+            "Confetti: 2 isolated cells, 0 small islands, 100% of travel"
+        );
+    }
+
+    fn _assert_tracker_matches_helper(
+        tracker: &ConfettiTracker,
+        model: &IncrementalCost,
+        colours: &HashMap<GridCell, usize>,
+    ) {
+        let stitches = model.stitches().copied().collect_vec();
+        let report = ConfettiReport::analyse(&stitches, colours, None);
+        assert_eq!(tracker.isolated_cells(), report.isolated_cells());
+        assert_eq!(tracker.small_islands(), report.small_islands());
+        assert_eq!(
+            tracker.cells().collect::<HashSet<_>>(),
+            report.cells().collect::<HashSet<_>>()
+        );
+        assert!((tracker.travel_share() - report.travel_share()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_tracker_follows_selections() {
+        let cells = [(0, 0), (1, 0), (2, 0), (12, 0), (13, 1), (5, 5), (20, 0)];
+        let stitches = _stitches(&cells);
+        // The last two cells are stitched in a second colour.
+        let colours: HashMap<GridCell, usize> =
+            [(GridCell::new(5, 5), 1), (GridCell::new(20, 0), 1)]
+                .into_iter()
+                .collect();
+        let mut model = IncrementalCost::new([], &colours, None);
+        let mut tracker = ConfettiTracker::new(&model, None);
+        for (key, stitch) in stitches.iter().enumerate() {
+            model.insert(key, *stitch, colour_of(&colours, stitch.cell()));
+            tracker.refresh(
+                &model,
+                ConfettiTracker::cells_around(&model, stitch.cell()),
+                None,
+            );
+            _assert_tracker_matches_helper(&tracker, &model, &colours);
+        }
+        // Taking a cell out of the row leaves two isolated cells behind.
+        for key in [1, 3, 6] {
+            let around = ConfettiTracker::cells_around(&model, stitches[key].cell());
+            model.remove(key);
+            tracker.refresh(&model, around, None);
+            _assert_tracker_matches_helper(&tracker, &model, &colours);
+        }
+    }
+
+    #[test]
+    fn test_a_cell_surrounded_by_another_colour_is_isolated() {
+        let cells = (0..3).cartesian_product(0..3).collect_vec();
+        let stitches = _stitches(&cells);
+        let centre = GridCell::new(1, 1);
+        let colours: HashMap<GridCell, usize> = [(centre, 2)].into_iter().collect();
+
+        let report = ConfettiReport::analyse(&stitches, &colours, None);
+        assert_eq!(report.isolated_cells(), 1);
+        assert_eq!(report.small_islands(), 0);
+        assert_eq!(report.cells().collect_vec(), [&centre]);
+
+        let keyed = stitches.iter().copied().enumerate();
+        let model = IncrementalCost::new(keyed, &colours, None);
+        let tracker = ConfettiTracker::new(&model, None);
+        assert_eq!(tracker.cells().collect_vec(), [&centre]);
+        _assert_tracker_matches_helper(&tracker, &model, &colours);
+    }
+}
@@ -0,0 +1,188 @@
+use crate::grid_cell::GridCell;
+use crate::parking::stitches_by_colour;
+use crate::regions::travel;
+use crate::stitch::{HalfStitch, RestartPolicy};
+use itertools::Itertools;
+use std::collections::{HashMap, HashSet};
+
+/// Choose which way along its diagonal each leg is stitched, keeping the order of the stitches,
+/// so that the travel of each colour's parked thread is as short as possible.
+/// Going back along a row the other way, as in the Danish method, comes out of this.
+/// With `consistent_top_legs`, the top legs of crosses keep the direction they have.
+pub fn optimise_corners(
+    stitches: &[HalfStitch],
+    colours: &HashMap<GridCell, usize>,
+    consistent_top_legs: bool,
+    restart_policy: Option<RestartPolicy>,
+) -> Vec<HalfStitch> {
+    let mut chosen = stitches.to_vec();
+    // Each colour's thread only travels between its own stitches, so each is optimised alone.
+    for (positions, thread) in stitches_by_colour(stitches, colours).values() {
+        let optimised = optimise_thread(thread, consistent_top_legs, restart_policy);
+        for (&i, stitch) in positions.iter().zip(optimised) {
+            chosen[i] = stitch;
+        }
+    }
+    chosen
+}
+
+/// Choose the corners for the stitches sewn one after another by a single thread.
+fn optimise_thread(
+    stitches: &[HalfStitch],
+    consistent_top_legs: bool,
+    restart_policy: Option<RestartPolicy>,
+) -> Vec<HalfStitch> {
+    let mut seen_cells = HashSet::new();
+    let options = stitches
+        .iter()
+        .map(|stitch| {
+            let top_leg = !seen_cells.insert(stitch.cell());
+            let flipped = HalfStitch::new_in_cell(
+                stitch.cell(),
+                stitch.stitch_corner.get_opposite_corner(),
+                stitch.order,
+            );
+            match top_leg && consistent_top_legs {
+                true => vec![*stitch],
+                false => vec![*stitch, flipped],
+            }
+        })
+        .collect_vec();
+
+    // The cheapest travel up to each way of stitching each leg, and the way the leg before went.
+    let mut best: Vec<Vec<(f64, usize)>> = Vec::with_capacity(options.len());
+    for (i, choices) in options.iter().enumerate() {
+        let costs = choices
+            .iter()
+            .map(|stitch| match i {
+                0 => (0.0, 0),
+                _ => options[i - 1]
+                    .iter()
+                    .zip(&best[i - 1])
+                    .enumerate()
+                    .map(|(u, (previous, (cost, _)))| {
+                        let (end, start) = (previous.get_end_location(), stitch.start);
+                        // Coming straight back up through the same hole isn't allowed.
+                        let step = match end == start {
+                            true => f64::INFINITY,
+                            false => travel(end, start, restart_policy),
+                        };
+                        (cost + step, u)
+                    })
+                    .min_by(|a, b| a.0.total_cmp(&b.0))
+                    .unwrap(),
+            })
+            .collect();
+        best.push(costs);
+    }
+
+    let Some(last) = best.last() else {
+        return vec![];
+    };
+    let mut v = (0..last.len())
+        .min_by(|&a, &b| last[a].0.total_cmp(&last[b].0))
+        .unwrap();
+    let mut chosen = vec![HalfStitch::default(); stitches.len()];
+    for i in (0..stitches.len()).rev() {
+        chosen[i] = options[i][v];
+        v = best[i][v].1;
+    }
+    chosen
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parking::parked_cost;
+    use crate::stitch::StartingStitchCorner;
+
+    fn _stitches(columns: &[isize]) -> Vec<HalfStitch> {
+        let cells = columns.iter().map(|&x| GridCell::new(x, 0)).collect_vec();
+        HalfStitch::convert_grid_cells(
+            cells.iter(),
+            StartingStitchCorner::BottomLeft,
+            StartingStitchCorner::BottomRight,
+        )
+    }
+
+    #[test]
+    fn test_legs_going_back_are_flipped() {
+        let stitches = _stitches(&[3, 2, 1, 0]);
+        let optimised = optimise_corners(&stitches, &HashMap::new(), false, None);
+        // Every leg starts a hole away from where the last went down, instead of two across.
+        assert_eq!(HalfStitch::calculate_cost_float(&optimised), 3.0);
+        assert!(HalfStitch::calculate_cost_float(&stitches) > 6.0);
+        // The stitches stay in the same cells, on the same diagonals, and in the same order.
+        assert!(optimised.iter().zip(&stitches).all(|(a, b)| {
+            a.cell() == b.cell()
+                && a.order == b.order
+                && [b.stitch_corner, b.stitch_corner.get_opposite_corner()]
+                    .contains(&a.stitch_corner)
+        }));
+    }
+
+    #[test]
+    fn test_consistent_top_legs_are_kept() {
+        let stitches = _stitches(&[3, 3, 2, 2, 1, 1, 0, 0]);
+        let consistent = optimise_corners(&stitches, &HashMap::new(), true, None);
+        assert!(consistent
+            .iter()
+            .skip(1)
+            .step_by(2)
+            .all(|stitch| stitch.stitch_corner == StartingStitchCorner::BottomRight));
+        assert!(HalfStitch::is_valid_sequence(&consistent));
+
+        let free = optimise_corners(&stitches, &HashMap::new(), false, None);
+        assert!(HalfStitch::is_valid_sequence(&free));
+        assert!(
+            HalfStitch::calculate_cost_float(&free)
+                <= HalfStitch::calculate_cost_float(&consistent)
+        );
+        assert!(
+            HalfStitch::calculate_cost_float(&consistent)
+                <= HalfStitch::calculate_cost_float(&stitches)
+        );
+    }
+
+    #[test]
+    fn test_empty_sequence() {
+        assert!(optimise_corners(&[], &HashMap::new(), true, None).is_empty());
+    }
+
+    #[test]
+    fn test_parked_cost_never_goes_up_with_colours() {
+        // Two colours taking turns along a row, then coming back along the row below.
+        let columns = (0..8).chain((0..8).rev()).collect_vec();
+        let cells = columns
+            .iter()
+            .enumerate()
+            .flat_map(|(i, &x)| {
+                let cell = GridCell::new(x, -((i / 8) as isize));
+                [cell, cell]
+            })
+            .collect_vec();
+        let stitches = HalfStitch::convert_grid_cells(
+            cells.iter(),
+            StartingStitchCorner::BottomLeft,
+            StartingStitchCorner::BottomRight,
+        );
+        let colours = cells
+            .iter()
+            .map(|cell| (*cell, 1 + (cell.x % 2) as usize))
+            .collect();
+        for policy in [None, Some(RestartPolicy::default())] {
+            let before = parked_cost(&stitches, &colours, policy).unwrap().float;
+            for consistent_top_legs in [true, false] {
+                let optimised = optimise_corners(&stitches, &colours, consistent_top_legs, policy);
+                let after = parked_cost(&optimised, &colours, policy).unwrap().float;
+                assert!(after <= before + 1e-9);
+            }
+        }
+        // Going back along the lower row, each colour's legs are turned to meet its own thread.
+        let optimised = optimise_corners(&stitches, &colours, false, None);
+        assert!(
+            parked_cost(&optimised, &colours, None).unwrap().float
+                < parked_cost(&stitches, &colours, None).unwrap().float
+        );
+    }
+}
@@ -0,0 +1,539 @@
+use crate::grid_cell::GridCell;
+use crate::parking::colour_of;
+use crate::spatial_index::SpatialIndex;
+use crate::stitch::{HalfStitch, RestartPolicy, SequenceCost};
+use crate::symbolic_sum::SymbolicSum;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::RangeInclusive;
+
+/// The running totals for the thread of one colour.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ColourTotals {
+    pub stitches: usize,
+
+    /// Distance travelled on the back, not counting travel replaced by restarts.
+    pub travel: f64,
+
+    /// Number of times the thread is cut and restarted.
+    pub restarts: usize,
+}
+
+/// The stitches for the selected cells, along with the running cost of stitching them.
+/// The stitches are kept in order by the key they were selected with,
+/// so selecting or unselecting a cell only looks up its neighbours in the sequence
+/// and updates the travel either side of the changed stitch.
+/// This keeps each change to O(log n), so large designs stay interactive.
+/// Each colour is stitched with its own thread, which is parked while the other colours are stitched,
+/// so the neighbours of a stitch are the ones before and after it in the same colour.
+#[derive(Debug, Clone, Default)]
+pub struct IncrementalCost {
+    /// Every stitch with its colour.
+    stitches: BTreeMap<usize, (HalfStitch, usize)>,
+
+    /// The keys of the stitches in each colour.
+    threads: BTreeMap<usize, BTreeSet<usize>>,
+
+    /// The same stitches and their keys, looked up by the cell they cross.
+    index: SpatialIndex<(usize, HalfStitch)>,
+
+    /// The holes where each thread starts and ends, looked up by the cell of their stitch.
+    thread_starts: SpatialIndex<GridCell>,
+    thread_ends: SpatialIndex<GridCell>,
+    restart_policy: Option<RestartPolicy>,
+
+    /// Distance travelled on the back, not counting travel replaced by restarts.
+    travel_float: f64,
+    travel_symbolic: SymbolicSum,
+
+    /// Number of times a thread is cut and restarted.
+    restarts: usize,
+
+    /// The totals for each colour in use.
+    colour_totals: BTreeMap<usize, ColourTotals>,
+
+    /// The colour and keys of consecutive pairs of stitches in that colour
+    /// where the second starts in the hole the first ended in.
+    invalid_pairs: BTreeSet<(usize, usize, usize)>,
+}
+
+impl IncrementalCost {
+    /// Build the model for the given stitches and their keys from scratch.
+    pub fn new(
+        stitches: impl IntoIterator<Item = (usize, HalfStitch)>,
+        colours: &HashMap<GridCell, usize>,
+        restart_policy: Option<RestartPolicy>,
+    ) -> Self {
+        let mut model = IncrementalCost {
+            restart_policy,
+            ..Default::default()
+        };
+        for (key, stitch) in stitches {
+            model.insert(key, stitch, colour_of(colours, stitch.cell()));
+        }
+        model
+    }
+
+    /// The stitches in the order they are stitched.
+    pub fn stitches(&self) -> impl DoubleEndedIterator<Item = &HalfStitch> + ExactSizeIterator {
+        self.stitches.values().map(|(stitch, _)| stitch)
+    }
+
+    /// The stitches crossing any cell in the given columns and rows.
+    pub fn stitches_in(
+        &self,
+        columns: RangeInclusive<isize>,
+        rows: RangeInclusive<isize>,
+    ) -> impl Iterator<Item = &HalfStitch> {
+        self.index
+            .query(columns, rows)
+            .map(|(_, (_, stitch))| stitch)
+    }
+
+    /// The colour of the stitches in the cell, if any are selected.
+    pub fn colour_at(&self, cell: GridCell) -> Option<usize> {
+        self.index
+            .query(cell.x..=cell.x, cell.y..=cell.y)
+            .map(|(_, (key, _))| self.stitches[key].1)
+            .next()
+    }
+
+    /// The holes where a thread starts, for stitches in the given columns and rows.
+    pub fn thread_starts_in(
+        &self,
+        columns: RangeInclusive<isize>,
+        rows: RangeInclusive<isize>,
+    ) -> impl Iterator<Item = &GridCell> {
+        self.thread_starts
+            .query(columns, rows)
+            .map(|(_, hole)| hole)
+    }
+
+    /// The holes where a thread ends, for stitches in the given columns and rows.
+    pub fn thread_ends_in(
+        &self,
+        columns: RangeInclusive<isize>,
+        rows: RangeInclusive<isize>,
+    ) -> impl Iterator<Item = &GridCell> {
+        self.thread_ends.query(columns, rows).map(|(_, hole)| hole)
+    }
+
+    /// The totals for each colour in use.
+    pub fn colour_totals(&self) -> &BTreeMap<usize, ColourTotals> {
+        &self.colour_totals
+    }
+
+    /// The travel of each colour's thread from where it was parked, as in `parked_travel`,
+    /// where each restart costs its penalty.
+    pub fn colour_travel(&self) -> BTreeMap<usize, f64> {
+        let penalty = self
+            .restart_policy
+            .map_or(0, |policy| policy.restart_penalty);
+        self.colour_totals
+            .iter()
+            .map(|(colour, totals)| (*colour, totals.travel + (penalty * totals.restarts) as f64))
+            .collect()
+    }
+
+    /// Add a stitch in the given colour at the place in the sequence given by its key.
+    pub fn insert(&mut self, key: usize, stitch: HalfStitch, colour: usize) {
+        // The stitch starts and ends a thread of its own until it is joined to its neighbours.
+        self.thread_starts.insert(stitch.cell(), stitch.start);
+        self.thread_ends
+            .insert(stitch.cell(), stitch.get_end_location());
+        let previous = self.previous(key, colour);
+        let next = self.next(key, colour);
+        if let (Some(previous), Some(next)) = (previous, next) {
+            self.change_travel(previous, next, colour, -1);
+        }
+        if let Some(previous) = previous {
+            self.change_travel(previous, (key, stitch), colour, 1);
+        }
+        if let Some(next) = next {
+            self.change_travel((key, stitch), next, colour, 1);
+        }
+        self.stitches.insert(key, (stitch, colour));
+        self.threads.entry(colour).or_default().insert(key);
+        self.colour_totals.entry(colour).or_default().stitches += 1;
+        self.index.insert(stitch.cell(), (key, stitch));
+    }
+
+    /// Remove the stitch with the given key from the sequence.
+    pub fn remove(&mut self, key: usize) {
+        let Some((stitch, colour)) = self.stitches.remove(&key) else {
+            return;
+        };
+        let thread = self.threads.get_mut(&colour).unwrap();
+        thread.remove(&key);
+        let thread_len = thread.len();
+        if thread_len == 0 {
+            self.threads.remove(&colour);
+        }
+        let previous = self.previous(key, colour);
+        let next = self.next(key, colour);
+        if let Some(previous) = previous {
+            self.change_travel(previous, (key, stitch), colour, -1);
+        }
+        if let Some(next) = next {
+            self.change_travel((key, stitch), next, colour, -1);
+        }
+        if let (Some(previous), Some(next)) = (previous, next) {
+            self.change_travel(previous, next, colour, 1);
+        }
+        self.index.remove(stitch.cell(), &(key, stitch));
+        self.thread_starts.remove(stitch.cell(), &stitch.start);
+        self.thread_ends
+            .remove(stitch.cell(), &stitch.get_end_location());
+
+        // Avoid rounding errors building up once there is nothing left to travel.
+        match thread_len {
+            0 => {
+                self.colour_totals.remove(&colour);
+            }
+            _ => {
+                let totals = self.colour_totals.get_mut(&colour).unwrap();
+                totals.stitches -= 1;
+                if thread_len == 1 {
+                    totals.travel = 0.0;
+                }
+            }
+        }
+        if self.stitches.len() < 2 {
+            self.travel_float = 0.0;
+        }
+    }
+
+    /// The travel to and from the stitches in the cell, as the pairs of consecutive stitches
+    /// on their threads, each with its key. This includes travel replaced by a restart.
+    pub fn travel_at(&self, cell: GridCell) -> Vec<((usize, HalfStitch), (usize, HalfStitch))> {
+        let mut pairs = Vec::new();
+        for &(_, (key, stitch)) in self.index.query(cell.x..=cell.x, cell.y..=cell.y) {
+            let colour = self.stitches[&key].1;
+            pairs.extend(
+                self.previous(key, colour)
+                    .map(|previous| (previous, (key, stitch))),
+            );
+            pairs.extend(self.next(key, colour).map(|next| ((key, stitch), next)));
+        }
+        pairs
+    }
+
+    /// The stitch in the colour before the given key in the sequence, with its key.
+    fn previous(&self, key: usize, colour: usize) -> Option<(usize, HalfStitch)> {
+        let previous = *self.threads.get(&colour)?.range(..key).next_back()?;
+        Some((previous, self.stitches[&previous].0))
+    }
+
+    /// The stitch in the colour after the given key in the sequence, with its key.
+    fn next(&self, key: usize, colour: usize) -> Option<(usize, HalfStitch)> {
+        let next = *self.threads.get(&colour)?.range(key + 1..).next()?;
+        Some((next, self.stitches[&next].0))
+    }
+
+    /// The cost of the sequence, or the first pair of cells that make it invalid.
+    pub fn cost(&self) -> Result<SequenceCost, (GridCell, GridCell)> {
+        if let Some((_, first, second)) = self.invalid_pairs.first() {
+            return Err((
+                self.stitches[first].0.cell(),
+                self.stitches[second].0.cell(),
+            ));
+        }
+        let penalty = self
+            .restart_policy
+            .map_or(0, |policy| policy.restart_penalty * self.restarts);
+        let mut symbolic = self.travel_symbolic.clone();
+        symbolic.add_constant(penalty);
+        Ok(SequenceCost {
+            float: self.travel_float + penalty as f64,
+            symbolic,
+        })
+    }
+
+    /// Add (when the sign is positive) or remove the travel between two consecutive stitches
+    /// in the same colour. Travel joins the two stitches into one thread, so the thread no longer
+    /// ends at the first or starts at the second.
+    fn change_travel(
+        &mut self,
+        (first_key, first): (usize, HalfStitch),
+        (second_key, second): (usize, HalfStitch),
+        colour: usize,
+        sign: isize,
+    ) {
+        let (from, to) = (first.get_end_location(), second.start);
+        let totals = self.colour_totals.entry(colour).or_default();
+        if from == to {
+            match sign > 0 {
+                true => self.invalid_pairs.insert((colour, first_key, second_key)),
+                false => self.invalid_pairs.remove(&(colour, first_key, second_key)),
+            };
+        }
+        if self
+            .restart_policy
+            .is_some_and(|policy| policy.is_restart(from, to))
+        {
+            self.restarts = self.restarts.wrapping_add_signed(sign);
+            totals.restarts = totals.restarts.wrapping_add_signed(sign);
+        } else {
+            totals.travel += sign as f64 * from.euclidean_distance(&to);
+            self.travel_float += sign as f64 * from.euclidean_distance(&to);
+            self.travel_symbolic =
+                self.travel_symbolic.clone() + SymbolicSum::from_distance(from, to) * sign;
+            if sign > 0 {
+                self.thread_ends.remove(first.cell(), &from);
+                self.thread_starts.remove(second.cell(), &to);
+            } else {
+                self.thread_ends.insert(first.cell(), from);
+                self.thread_starts.insert(second.cell(), to);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parking::{parked_cost, parked_runs, parked_travel, DEFAULT_COLOUR};
+    use crate::stitch::StartingStitchCorner;
+
+    fn _round_float(number: f64) -> f64 {
+        (number * 1000.0).round() / 1000.0
+    }
+
+    fn _stitches(cells: &[GridCell]) -> Vec<HalfStitch> {
+        HalfStitch::convert_grid_cells(
+            cells.iter(),
+            StartingStitchCorner::BottomLeft,
+            StartingStitchCorner::BottomRight,
+        )
+    }
+
+    fn _model(stitches: Vec<HalfStitch>, restart_policy: Option<RestartPolicy>) -> IncrementalCost {
+        IncrementalCost::new(
+            stitches.into_iter().enumerate(),
+            &HashMap::new(),
+            restart_policy,
+        )
+    }
+
+    fn _thread_runs(model: &IncrementalCost) -> usize {
+        model
+            .colour_totals()
+            .values()
+            .map(|totals| totals.restarts + 1)
+            .sum()
+    }
+
+    fn _colours(model: &IncrementalCost) -> HashMap<GridCell, usize> {
+        model
+            .stitches
+            .values()
+            .map(|(stitch, colour)| (stitch.cell(), *colour))
+            .collect()
+    }
+
+    fn _assert_matches_full_calculation(model: &IncrementalCost) {
+        let stitches = model.stitches().copied().collect::<Vec<_>>();
+        let colours = _colours(model);
+        let expected = parked_cost(&stitches, &colours, model.restart_policy);
+        let result = model.cost();
+        assert_eq!(
+            _thread_runs(model),
+            parked_runs(&stitches, &colours, model.restart_policy).len()
+        );
+        assert_eq!(
+            model
+                .colour_totals()
+                .values()
+                .map(|totals| totals.stitches)
+                .sum::<usize>(),
+            stitches.len()
+        );
+        let travel = parked_travel(&stitches, &colours, model.restart_policy);
+        assert_eq!(
+            model.colour_travel().keys().collect::<Vec<_>>(),
+            travel.keys().collect::<Vec<_>>()
+        );
+        for (colour, distance) in travel {
+            assert_eq!(
+                _round_float(model.colour_travel()[&colour]),
+                _round_float(distance)
+            );
+        }
+        match (result, expected) {
+            (Ok(result), Ok(expected)) => {
+                assert_eq!(_round_float(result.float), _round_float(expected.float));
+                assert_eq!(result.symbolic, expected.symbolic);
+            }
+            (result, expected) => assert_eq!(result, expected),
+        }
+    }
+
+    #[test]
+    fn test_push_matches_full_calculation() {
+        let stitches = _stitches(&[
+            GridCell::new(0, 0),
+            GridCell::new(0, 0),
+            GridCell::new(1, 0),
+            GridCell::new(3, 2),
+        ]);
+        let mut model = IncrementalCost::default();
+        for (key, stitch) in stitches.into_iter().enumerate() {
+            model.insert(key, stitch, DEFAULT_COLOUR);
+            _assert_matches_full_calculation(&model);
+        }
+    }
+
+    #[test]
+    fn test_remove_middle_matches_full_calculation() {
+        let stitches = _stitches(&[
+            GridCell::new(0, 0),
+            GridCell::new(0, 0),
+            GridCell::new(1, 0),
+            GridCell::new(3, 2),
+        ]);
+        let mut model = _model(stitches, None);
+        model.remove(2);
+        _assert_matches_full_calculation(&model);
+        model.remove(0);
+        _assert_matches_full_calculation(&model);
+        model.remove(3);
+        _assert_matches_full_calculation(&model);
+        model.remove(1);
+        assert_eq!(model.cost().unwrap(), SequenceCost::default());
+    }
+
+    #[test]
+    fn test_insert_between_matches_full_calculation() {
+        let stitches = _stitches(&[
+            GridCell::new(0, 0),
+            GridCell::new(1, 0),
+            GridCell::new(3, 2),
+        ]);
+        let mut model = IncrementalCost::default();
+        for (key, stitch) in [(0, stitches[0]), (10, stitches[2]), (5, stitches[1])] {
+            model.insert(key, stitch, DEFAULT_COLOUR);
+            _assert_matches_full_calculation(&model);
+        }
+        assert!(model.stitches().copied().eq(stitches));
+    }
+
+    #[test]
+    fn test_invalid_pair_removed() {
+        // The second stitch starts in the hole the first ended in.
+        let stitches = vec![
+            HalfStitch {
+                start: GridCell::new(0, 0),
+                stitch_corner: StartingStitchCorner::BottomLeft,
+                order: 1,
+            },
+            HalfStitch {
+                start: GridCell::new(1, 1),
+                stitch_corner: StartingStitchCorner::TopLeft,
+                order: 3,
+            },
+        ];
+        let mut model = _model(stitches, None);
+        assert!(model.cost().is_err());
+        model.remove(1);
+        assert!(model.cost().is_ok());
+    }
+
+    #[test]
+    fn test_restarts_match_full_calculation() {
+        let stitches = _stitches(&[
+            GridCell::new(0, 0),
+            GridCell::new(10, 0),
+            GridCell::new(11, 0),
+        ]);
+        let mut model = _model(stitches, Some(RestartPolicy::default()));
+        assert_eq!(_thread_runs(&model), 2);
+        _assert_matches_full_calculation(&model);
+        model.remove(1);
+        assert_eq!(_thread_runs(&model), 2);
+        _assert_matches_full_calculation(&model);
+        model.remove(0);
+        assert_eq!(_thread_runs(&model), 1);
+        _assert_matches_full_calculation(&model);
+    }
+
+    fn _assert_thread_markers_match_runs(model: &IncrementalCost) {
+        let stitches = model.stitches().copied().collect::<Vec<_>>();
+        let (mut starts, mut ends): (Vec<GridCell>, Vec<GridCell>) =
+            parked_runs(&stitches, &_colours(model), model.restart_policy)
+                .into_iter()
+                .map(|run| (run[0].start, run[run.len() - 1].get_end_location()))
+                .unzip();
+        let everywhere = || (-100..=100, -100..=100);
+        let mut result_starts = model
+            .thread_starts_in(everywhere().0, everywhere().1)
+            .copied()
+            .collect::<Vec<_>>();
+        let mut result_ends = model
+            .thread_ends_in(everywhere().0, everywhere().1)
+            .copied()
+            .collect::<Vec<_>>();
+        for holes in [&mut starts, &mut ends, &mut result_starts, &mut result_ends] {
+            holes.sort_by_key(|hole| (hole.x, hole.y));
+        }
+        assert_eq!(result_starts, starts);
+        assert_eq!(result_ends, ends);
+    }
+
+    #[test]
+    fn test_thread_markers_follow_changes() {
+        let stitches = _stitches(&[
+            GridCell::new(0, 0),
+            GridCell::new(10, 0),
+            GridCell::new(11, 0),
+            GridCell::new(11, 0),
+        ]);
+        let mut model = _model(stitches, Some(RestartPolicy::default()));
+        _assert_thread_markers_match_runs(&model);
+        for key in [2, 0, 3, 1] {
+            model.remove(key);
+            _assert_thread_markers_match_runs(&model);
+        }
+        model.insert(7, _stitches(&[GridCell::new(4, 4)])[0], DEFAULT_COLOUR);
+        _assert_thread_markers_match_runs(&model);
+    }
+
+    #[test]
+    fn test_colours_are_parked_threads() {
+        // Two colours taking turns, a long way apart, so one thread would be cut at every turn.
+        let cells =
+            [(0, 0), (30, 0), (1, 0), (31, 0), (1, 0), (0, 0)].map(|(x, y)| GridCell::new(x, y));
+        let stitches = _stitches(&cells);
+        let colours = HashMap::from([(GridCell::new(30, 0), 2), (GridCell::new(31, 0), 2)]);
+        let mut model = IncrementalCost::new(
+            stitches.into_iter().enumerate(),
+            &colours,
+            Some(RestartPolicy::default()),
+        );
+        assert_eq!(_thread_runs(&model), 2);
+        assert_eq!(model.colour_travel().len(), 2);
+        _assert_matches_full_calculation(&model);
+        _assert_thread_markers_match_runs(&model);
+        for key in [1, 4, 3, 0] {
+            model.remove(key);
+            _assert_matches_full_calculation(&model);
+            _assert_thread_markers_match_runs(&model);
+        }
+        assert_eq!(model.colour_travel().len(), 1);
+        model.insert(3, _stitches(&[GridCell::new(31, 0)])[0], 2);
+        _assert_matches_full_calculation(&model);
+        _assert_thread_markers_match_runs(&model);
+    }
+
+    #[test]
+    fn test_stitches_in_region_follow_removal() {
+        let stitches = _stitches(&[
+            GridCell::new(0, 0),
+            GridCell::new(0, 0),
+            GridCell::new(50, 50),
+        ]);
+        let mut model = _model(stitches, None);
+        assert_eq!(model.stitches_in(-1..=1, -1..=1).count(), 2);
+        model.remove(0);
+        assert_eq!(model.stitches_in(-1..=1, -1..=1).count(), 1);
+        assert_eq!(model.stitches_in(40..=60, 40..=60).count(), 1);
+    }
+}
@@ -1,21 +1,53 @@
+use crate::confetti::ConfettiTracker;
+use crate::corner_choice::optimise_corners;
+use crate::cost_model::IncrementalCost;
 use crate::grid_cell::GridCell;
-use crate::stitch::{HalfStitch, StartingStitchCorner};
+use crate::minimap::{Minimap, SelectionBounds};
+use crate::parking::{
+    describe_parked_travel, parked_cost, parked_runs, ParkingPlan, DEFAULT_COLOUR,
+};
+use crate::regions::{Connectivity, RegionPlan};
+use crate::stitch::{HalfStitch, Leg, RestartPolicy, SequenceCost, StartingStitchCorner};
+use crate::theme::ColourTheme;
+use crate::thread_usage::{describe_thread_estimates, Fabric, FabricGrid, ThreadEstimate};
 use crate::ProgramState;
 use iced::event::Status;
+use iced::keyboard::key::Named;
 use iced::mouse::Cursor;
 use iced::widget::canvas::{Cache, Event, Frame, Geometry, Path, Stroke, Style, Text};
 use iced::widget::{canvas, Canvas};
 use iced::{
-    alignment, mouse, Color, Element, Fill, Font, Point, Rectangle, Renderer, Size, Theme, Vector,
+    alignment, keyboard, mouse, Color, Element, Fill, Font, Point, Rectangle, Renderer, Size,
+    Theme, Vector,
 };
+use itertools::Itertools;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashSet};
 use std::ops::RangeInclusive;
 
 #[derive(Debug, Clone)]
 pub enum Message {
     Select(GridCell),
     Unselect(GridCell),
+    /// Reverse the direction of the last leg in the cell that can be flipped.
+    FlipLeg(GridCell),
     Translated(Vector),
-    Scaled(f32),
+    /// Change the scaling, keeping the point at the given offset from the centre of the screen
+    /// in the same place, or zooming around the centre if there is no point.
+    Scaled(f32, Option<Vector>),
+    FitSelection,
+    ResetView,
+    MoveCursor(GridCell),
+    Shortcut(Shortcut),
+}
+
+/// Keyboard shortcuts for actions that belong to the rest of the application.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shortcut {
+    Clear,
+    Export,
+    CycleBottomStitchCorner,
+    CycleTopStitchCorner,
 }
 
 #[derive(Debug)]
@@ -26,11 +58,23 @@ pub struct GridState {
     /// Cache for the selected cells and stitch markings.
     cell_cache: Cache,
 
+    /// Cache for the selected cells in the minimap.
+    minimap_cache: Cache,
+
     /// Offset for the view of the screen from the origin.
     translation: Vector,
 
     /// Scaling factor for the view.
     scaling: f32,
+
+    /// Size of the canvas when it was last drawn, used to fit the view to the selection.
+    viewport_size: std::cell::Cell<Size>,
+
+    /// The cell that is selected or unselected from the keyboard.
+    keyboard_cursor: GridCell,
+
+    /// The modifier keys held down, so a click can do something different with them.
+    modifiers: std::cell::Cell<keyboard::Modifiers>,
     pub program_state: ProgramState,
 
     /// Bool for whether to display the cost in precise mathematical terms.
@@ -41,6 +85,58 @@ pub struct GridState {
 
     /// Where the bottom stitch starts.
     pub top_stitch_corner: StartingStitchCorner,
+
+    /// Bool for whether top legs always start from the top stitch corner,
+    /// so they are never flipped by hand or by the optimiser.
+    pub consistent_top_legs: bool,
+
+    /// The colours used to draw the grid and the exported SVG.
+    pub theme: ColourTheme,
+
+    /// The fabric being stitched on, used to estimate thread usage.
+    pub fabric: Fabric,
+
+    /// How many fabric threads each stitch crosses on evenweave and linen.
+    pub stitch_over: usize,
+
+    /// How many strands of thread are stitched with at once.
+    pub strands: usize,
+
+    /// Bool for whether long travel on the back is replaced by cutting the thread.
+    pub cut_long_travel: bool,
+
+    /// When the thread is cut and restarted, if cutting long travel is enabled.
+    pub restart_policy: RestartPolicy,
+
+    /// A pinned sequence to compare the cost of the current sequence against.
+    pub baseline: Option<Baseline>,
+
+    /// Bool for whether isolated cells and small islands are outlined.
+    pub highlight_confetti: bool,
+
+    /// The stitches for the selected cells and their cost, kept up to date as cells change.
+    cost_model: IncrementalCost,
+
+    /// The isolated cells and small islands, found when they are highlighted.
+    confetti: Option<ConfettiTracker>,
+
+    /// A description of the plan the sequence was last made from, until a cell is changed by hand.
+    sequence_plan: Option<String>,
+
+    /// The colour given to newly selected cells, counting from 1.
+    pub thread_colour: usize,
+
+    /// The rectangle covering the selected cells, for the minimap.
+    pub selection_bounds: SelectionBounds,
+}
+
+/// A sequence pinned to compare against.
+#[derive(Debug, Clone)]
+pub struct Baseline {
+    stitches: Vec<HalfStitch>,
+
+    /// The cost of the stitches, kept so it isn't recalculated every frame.
+    cost: Result<SequenceCost, (GridCell, GridCell)>,
 }
 
 impl Default for GridState {
@@ -48,20 +144,51 @@ impl Default for GridState {
         Self {
             grid_cache: Cache::default(),
             cell_cache: Cache::default(),
+            minimap_cache: Cache::default(),
             translation: Default::default(),
-            scaling: 2.0,
+            scaling: Self::DEFAULT_SCALING,
+            viewport_size: Default::default(),
+            keyboard_cursor: GridCell::new(0, 0),
+            modifiers: Default::default(),
             program_state: Default::default(),
             precise_cost: false,
             bottom_stitch_corner: StartingStitchCorner::BottomLeft,
             top_stitch_corner: StartingStitchCorner::BottomRight,
+            consistent_top_legs: true,
+            theme: ColourTheme::default(),
+            fabric: Fabric::default(),
+            stitch_over: 2,
+            strands: 2,
+            cut_long_travel: false,
+            restart_policy: RestartPolicy::default(),
+            baseline: None,
+            highlight_confetti: false,
+            cost_model: IncrementalCost::default(),
+            confetti: None,
+            sequence_plan: None,
+            thread_colour: DEFAULT_COLOUR,
+            selection_bounds: SelectionBounds::default(),
         }
     }
 }
 
 impl GridState {
     const MIN_SCALING: f32 = 0.1;
+    const DEFAULT_SCALING: f32 = 2.0;
     const MAX_SCALING: f32 = 4.0;
 
+    /// How far the arrow keys pan the view, in cells.
+    const KEYBOARD_PAN_CELLS: f32 = 5.0;
+
+    /// How much each press of the zoom keys changes the scaling by.
+    const KEYBOARD_ZOOM_FACTOR: f32 = 1.25;
+
+    /// Below this scaling, cells are drawn without their stitches.
+    const MIN_DETAILED_SCALING: f32 = 0.5;
+
+    /// The closest that fabric threads are drawn on screen, in pixels.
+    const MIN_THREAD_SPACING: f32 = 6.0;
+
     /// Determine the region that should be visible.
     fn visible_region(&self, size: Size) -> Region {
         let view_width = size.width / self.scaling;
@@ -75,10 +202,113 @@ impl GridState {
         }
     }
 
+    /// The part of the grid that was visible when it was last drawn, before the cells are flipped.
+    pub fn visible_rectangle(&self) -> Rectangle {
+        let region = self.visible_region(self.viewport_size.get());
+        Rectangle::new(
+            Point::new(region.x, region.y),
+            Size::new(region.width, region.height),
+        )
+    }
+
     /// Clear everything to return to as-new state.
     pub fn clear(&mut self) {
         self.clear_cache();
+        self.minimap_cache.clear();
         self.program_state.clear();
+        self.sequence_plan = None;
+        self.update_stitches();
+    }
+
+    /// Restitch the selection one region at a time, touring the regions to keep the jumps short.
+    pub fn sequence_by_region(&mut self, connectivity: Connectivity) {
+        let plan = RegionPlan::new(
+            self.program_state.selected_cells.values(),
+            connectivity,
+            (self.bottom_stitch_corner, self.top_stitch_corner),
+            self.active_restart_policy(),
+        );
+        self.program_state
+            .replace_sequence(plan.stitches().iter().map(|stitch| stitch.cell()));
+        self.sequence_plan = Some(plan.to_string());
+        self.update_stitches();
+        self.minimap_cache.clear();
+    }
+
+    /// Restitch the selection a block at a time, parking each colour's thread between blocks.
+    pub fn sequence_by_parking(&mut self, block_size: usize) {
+        let plan = ParkingPlan::new(
+            self.program_state.selected_cells.values(),
+            &self.program_state.cell_colours,
+            block_size,
+            (self.bottom_stitch_corner, self.top_stitch_corner),
+            self.active_restart_policy(),
+        );
+        self.program_state
+            .replace_sequence(plan.stitches.iter().map(|stitch| stitch.cell()));
+        self.sequence_plan = Some(plan.to_string());
+        self.update_stitches();
+        self.minimap_cache.clear();
+    }
+
+    /// Rebuild the stitches and their costs after a setting that affects all of them has changed.
+    pub fn update_stitches(&mut self) {
+        let restart_policy = self.active_restart_policy();
+        let stitches = self
+            .program_state
+            .selected_cells
+            .keys()
+            .zip(self.stitches());
+        self.cost_model = IncrementalCost::new(
+            stitches.map(|(&key, stitch)| (key, HalfStitch::with_key(stitch, key))),
+            &self.program_state.cell_colours,
+            restart_policy,
+        );
+        self.selection_bounds = SelectionBounds::default();
+        for cell in self.program_state.selected_cells.values() {
+            self.selection_bounds.add(*cell);
+        }
+        if let Some(baseline) = &mut self.baseline {
+            baseline.cost = parked_cost(
+                &baseline.stitches,
+                &self.program_state.cell_colours,
+                restart_policy,
+            );
+        }
+        self.update_analysis();
+        self.clear_cache();
+    }
+
+    /// Find the isolated cells and small islands again after the stitches have changed,
+    /// if they are highlighted.
+    fn update_analysis(&mut self) {
+        let restart_policy = self.active_restart_policy();
+        self.confetti = self
+            .highlight_confetti
+            .then(|| ConfettiTracker::new(&self.cost_model, restart_policy));
+    }
+
+    /// Find the islands again only around a cell that was just selected or unselected,
+    /// and the cells its thread travels to or from.
+    fn refresh_analysis(&mut self, cells: Vec<GridCell>) {
+        let restart_policy = self.active_restart_policy();
+        if let Some(tracker) = &mut self.confetti {
+            tracker.refresh(&self.cost_model, cells, restart_policy);
+        }
+    }
+
+    /// The cells to look at again after selecting or unselecting a cell, if confetti is highlighted.
+    fn travel_neighbours(&self, grid_cell: GridCell) -> Vec<GridCell> {
+        match self.confetti {
+            Some(_) => ConfettiTracker::cells_around(&self.cost_model, grid_cell),
+            None => vec![],
+        }
+    }
+
+    pub fn change_theme(&mut self, theme: ColourTheme) {
+        self.theme = theme;
+        self.clear_cache();
+        self.minimap_cache.clear();
     }
 
     pub fn clear_cache(&mut self) {
@@ -103,30 +333,180 @@ impl GridState {
     pub fn update(&mut self, message: Message) {
         match message {
             Message::Select(grid_cell) => {
-                self.program_state.select_cell(grid_cell);
+                if let Some((leg, key)) = self.program_state.select_cell(grid_cell) {
+                    if leg == Leg::Bottom {
+                        self.program_state
+                            .cell_colours
+                            .insert(grid_cell, self.thread_colour);
+                    }
+                    let corner = self.leg_corner(grid_cell, leg);
+                    let stitch = HalfStitch::new_in_cell(grid_cell, corner, 0);
+                    self.cost_model.insert(
+                        key,
+                        HalfStitch::with_key(stitch, key),
+                        self.program_state.colour_of(grid_cell),
+                    );
+                    self.selection_bounds.add(grid_cell);
+                    self.sequence_plan = None;
+                    self.refresh_analysis(self.travel_neighbours(grid_cell));
+                }
                 self.cell_cache.clear();
+                self.minimap_cache.clear();
             }
             Message::Unselect(grid_cell) => {
-                self.program_state.unselect_cell(grid_cell);
+                if let Some(key) = self.program_state.unselect_cell(grid_cell) {
+                    let neighbours = self.travel_neighbours(grid_cell);
+                    self.cost_model.remove(key);
+                    self.selection_bounds.remove(grid_cell);
+                    self.sequence_plan = None;
+                    self.refresh_analysis(neighbours);
+                }
                 self.cell_cache.clear();
+                self.minimap_cache.clear();
+            }
+            Message::FlipLeg(grid_cell) => {
+                self.sequence_plan = None;
+                self.flip_leg(grid_cell);
             }
             Message::Translated(translation) => {
                 self.translation = translation;
 
                 self.clear_cache();
             }
-            Message::Scaled(scaling) => {
+            Message::Scaled(scaling, anchor) => {
+                if let Some(anchor) = anchor {
+                    // Keep the point under the anchor fixed on screen.
+                    self.translation =
+                        self.translation + anchor * (1.0 / scaling) - anchor * (1.0 / self.scaling);
+                }
                 self.scaling = scaling;
 
                 self.clear_cache();
             }
+            Message::FitSelection => {
+                let cells = self.program_state.selected_cells.values();
+                if let Some((translation, scaling)) =
+                    Self::fit_view(cells, self.viewport_size.get())
+                {
+                    self.translation = translation;
+                    self.scaling = scaling;
+                    self.clear_cache();
+                }
+            }
+            Message::ResetView => {
+                self.translation = Vector::ZERO;
+                self.scaling = Self::DEFAULT_SCALING;
+                self.clear_cache();
+            }
+            Message::MoveCursor(cell) => {
+                self.keyboard_cursor = cell;
+            }
+            // Shortcuts are handled by the application, which owns the other controls.
+            Message::Shortcut(_) => {}
         }
     }
 
-    pub fn view(&self) -> Element<Message> {
+    /// The translation and scaling that fit the cells into a view of the given size,
+    /// if there are any cells.
+    fn fit_view<'a>(
+        mut cells: impl Iterator<Item = &'a GridCell>,
+        size: Size,
+    ) -> Option<(Vector, f32)> {
+        let first = cells.next()?;
+        let (min, max) = cells.fold((*first, *first), |(min, max), cell| {
+            (
+                GridCell::new(min.x.min(cell.x), min.y.min(cell.y)),
+                GridCell::new(max.x.max(cell.x), max.y.max(cell.y)),
+            )
+        });
+
+        // Leave a margin of a cell around the selection.
+        let cell_size = GridCell::SIZE as f32;
+        let width = (max.x - min.x + 3) as f32 * cell_size;
+        let height = (max.y - min.y + 3) as f32 * cell_size;
+        let scaling = (size.width / width)
+            .min(size.height / height)
+            .clamp(Self::MIN_SCALING, Self::MAX_SCALING);
+
+        // The y-axis of the view points down the screen, so the cells are flipped.
+        let centre = Vector::new(
+            (min.x + max.x + 1) as f32 / 2.0 * cell_size,
+            -(min.y + max.y + 1) as f32 / 2.0 * cell_size,
+        );
+        Some((Vector::ZERO - centre, scaling))
+    }
+
+    /// The message for a key press on the grid, if it does anything.
+    /// Arrows pan the view, or move the keyboard cursor while Shift is held.
+    fn keyboard_message(
+        &self,
+        key: &keyboard::Key,
+        modifiers: keyboard::Modifiers,
+    ) -> Option<Message> {
+        let arrow_direction = match key {
+            keyboard::Key::Named(Named::ArrowUp) => Some(GridCell::new(0, 1)),
+            keyboard::Key::Named(Named::ArrowDown) => Some(GridCell::new(0, -1)),
+            keyboard::Key::Named(Named::ArrowLeft) => Some(GridCell::new(-1, 0)),
+            keyboard::Key::Named(Named::ArrowRight) => Some(GridCell::new(1, 0)),
+            _ => None,
+        };
+        if let Some(direction) = arrow_direction {
+            return Some(if modifiers.shift() {
+                Message::MoveCursor(self.keyboard_cursor + direction)
+            } else {
+                // Panning moves the view, so the grid moves the opposite way on screen.
+                // The y-axis of the translation points down the screen.
+                let step = Self::KEYBOARD_PAN_CELLS * GridCell::SIZE as f32;
+                Message::Translated(
+                    self.translation
+                        + Vector::new(-direction.x as f32 * step, direction.y as f32 * step),
+                )
+            });
+        }
+
+        if modifiers.command() {
+            return match key.as_ref() {
+                keyboard::Key::Named(Named::Delete) => Some(Message::Shortcut(Shortcut::Clear)),
+                keyboard::Key::Character("e") => Some(Message::Shortcut(Shortcut::Export)),
+                keyboard::Key::Character("b") => {
+                    Some(Message::Shortcut(Shortcut::CycleBottomStitchCorner))
+                }
+                keyboard::Key::Character("t") => {
+                    Some(Message::Shortcut(Shortcut::CycleTopStitchCorner))
+                }
+                _ => None,
+            };
+        }
+
+        match key.as_ref() {
+            keyboard::Key::Named(Named::Space) => Some(Message::Select(self.keyboard_cursor)),
+            keyboard::Key::Named(Named::Backspace) => Some(Message::Unselect(self.keyboard_cursor)),
+            keyboard::Key::Character("+" | "=") => Some(Message::Scaled(
+                (self.scaling * Self::KEYBOARD_ZOOM_FACTOR).min(Self::MAX_SCALING),
+                None,
+            )),
+            keyboard::Key::Character("-" | "_") => Some(Message::Scaled(
+                (self.scaling / Self::KEYBOARD_ZOOM_FACTOR).max(Self::MIN_SCALING),
+                None,
+            )),
+            keyboard::Key::Character("f") => Some(Message::FitSelection),
+            keyboard::Key::Character("0") => Some(Message::ResetView),
+            _ => None,
+        }
+    }
+
+    pub fn view(&self) -> Element<'_, Message> {
         Canvas::new(self).width(Fill).height(Fill).into()
     }
 
+    pub fn minimap(&self) -> Element<'_, Message> {
+        Minimap {
+            grid_state: self,
+            cell_cache: &self.minimap_cache,
+        }
+        .view()
+    }
+
     fn transform_frame_for_cells(&self, screen_centre: Vector, frame: &mut Frame<Renderer>) {
         // Order here is necessary for it to work correctly.
         // First translate so the origin is the centre of the screen,
@@ -152,7 +532,7 @@ impl GridState {
             let columns = region.columns();
             let (total_rows, total_columns) = (rows.clone().count(), columns.clone().count());
             let width = 2.0 / GridCell::SIZE as f32;
-            let color = Color::from_rgb8(70, 74, 83);
+            let color = self.theme.palette().grid_lines;
 
             frame.translate(Vector::new(-width / 2.0, -width / 2.0));
 
@@ -171,48 +551,240 @@ impl GridState {
                     color,
                 );
             }
+
+            // On evenweave and linen, the fabric threads between the corners of each cell
+            // are drawn faintly, once they are far enough apart to make out.
+            let threads_per_cell = self.fabric_grid().threads_per_cell();
+            let thread_spacing = GridCell::SIZE as f32 * self.scaling / threads_per_cell as f32;
+            if threads_per_cell > 1 && thread_spacing >= Self::MIN_THREAD_SPACING {
+                let thread_color = Color {
+                    a: color.a * 0.4,
+                    ..color
+                };
+                let thread_width = width / 2.0;
+                let offsets = (1..threads_per_cell).map(|i| i as f32 / threads_per_cell as f32);
+                for (row, offset) in region.rows().cartesian_product(offsets.clone()) {
+                    frame.fill_rectangle(
+                        Point::new(*columns.start() as f32, row as f32 + offset),
+                        Size::new(total_columns as f32, thread_width),
+                        thread_color,
+                    );
+                }
+                for (column, offset) in region.columns().cartesian_product(offsets) {
+                    frame.fill_rectangle(
+                        Point::new(column as f32 + offset, *rows.start() as f32),
+                        Size::new(thread_width, total_rows as f32),
+                        thread_color,
+                    );
+                }
+            }
         })
     }
 
+    /// The opacity of each of the most recent stitches, newest first,
+    /// followed by the opacity of every stitch before them.
+    fn recent_stitch_opacities() -> (Vec<f32>, f32) {
+        let mut opacities = Vec::new();
+        let mut alpha = 1.0;
+        while alpha > 0.4 {
+            opacities.push(alpha);
+            alpha -= if alpha < 0.95 { 0.05 } else { 0.01 };
+        }
+        (opacities, alpha)
+    }
+
+    /// The restart policy in use, if the thread is cut on long travel.
+    pub fn active_restart_policy(&self) -> Option<RestartPolicy> {
+        self.cut_long_travel.then_some(self.restart_policy)
+    }
+
+    /// How the cells sit on the threads of the current fabric.
+    pub fn fabric_grid(&self) -> FabricGrid {
+        FabricGrid::new(self.fabric, self.stitch_over)
+    }
+
+    /// Estimate the thread needed for each colour of the given stitches on the current fabric.
+    pub fn thread_estimates(&self, stitches: &[HalfStitch]) -> BTreeMap<usize, ThreadEstimate> {
+        let thread_runs = parked_runs(
+            stitches,
+            &self.program_state.cell_colours,
+            self.active_restart_policy(),
+        );
+        thread_runs
+            .iter()
+            .map(Vec::as_slice)
+            .into_group_map_by(|run| self.program_state.colour_of(run[0].cell()))
+            .into_iter()
+            .map(|(colour, runs)| {
+                let estimate = ThreadEstimate::calculate(&runs, self.fabric_grid(), self.strands);
+                (colour, estimate)
+            })
+            .collect()
+    }
+
+    /// Pin the current sequence as the baseline to compare against.
+    pub fn pin_baseline(&mut self) {
+        self.baseline = Some(Baseline {
+            stitches: self.stitches(),
+            cost: self.cost_model.cost(),
+        });
+    }
+
+    /// The stitches for the currently selected cells.
+    pub fn stitches(&self) -> Vec<HalfStitch> {
+        HalfStitch::convert_grid_cells_with_corners(
+            self.program_state.selected_cells.values(),
+            |cell, leg| self.leg_corner(cell, leg),
+        )
+    }
+
+    /// The corner a leg in the cell starts from, unless it has been flipped.
+    fn default_corner(&self, leg: Leg) -> StartingStitchCorner {
+        match leg {
+            Leg::Bottom => self.bottom_stitch_corner,
+            Leg::Top => self.top_stitch_corner,
+        }
+    }
+
+    /// The corner a leg in the cell starts from.
+    fn leg_corner(&self, cell: GridCell, leg: Leg) -> StartingStitchCorner {
+        if leg == Leg::Top && self.consistent_top_legs {
+            return self.top_stitch_corner;
+        }
+        self.program_state
+            .corner_overrides
+            .get(&(cell, leg))
+            .copied()
+            .unwrap_or(self.default_corner(leg))
+    }
+
+    /// Reverse the direction of a leg in the cell, keeping it on the same diagonal.
+    /// The top leg is flipped if there is one and top legs can be flipped,
+    /// otherwise the bottom leg is.
+    fn flip_leg(&mut self, cell: GridCell) {
+        let leg = match self.program_state.selection_count(cell) {
+            0 => return,
+            2 if !self.consistent_top_legs => Leg::Top,
+            _ => Leg::Bottom,
+        };
+        let flipped = self.leg_corner(cell, leg).get_opposite_corner();
+        if flipped == self.default_corner(leg) {
+            self.program_state.corner_overrides.remove(&(cell, leg));
+        } else {
+            self.program_state
+                .corner_overrides
+                .insert((cell, leg), flipped);
+        }
+        self.update_stitches();
+    }
+
+    /// Flip the legs that make the travel shorter, keeping the order of the stitches.
+    pub fn optimise_corners(&mut self) {
+        let optimised = optimise_corners(
+            &self.stitches(),
+            &self.program_state.cell_colours,
+            self.consistent_top_legs,
+            self.active_restart_policy(),
+        );
+        let mut seen_cells = HashSet::new();
+        self.program_state.corner_overrides = optimised
+            .iter()
+            .map(|stitch| {
+                let leg = match seen_cells.insert(stitch.cell()) {
+                    true => Leg::Bottom,
+                    false => Leg::Top,
+                };
+                ((stitch.cell(), leg), stitch.stitch_corner)
+            })
+            .filter(|((_, leg), corner)| *corner != self.default_corner(*leg))
+            .collect();
+        self.update_stitches();
+    }
+
+    /// Describe the current cost against the baseline, in both representations.
+    fn describe_baseline_comparison(
+        &self,
+        current_cost: &Result<SequenceCost, (GridCell, GridCell)>,
+    ) -> Vec<String> {
+        let Some(baseline) = &self.baseline else {
+            return vec![];
+        };
+        let baseline_cost = &baseline.cost;
+        let mut lines = vec![
+            match &current_cost {
+                Ok(cost) => format!("Current: {}", cost),
+                Err(_) => "Current: invalid sequence".to_string(),
+            },
+            match &baseline_cost {
+                Ok(cost) => format!("Baseline: {}", cost),
+                Err(_) => "Baseline: invalid sequence".to_string(),
+            },
+        ];
+        if let (Ok(current_cost), Ok(baseline_cost)) = (current_cost, baseline_cost) {
+            let difference = current_cost.difference_from(baseline_cost);
+            lines.push(format!(
+                "Difference: {} ({})",
+                difference,
+                match difference.symbolic.signum() {
+                    Ordering::Less => "better",
+                    Ordering::Equal => "no change",
+                    Ordering::Greater => "worse",
+                }
+            ));
+        }
+        lines
+    }
+
     fn draw_text_information(
         &self,
         valid_sequence: Result<String, (GridCell, GridCell)>,
+        thread_estimates: BTreeMap<usize, ThreadEstimate>,
+        baseline_comparison: Vec<String>,
         frame: &mut Frame<Renderer>,
         text: Text,
         cell: GridCell,
     ) {
-        frame.fill_text(Text {
-            content: format!("({}, {}) grid", cell.x, cell.y,),
-            position: text.position - Vector::new(0.0, 32.0),
-            ..text
-        });
-
         let visible_region = self.visible_region(frame.size());
-        frame.fill_text(Text {
-            content: format!(
+        let cell_count = self.program_state.selected_cells.len();
+
+        let mut lines = baseline_comparison;
+        lines.extend(self.sequence_plan.iter().cloned());
+        let colour_travel = self.cost_model.colour_travel();
+        if colour_travel.len() > 1 {
+            lines.push(describe_parked_travel(&colour_travel));
+        }
+        lines.extend(self.confetti.iter().map(|report| report.to_string()));
+        lines.push(format!("({}, {}) grid", cell.x, cell.y,));
+        lines.extend(describe_thread_estimates(
+            &thread_estimates,
+            self.fabric_grid(),
+        ));
+        lines.extend([
+            format!(
                 "Visible Area: columns {} to {}, rows {} to {}",
                 visible_region.columns().start(),
                 visible_region.columns().end(),
                 visible_region.rows().start(),
                 visible_region.rows().end(),
             ),
-            position: text.position - Vector::new(0.0, 16.0),
-            ..text
-        });
-        let cell_count = self.program_state.selected_cells.len();
-
-        frame.fill_text(Text {
-            content: format!(
+            format!(
                 "{cell_count} cell{} @ {}",
                 if cell_count == 1 { "" } else { "s" },
-                if valid_sequence.is_ok() {
-                    format!("{} distance", valid_sequence.unwrap())
-                } else {
-                    "invalid sequence".to_string()
+                match valid_sequence {
+                    Ok(cost) => format!("{} distance", cost),
+                    Err(_) => "invalid sequence".to_string(),
                 },
             ),
-            ..text
-        });
+        ]);
+
+        // Draw from the bottom up, so the last line sits in the corner.
+        for (i, line) in lines.into_iter().rev().enumerate() {
+            frame.fill_text(Text {
+                content: line,
+                position: text.position - Vector::new(0.0, 16.0 * i as f32),
+                ..text
+            });
+        }
     }
 }
 
@@ -226,9 +798,21 @@ impl canvas::Program<Message> for GridState {
         bounds: Rectangle,
         cursor: Cursor,
     ) -> (Status, Option<Message>) {
+        self.viewport_size.set(bounds.size());
         if let Event::Mouse(mouse::Event::ButtonReleased(_)) = event {
             *interaction = GridInteraction::None;
         }
+
+        if let Event::Keyboard(keyboard::Event::ModifiersChanged(modifiers)) = &event {
+            self.modifiers.set(*modifiers);
+        }
+        // The keyboard works wherever the mouse is.
+        if let Event::Keyboard(keyboard::Event::KeyPressed { key, modifiers, .. }) = &event {
+            return match self.keyboard_message(key, *modifiers) {
+                Some(message) => (Status::Captured, Some(message)),
+                None => (Status::Ignored, None),
+            };
+        }
         let screen_cursor_position = match cursor.position_in(bounds) {
             None => {
                 return (Status::Ignored, None);
@@ -243,6 +827,9 @@ impl canvas::Program<Message> for GridState {
             Event::Mouse(mouse_event) => match mouse_event {
                 mouse::Event::ButtonPressed(button) => {
                     let message = match button {
+                        mouse::Button::Left if self.modifiers.get().shift() => {
+                            Some(Message::FlipLeg(cell))
+                        }
                         mouse::Button::Left => Some(Message::Select(cell)),
                         mouse::Button::Right => Some(Message::Unselect(cell)),
                         mouse::Button::Middle => {
@@ -281,7 +868,9 @@ impl canvas::Program<Message> for GridState {
                             let scaling = (self.scaling * (1.0 + (y / 30.0)))
                                 .clamp(Self::MIN_SCALING, Self::MAX_SCALING);
 
-                            let message = Message::Scaled(scaling);
+                            let anchor = screen_cursor_position
+                                - Point::new(bounds.width / 2.0, bounds.height / 2.0);
+                            let message = Message::Scaled(scaling, Some(anchor));
                             (Status::Captured, Some(message))
                         } else {
                             (Status::Ignored, None)
@@ -304,31 +893,70 @@ impl canvas::Program<Message> for GridState {
         cursor: Cursor,
     ) -> Vec<Geometry<Renderer>> {
         let screen_centre = Vector::new(bounds.width / 2.0, bounds.height / 2.0);
-
-        // Convert the stitches that already exist and check if they're valid,
-        // computing the cost as we go.
-        let stitches = HalfStitch::convert_grid_cells(
-            self.program_state.selected_cells.iter(),
-            self.bottom_stitch_corner,
-            self.top_stitch_corner,
-        );
-        let valid_sequence = if self.precise_cost {
-            HalfStitch::check_valid_sequence_symbolic(&stitches)
-        } else {
-            HalfStitch::check_valid_sequence_float(&stitches)
-        };
+        self.viewport_size.set(bounds.size());
+        let palette = self.theme.palette();
+
+        // The stitches and their cost are kept up to date as cells are selected,
+        // so they don't need to be rebuilt for every frame.
+        let cost = self.cost_model.cost();
+        let baseline_comparison = self.describe_baseline_comparison(&cost);
+        let valid_sequence = cost.map(|cost| {
+            if self.precise_cost {
+                cost.symbolic.to_string()
+            } else {
+                format!("{:.4}", cost.float)
+            }
+        });
+        let thread_estimates = self
+            .cost_model
+            .colour_totals()
+            .iter()
+            .map(|(colour, totals)| {
+                let estimate = ThreadEstimate::new(
+                    totals.stitches,
+                    totals.travel,
+                    totals.restarts + 1,
+                    self.fabric_grid(),
+                    self.strands,
+                );
+                (*colour, estimate)
+            })
+            .collect();
 
         let selected_cells = self.cell_cache.draw(renderer, bounds.size(), |frame| {
             let background = Path::rectangle(Point::ORIGIN, frame.size());
-            frame.fill(&background, Color::from_rgb8(0x40, 0x44, 0x4B));
+            frame.fill(&background, palette.background);
+
+            // Only the cells on screen are drawn, so large designs stay responsive.
+            let region = self.visible_region(frame.size());
+            let (columns, rows) = (region.columns(), region.cell_rows());
+            let visible = |cell: GridCell| columns.contains(&cell.x) && rows.contains(&cell.y);
 
             frame.with_save(|frame| {
                 self.transform_frame_for_cells(screen_centre, frame);
 
                 frame.scale_nonuniform(Vector { x: 1.0, y: -1.0 });
 
-                for cell in self.program_state.selected_cells.iter() {
-                    frame.fill_rectangle(Point::from(cell), Size::UNIT, Color::WHITE);
+                for stitch in self.cost_model.stitches_in(columns.clone(), rows.clone()) {
+                    frame.fill_rectangle(
+                        Point::from(stitch.cell()),
+                        Size::UNIT,
+                        palette.thread_cell(self.program_state.colour_of(stitch.cell())),
+                    );
+                }
+
+                // Outline the isolated cells and small islands, which waste the most thread.
+                if let Some(report) = &self.confetti {
+                    for cell in report.cells().filter(|cell| visible(**cell)) {
+                        frame.stroke(
+                            &Path::rectangle(Point::from(cell), Size::UNIT),
+                            Stroke {
+                                width: 2.0 * self.scaling,
+                                style: Style::Solid(palette.confetti),
+                                ..Default::default()
+                            },
+                        );
+                    }
                 }
 
                 // Mark the first pair of invalid stitches, if there are any.
@@ -339,29 +967,58 @@ impl canvas::Program<Message> for GridState {
                             frame.fill_rectangle(
                                 Point::from(cell),
                                 Size::UNIT,
-                                Color::from_rgb(100.0, 0.0, 0.0),
+                                palette.invalid_cell,
                             );
                         }
                     }
                 }
 
-                let mut alpha = 1.0;
-                // Iterate in verse order so we can decrease the opacity for each stitch.
-                for stitch in stitches.iter().rev() {
-                    let line = stitch.make_path_stroke();
-                    let line_stroke = Stroke {
-                        width: 2.0 * self.scaling,
-                        style: Style::Solid(Color {
-                            a: alpha,
-                            ..Color::BLACK
-                        }),
-                        ..Default::default()
-                    };
-                    frame.stroke(&line, line_stroke);
-                    if alpha > 0.4 {
-                        let reduction = if alpha < 0.95 { 0.05 } else { 0.01 };
-                        alpha -= reduction;
-                    }
+                // Individual stitches can't be made out when zoomed far out,
+                // so only the filled cells are drawn.
+                if self.scaling < Self::MIN_DETAILED_SCALING {
+                    return;
+                }
+
+                let stitch_stroke = |alpha| Stroke {
+                    width: 2.0 * self.scaling,
+                    style: Style::Solid(Color {
+                        a: alpha,
+                        ..palette.stitch
+                    }),
+                    ..Default::default()
+                };
+
+                // The most recent stitches fade out so the order can be followed,
+                // and every earlier stitch is drawn at the same faint opacity.
+                let (opacities, faint_alpha) = Self::recent_stitch_opacities();
+                let recent: Vec<(&HalfStitch, f32)> =
+                    self.cost_model.stitches().rev().zip(opacities).collect();
+                let recent_stitches: HashSet<&HalfStitch> =
+                    recent.iter().map(|(stitch, _)| *stitch).collect();
+
+                for stitch in self
+                    .cost_model
+                    .stitches_in(columns.clone(), rows.clone())
+                    .filter(|stitch| !recent_stitches.contains(stitch))
+                {
+                    frame.stroke(&stitch.make_path_stroke(), stitch_stroke(faint_alpha));
+                }
+                for (stitch, alpha) in recent.into_iter().rev() {
+                    frame.stroke(&stitch.make_path_stroke(), stitch_stroke(alpha));
+                }
+
+                // Mark where each thread starts and ends.
+                for hole in self
+                    .cost_model
+                    .thread_starts_in(columns.clone(), rows.clone())
+                {
+                    frame.fill(&Path::circle(Point::from(*hole), 0.2), palette.thread_start);
+                }
+                for hole in self
+                    .cost_model
+                    .thread_ends_in(columns.clone(), rows.clone())
+                {
+                    frame.fill(&Path::circle(Point::from(*hole), 0.2), palette.thread_end);
                 }
             });
         });
@@ -375,11 +1032,11 @@ impl canvas::Program<Message> for GridState {
                 )
             });
 
-            if let Some(cell) = hovered_grid_cell {
-                frame.with_save(|frame| {
-                    self.transform_frame_for_cells(screen_centre, frame);
+            frame.with_save(|frame| {
+                self.transform_frame_for_cells(screen_centre, frame);
 
-                    frame.scale_nonuniform(Vector { x: 1.0, y: -1.0 });
+                frame.scale_nonuniform(Vector { x: 1.0, y: -1.0 });
+                if let Some(cell) = hovered_grid_cell {
                     frame.fill_rectangle(
                         Point::from(cell),
                         Size::UNIT,
@@ -388,12 +1045,20 @@ impl canvas::Program<Message> for GridState {
                             ..Color::BLACK
                         },
                     );
-                });
-            }
+                }
+                frame.stroke(
+                    &Path::rectangle(Point::from(self.keyboard_cursor), Size::UNIT),
+                    Stroke {
+                        width: 2.0 * self.scaling,
+                        style: Style::Solid(palette.cursor),
+                        ..Default::default()
+                    },
+                );
+            });
 
             // Make text for coordinates in the corner
             let text = Text {
-                color: Color::WHITE,
+                color: palette.text,
                 size: 14.0.into(),
                 position: Point::new(frame.width(), frame.height()),
                 horizontal_alignment: alignment::Horizontal::Right,
@@ -401,8 +1066,15 @@ impl canvas::Program<Message> for GridState {
                 font: Font::MONOSPACE,
                 ..Text::default()
             };
-            if let Some(cell) = hovered_grid_cell {
-                self.draw_text_information(valid_sequence, &mut frame, text, cell);
+            if let Some(cell) = hovered_grid_cell.or(Some(self.keyboard_cursor)) {
+                self.draw_text_information(
+                    valid_sequence,
+                    thread_estimates,
+                    baseline_comparison,
+                    &mut frame,
+                    text,
+                    cell,
+                );
             }
 
             frame.into_geometry()
@@ -426,6 +1098,13 @@ pub struct Region {
 }
 
 impl Region {
+    /// Get the y-coordinates of all cells that should be visible.
+    /// The y-axis of cells points up the screen, so is the reverse of the rows.
+    fn cell_rows(&self) -> RangeInclusive<isize> {
+        let rows = self.rows();
+        (-rows.end() - 1)..=(-rows.start() - 1)
+    }
+
     /// Get indices of all cell rows that should be visible
     fn rows(&self) -> RangeInclusive<isize> {
         let first_row = (self.y / GridCell::SIZE as f32).floor() as isize;
@@ -494,4 +1173,191 @@ mod tests {
         let result = test_region.rows();
         assert_eq!(result, -5..=5);
     }
+
+    #[test]
+    fn test_region_cell_rows_flip_y() {
+        let test_region = Region {
+            x: 0.0,
+            y: -40.0,
+            width: 100.0,
+            height: 100.0,
+        };
+        assert_eq!(test_region.rows(), -2..=3);
+        assert_eq!(test_region.cell_rows(), -4..=1);
+    }
+
+    #[test]
+    fn test_recent_stitch_opacities_fade_to_faint() {
+        let (opacities, faint_alpha) = GridState::recent_stitch_opacities();
+        assert_eq!(opacities[0], 1.0);
+        assert!(opacities.windows(2).all(|pair| pair[0] > pair[1]));
+        assert!(faint_alpha <= 0.4 && faint_alpha < opacities[opacities.len() - 1]);
+    }
+
+    fn _character(character: &str) -> keyboard::Key {
+        keyboard::Key::Character(character.into())
+    }
+
+    #[test]
+    fn test_keyboard_arrow_pans_view() {
+        let grid_state = GridState::default();
+        let result = grid_state.keyboard_message(
+            &keyboard::Key::Named(Named::ArrowRight),
+            keyboard::Modifiers::empty(),
+        );
+        assert!(matches!(result, Some(Message::Translated(v)) if v.x < 0.0 && v.y == 0.0));
+    }
+
+    #[test]
+    fn test_keyboard_shift_arrow_moves_cursor() {
+        let grid_state = GridState::default();
+        let result = grid_state.keyboard_message(
+            &keyboard::Key::Named(Named::ArrowUp),
+            keyboard::Modifiers::SHIFT,
+        );
+        assert!(matches!(result, Some(Message::MoveCursor(cell)) if cell == GridCell::new(0, 1)));
+    }
+
+    #[test]
+    fn test_keyboard_space_selects_cursor() {
+        let mut grid_state = GridState::default();
+        grid_state.update(Message::MoveCursor(GridCell::new(3, -2)));
+        let result = grid_state.keyboard_message(
+            &keyboard::Key::Named(Named::Space),
+            keyboard::Modifiers::empty(),
+        );
+        assert!(matches!(result, Some(Message::Select(cell)) if cell == GridCell::new(3, -2)));
+    }
+
+    #[test]
+    fn test_keyboard_zoom_is_clamped() {
+        let mut grid_state = GridState::default();
+        grid_state.update(Message::Scaled(GridState::MAX_SCALING, None));
+        let result = grid_state.keyboard_message(&_character("+"), keyboard::Modifiers::empty());
+        assert!(matches!(result, Some(Message::Scaled(s, None)) if s == GridState::MAX_SCALING));
+    }
+
+    #[test]
+    fn test_keyboard_shortcuts_need_command() {
+        let grid_state = GridState::default();
+        let result = grid_state.keyboard_message(&_character("e"), keyboard::Modifiers::CTRL);
+        assert!(matches!(result, Some(Message::Shortcut(Shortcut::Export))));
+        let result = grid_state.keyboard_message(&_character("e"), keyboard::Modifiers::empty());
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn test_zoom_keeps_anchor_in_place() {
+        let mut grid_state = GridState::default();
+        let size = Size::new(400.0, 300.0);
+        let anchor = Vector::new(60.0, -40.0);
+        let screen_point = Point::new(200.0, 150.0) + anchor;
+        let before = grid_state.project_screen_to_mathematical_point(screen_point, size);
+        grid_state.update(Message::Scaled(3.0, Some(anchor)));
+        let after = grid_state.project_screen_to_mathematical_point(screen_point, size);
+        assert!((before.x - after.x).abs() < 1e-3 && (before.y - after.y).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_fit_view_centres_selection() {
+        let cells = [GridCell::new(0, 0), GridCell::new(9, -4)];
+        let (translation, scaling) =
+            GridState::fit_view(cells.iter(), Size::new(600.0, 600.0)).unwrap();
+        assert_eq!(scaling, 600.0 / (12.0 * 20.0));
+        assert_eq!(translation, Vector::new(-100.0, -30.0));
+    }
+
+    #[test]
+    fn test_fit_view_respects_scaling_limits() {
+        let cells = [GridCell::new(0, 0)];
+        let (_, scaling) = GridState::fit_view(cells.iter(), Size::new(4000.0, 4000.0)).unwrap();
+        assert_eq!(scaling, GridState::MAX_SCALING);
+        assert!(GridState::fit_view([].iter(), Size::new(10.0, 10.0)).is_none());
+    }
+
+    #[test]
+    fn test_flip_leg_reverses_one_cell() {
+        let mut grid_state = GridState::default();
+        let (cell, other) = (GridCell::new(0, 0), GridCell::new(1, 0));
+        for selected in [cell, cell, other] {
+            grid_state.update(Message::Select(selected));
+        }
+        let corners = |grid_state: &GridState| {
+            grid_state
+                .stitches()
+                .iter()
+                .map(|stitch| stitch.stitch_corner)
+                .collect::<Vec<_>>()
+        };
+
+        // Top legs are kept the same way, so the bottom leg is flipped.
+        grid_state.update(Message::FlipLeg(cell));
+        assert_eq!(
+            corners(&grid_state),
+            [
+                StartingStitchCorner::TopRight,
+                StartingStitchCorner::BottomRight,
+                StartingStitchCorner::BottomLeft
+            ]
+        );
+
+        grid_state.consistent_top_legs = false;
+        grid_state.update(Message::FlipLeg(cell));
+        assert_eq!(corners(&grid_state)[1], StartingStitchCorner::TopLeft);
+
+        // Flipping back to the usual corner leaves nothing to remember.
+        grid_state.update(Message::FlipLeg(other));
+        grid_state.update(Message::FlipLeg(other));
+        assert_eq!(grid_state.program_state.corner_overrides.len(), 2);
+        assert!(grid_state
+            .stitches()
+            .iter()
+            .eq(grid_state.cost_model.stitches()));
+    }
+
+    #[test]
+    fn test_unselect_from_middle_keeps_cost_in_step() {
+        let mut grid_state = GridState::default();
+        let cells = [(0, 0), (1, 0), (0, 0), (2, 1), (1, 0)].map(|(x, y)| GridCell::new(x, y));
+        for cell in cells {
+            grid_state.update(Message::Select(cell));
+        }
+        grid_state.update(Message::Unselect(GridCell::new(1, 0)));
+        grid_state.update(Message::Unselect(GridCell::new(0, 0)));
+        let cells = grid_state
+            .stitches()
+            .iter()
+            .map(|stitch| stitch.cell())
+            .collect_vec();
+        assert_eq!(
+            cells,
+            [
+                GridCell::new(0, 0),
+                GridCell::new(1, 0),
+                GridCell::new(2, 1)
+            ]
+        );
+        let expected = HalfStitch::calculate_cost(&grid_state.stitches(), None);
+        assert_eq!(
+            grid_state.cost_model.cost().map(|cost| cost.symbolic),
+            expected.map(|cost| cost.symbolic)
+        );
+    }
+
+    #[test]
+    fn test_optimise_corners_only_remembers_flipped_legs() {
+        let mut grid_state = GridState {
+            consistent_top_legs: false,
+            ..GridState::default()
+        };
+        for x in [3, 2, 1, 0] {
+            grid_state.update(Message::Select(GridCell::new(x, 0)));
+        }
+        let before = HalfStitch::calculate_cost_float(&grid_state.stitches());
+        grid_state.optimise_corners();
+        let after = HalfStitch::calculate_cost_float(&grid_state.stitches());
+        assert_eq!(after, 3.0);
+        assert!(after < before);
+        assert!(grid_state.program_state.corner_overrides.len() < 4);
+    }
 }
@@ -33,6 +33,22 @@ impl GridCell {
             as usize
     }
 
+    /// The four cells that share an edge with this one.
+    pub fn edge_neighbours(&self) -> impl Iterator<Item = GridCell> + '_ {
+        [(1, 0), (0, 1), (-1, 0), (0, -1)]
+            .into_iter()
+            .map(move |(x, y)| *self + GridCell::new(x, y))
+    }
+
+    /// The eight cells around this one. Cells that only touch at a corner still share a hole,
+    /// so they count as neighbours.
+    pub fn neighbours(&self) -> impl Iterator<Item = GridCell> + '_ {
+        (-1..=1)
+            .flat_map(|dy| (-1..=1).map(move |dx| GridCell::new(dx, dy)))
+            .filter(|offset| *offset != GridCell::default())
+            .map(move |offset| *self + offset)
+    }
+
     pub fn invert_y(&self) -> Self {
         GridCell {
             x: self.x,
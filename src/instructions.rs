@@ -0,0 +1,313 @@
+use crate::grid_cell::GridCell;
+use crate::parking::{colour_of, parked_runs};
+use crate::stitch::{HalfStitch, RestartPolicy, StartingStitchCorner};
+use std::collections::{HashMap, HashSet};
+
+/// How written instructions are laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstructionFormat {
+    PlainText,
+    Markdown,
+}
+
+/// One half stitch, and the travel on the back to the next one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Step {
+    /// Where the stitch is on the chart, counting columns from the left and rows from the top.
+    cell: GridCell,
+    corner: StartingStitchCorner,
+
+    /// Whether the stitch crosses over one already in the cell.
+    top_leg: bool,
+
+    /// How many holes right and up the needle moves to the next stitch,
+    /// or `None` when the thread ends here.
+    travel: Option<(isize, isize)>,
+}
+
+/// Write out the sequence as numbered steps for each thread,
+/// with runs of the same stitch along neighbouring cells written as one step.
+/// Each colour has its own threads, which are named with their colour when there is more than one.
+pub fn write_instructions(
+    stitches: &[HalfStitch],
+    colours: &HashMap<GridCell, usize>,
+    restart_policy: Option<RestartPolicy>,
+    format: InstructionFormat,
+) -> String {
+    let markdown = format == InstructionFormat::Markdown;
+    let mut text = if markdown {
+        "# Stitching instructions\n\n".to_string()
+    } else {
+        "STITCHING INSTRUCTIONS\n\n".to_string()
+    };
+    text.push_str(
+        "Cells are written as (column, row), counting columns from the left \
+         and rows from the top of the design, starting at 1.\n",
+    );
+    if stitches.is_empty() {
+        text.push_str("\nThere is nothing to stitch.\n");
+        return text;
+    }
+
+    let left = stitches.iter().map(|s| s.cell().x).min().unwrap();
+    let top = stitches.iter().map(|s| s.cell().y).max().unwrap();
+    let chart_cell = |cell: GridCell| GridCell::new(cell.x - left + 1, top - cell.y + 1);
+
+    let mut seen_cells = HashSet::new();
+    let mut step_number = 1;
+    let runs = parked_runs(stitches, colours, restart_policy);
+    let run_colour = |run: &[HalfStitch]| colour_of(colours, run[0].cell());
+    let several_colours = runs
+        .iter()
+        .any(|run| run_colour(run) != run_colour(&runs[0]));
+    for (thread, run) in runs.iter().enumerate() {
+        let heading = match several_colours {
+            true => format!("Thread {}, colour {}", thread + 1, run_colour(run)),
+            false => format!("Thread {}", thread + 1),
+        };
+        if markdown {
+            text.push_str(&format!("\n## {}\n\n", heading));
+        } else {
+            text.push_str(&format!("\n{}\n{}\n", heading, "-".repeat(heading.len())));
+        }
+        let steps = run
+            .iter()
+            .enumerate()
+            .map(|(i, stitch)| Step {
+                cell: chart_cell(stitch.cell()),
+                corner: stitch.stitch_corner,
+                top_leg: !seen_cells.insert(stitch.cell()),
+                travel: run.get(i + 1).map(|next| {
+                    let end = stitch.get_end_location();
+                    (next.start.x - end.x, next.start.y - end.y)
+                }),
+            })
+            .collect::<Vec<_>>();
+        for group in group_steps(&steps) {
+            text.push_str(&format!("{}. {}\n", step_number, describe_group(group)));
+            step_number += 1;
+        }
+    }
+    text
+}
+
+/// Split the steps into runs of the same stitch, one cell apart in the same direction,
+/// with the same travel between them.
+fn group_steps(steps: &[Step]) -> Vec<&[Step]> {
+    let mut groups = Vec::new();
+    let mut start = 0;
+    for i in 1..=steps.len() {
+        let continues = i < steps.len() && {
+            let (first, previous, current) = (&steps[start], &steps[i - 1], &steps[i]);
+            let step = current.cell - previous.cell;
+            current.corner == first.corner
+                && current.top_leg == first.top_leg
+                && step.x.abs() <= 1
+                && step.y.abs() <= 1
+                && step != GridCell::default()
+                && previous.travel == first.travel
+                && (i - start == 1 || step == steps[start + 1].cell - first.cell)
+        };
+        if !continues {
+            groups.push(&steps[start..i]);
+            start = i;
+        }
+    }
+    groups
+}
+
+fn describe_group(group: &[Step]) -> String {
+    let (first, last) = (&group[0], &group[group.len() - 1]);
+    let corner = first.corner.to_string().to_lowercase();
+    let opposite = first
+        .corner
+        .get_opposite_corner()
+        .to_string()
+        .to_lowercase();
+    let crossing = if first.top_leg {
+        ", crossing over the first leg"
+    } else {
+        ""
+    };
+    let after = match last.travel {
+        Some((0, 0)) => "come back up through the same hole".to_string(),
+        Some(travel) => format!("travel {}", describe_travel(travel)),
+        None => "finish the thread".to_string(),
+    };
+
+    if group.len() == 1 {
+        return format!(
+            "Row {}: bring the needle up at the {} of ({}, {}), down at the {}{}; {}.",
+            first.cell.y, corner, first.cell.x, first.cell.y, opposite, crossing, after
+        );
+    }
+
+    let location = if first.cell.y == last.cell.y {
+        format!("Row {}", first.cell.y)
+    } else if first.cell.x == last.cell.x {
+        format!("Column {}", first.cell.x)
+    } else {
+        "Diagonally".to_string()
+    };
+    let step = group[1].cell - first.cell;
+    format!(
+        "{}, ({}, {}) to ({}, {}): {} half stitches from the {} to the {}{}, \
+         moving {} each time and travelling {} between them; then {}.",
+        location,
+        first.cell.x,
+        first.cell.y,
+        last.cell.x,
+        last.cell.y,
+        group.len(),
+        corner,
+        opposite,
+        crossing,
+        // The chart counts rows downwards, so flip the step back to holes up.
+        describe_movement((step.x, -step.y), "cell", "cells"),
+        first.travel.map(describe_travel).unwrap_or_default(),
+        after
+    )
+}
+
+fn describe_travel(travel: (isize, isize)) -> String {
+    if travel == (0, 0) {
+        "back to the same hole".to_string()
+    } else {
+        describe_movement(travel, "hole", "holes")
+    }
+}
+
+/// Describe a move right and up, such as "2 holes left and 1 hole up".
+fn describe_movement((right, up): (isize, isize), unit: &str, units: &str) -> String {
+    let part = |distance: isize, positive: &str, negative: &str| {
+        (distance != 0).then(|| {
+            format!(
+                "{} {} {}",
+                distance.abs(),
+                if distance.abs() == 1 { unit } else { units },
+                if distance > 0 { positive } else { negative }
+            )
+        })
+    };
+    [part(right, "right", "left"), part(up, "up", "down")]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(" and ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn _row(cells: &[(isize, isize)]) -> Vec<HalfStitch> {
+        let cells = cells
+            .iter()
+            .map(|&(x, y)| GridCell::new(x, y))
+            .collect::<Vec<_>>();
+        HalfStitch::convert_grid_cells(
+            cells.iter(),
+            StartingStitchCorner::BottomLeft,
+            StartingStitchCorner::BottomRight,
+        )
+    }
+
+    fn _steps(text: &str) -> Vec<&str> {
+        text.lines()
+            .filter(|line| line.starts_with(|c: char| c.is_ascii_digit()))
+            .collect()
+    }
+
+    #[test]
+    fn test_single_stitch() {
+        let text = write_instructions(
+            &_row(&[(5, 7)]),
+            &HashMap::new(),
+            None,
+            InstructionFormat::PlainText,
+        );
+        assert_eq!(
+            _steps(&text),
+            [
+                "1. Row 1: bring the needle up at the bottom left of (1, 1), \
+              down at the top right; finish the thread."
+            ]
+        );
+    }
+
+    #[test]
+    fn test_run_along_a_row_is_one_step() {
+        let text = write_instructions(
+            &_row(&[(0, 0), (1, 0), (2, 0), (3, 0)]),
+            &HashMap::new(),
+            None,
+            InstructionFormat::PlainText,
+        );
+        assert_eq!(
+            _steps(&text),
+            ["1. Row 1, (1, 1) to (4, 1): 4 half stitches from the bottom left to the top right, \
+              moving 1 cell right each time and travelling 1 hole down between them; \
+              then finish the thread."]
+        );
+    }
+
+    #[test]
+    fn test_crossing_back_is_a_new_step() {
+        let text = write_instructions(
+            &_row(&[(0, 1), (1, 1), (1, 1), (0, 1), (0, 0)]),
+            &HashMap::new(),
+            None,
+            InstructionFormat::PlainText,
+        );
+        let steps = _steps(&text);
+        assert_eq!(steps.len(), 3);
+        assert!(steps[0].contains("2 half stitches from the bottom left to the top right"));
+        assert!(steps[0].ends_with("then travel 1 hole down."));
+        assert!(steps[1].contains("crossing over the first leg, moving 1 cell left each time"));
+        assert!(steps[2].starts_with("3. Row 2: bring the needle up at the bottom left of (1, 2)"));
+    }
+
+    #[test]
+    fn test_each_thread_is_a_section() {
+        let stitches = _row(&[(0, 0), (20, 0)]);
+        let text = write_instructions(
+            &stitches,
+            &HashMap::new(),
+            Some(RestartPolicy::default()),
+            InstructionFormat::Markdown,
+        );
+        assert!(text.starts_with("# Stitching instructions\n"));
+        assert!(text.contains("\n## Thread 1\n"));
+        assert!(text.contains("\n## Thread 2\n"));
+        let plain = write_instructions(
+            &stitches,
+            &HashMap::new(),
+            None,
+            InstructionFormat::PlainText,
+        );
+        assert!(plain.contains("\nThread 1\n--------\n"));
+        assert!(!plain.contains("Thread 2"));
+        assert!(_steps(&plain)[0].ends_with("travel 19 holes right and 1 hole down."));
+    }
+
+    #[test]
+    fn test_each_colour_has_its_own_threads() {
+        let stitches = _row(&[(0, 0), (5, 0), (1, 0)]);
+        let colours = HashMap::from([(GridCell::new(5, 0), 2)]);
+        let text = write_instructions(&stitches, &colours, None, InstructionFormat::PlainText);
+        assert!(text.contains("\nThread 1, colour 1\n"));
+        assert!(text.contains("\nThread 2, colour 2\n"));
+        // The first colour goes straight to its next stitch, past the other colour.
+        assert!(_steps(&text)[0].contains("travelling 1 hole down between them"));
+    }
+
+    #[test]
+    fn test_describe_movement() {
+        assert_eq!(describe_movement((-1, 0), "hole", "holes"), "1 hole left");
+        assert_eq!(
+            describe_movement((2, -3), "hole", "holes"),
+            "2 holes right and 3 holes down"
+        );
+        assert_eq!(describe_travel((0, 0)), "back to the same hole");
+    }
+}
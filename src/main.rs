@@ -1,17 +1,39 @@
+mod confetti;
+mod corner_choice;
+mod cost_model;
 mod grid;
 mod grid_cell;
+mod instructions;
+mod minimap;
+mod parking;
+mod regions;
+mod settings;
+mod spatial_index;
 mod stitch;
 mod svg_output;
 mod symbolic_sum;
+mod theme;
+mod thread_usage;
 
-use crate::grid::GridState;
-use crate::stitch::{HalfStitch, StartingStitchCorner};
+use crate::grid::{GridState, Shortcut};
+use crate::instructions::{write_instructions, InstructionFormat};
+use crate::parking::{DEFAULT_BLOCK_SIZE, DEFAULT_COLOUR};
+use crate::regions::Connectivity;
+use crate::settings::Settings;
+use crate::stitch::{Leg, RestartPolicy, StartingStitchCorner};
+use crate::svg_output::html_export::create_interactive_html;
 use crate::svg_output::svg_construction::create_graphic;
+use crate::svg_output::svg_options::{ArrowStyle, SvgOptions, DEFAULT_TILE_SIZE};
+use crate::svg_output::tiling::create_tiles;
+use crate::theme::{ColourTheme, THREAD_COLOURS};
+use crate::thread_usage::{describe_thread_estimates, Fabric, FabricGrid};
 use grid_cell::GridCell;
-use iced::widget::{button, checkbox, column, container, pick_list, row};
-use iced::{Element, Fill, Task, Theme};
+use iced::widget::{button, checkbox, column, container, pick_list, row, slider, text};
+use iced::{Element, Fill, Task};
 use log::error;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use svg::node::element::Description;
 
 fn main() -> iced::Result {
     iced::application(
@@ -19,10 +41,10 @@ fn main() -> iced::Result {
         CrossStitchSolver::update,
         CrossStitchSolver::view,
     )
-    .theme(|_| Theme::Dark)
+    .theme(|solver| solver.grid_state.theme.iced_theme())
     .antialiasing(true)
     .centered()
-    .run()
+    .run_with(|| (CrossStitchSolver::new(Settings::load()), Task::none()))
 }
 
 #[derive(Debug, Clone)]
@@ -30,19 +52,172 @@ pub enum Message {
     Grid(grid::Message),
     ClearGrid,
     ChangeCalculationSpecificity(bool),
+    ToggleConfetti(bool),
     ChangeBottomStitchCorner(StartingStitchCorner),
     ChangeTopStitchCorner(StartingStitchCorner),
+    ChangeConsistentTopLegs(bool),
+    OptimiseCorners,
+    ChangeTheme(ColourTheme),
+    ChangeFabric(Fabric),
+    ChangeStitchOver(usize),
+    ChangeStrands(usize),
+    ChangeCutLongTravel(bool),
+    ChangeMaxTravelDistance(f64),
+    /// The maximum travel slider was let go, so the distance it was moved to can be saved.
+    MaxTravelDistanceChosen,
+    ChangeRestartPenalty(usize),
+    PinBaseline,
+    ClearBaseline,
+    ChangeConnectivity(Connectivity),
+    SequenceByRegion,
+    ChangeThreadColour(usize),
+    ChangeParkingBlockSize(usize),
+    SequenceByParking,
+    ChangeSvgOptions(SvgOptions),
+    ToggleSettings,
+    ResetSettings,
     GenerateSVG,
+    SaveSVG(Option<PathBuf>),
+}
+
+impl Message {
+    /// Whether the message changes a setting that is saved between launches.
+    /// Dragging the maximum travel slider sends a message for every step,
+    /// so its setting is only saved once the slider is let go.
+    fn changes_settings(&self) -> bool {
+        matches!(
+            self,
+            Message::Grid(grid::Message::Shortcut(
+                Shortcut::CycleBottomStitchCorner | Shortcut::CycleTopStitchCorner
+            )) | Message::ChangeCalculationSpecificity(_)
+                | Message::ChangeBottomStitchCorner(_)
+                | Message::ChangeTopStitchCorner(_)
+                | Message::ChangeConsistentTopLegs(_)
+                | Message::ChangeTheme(_)
+                | Message::ChangeFabric(_)
+                | Message::ChangeStitchOver(_)
+                | Message::ChangeStrands(_)
+                | Message::ChangeCutLongTravel(_)
+                | Message::MaxTravelDistanceChosen
+                | Message::ChangeRestartPenalty(_)
+                | Message::ChangeSvgOptions(_)
+                | Message::ResetSettings
+                | Message::SaveSVG(Some(_))
+        )
+    }
 }
 
 #[derive(Debug, Default)]
 struct CrossStitchSolver {
     grid_state: GridState,
+
+    /// Whether the settings panel is shown.
+    show_settings: bool,
+
+    /// How exported SVGs are drawn.
+    svg_options: SvgOptions,
+
+    /// Which cells count as touching when the selection is sequenced by region.
+    connectivity: Connectivity,
+
+    /// The width and height in cells of the blocks stitched in turn when parking threads.
+    parking_block_size: usize,
+
+    /// Where the last SVG was exported to, so the next export starts there.
+    last_export_directory: Option<PathBuf>,
+
+    /// The settings as they were last saved, so they are only written when they change.
+    saved_settings: Settings,
 }
 
 impl CrossStitchSolver {
+    fn new(settings: Settings) -> Self {
+        let mut solver = CrossStitchSolver {
+            saved_settings: settings.clone(),
+            parking_block_size: DEFAULT_BLOCK_SIZE,
+            ..Default::default()
+        };
+        solver.apply_settings(settings);
+        solver
+    }
+
+    fn apply_settings(&mut self, settings: Settings) {
+        let grid_state = &mut self.grid_state;
+        grid_state.bottom_stitch_corner = settings.bottom_stitch_corner;
+        grid_state.top_stitch_corner = settings.top_stitch_corner;
+        grid_state.consistent_top_legs = settings.consistent_top_legs;
+        grid_state.precise_cost = settings.precise_cost;
+        grid_state.fabric = settings.fabric;
+        grid_state.stitch_over = settings.stitch_over;
+        grid_state.strands = settings.strands;
+        grid_state.cut_long_travel = settings.cut_long_travel;
+        grid_state.restart_policy = settings.restart_policy;
+        grid_state.change_theme(settings.theme);
+        grid_state.update_stitches();
+        self.svg_options = settings.svg_options;
+        self.last_export_directory = settings.last_export_directory;
+    }
+
+    fn settings(&self) -> Settings {
+        Settings {
+            bottom_stitch_corner: self.grid_state.bottom_stitch_corner,
+            top_stitch_corner: self.grid_state.top_stitch_corner,
+            precise_cost: self.grid_state.precise_cost,
+            theme: self.grid_state.theme,
+            fabric: self.grid_state.fabric,
+            stitch_over: self.grid_state.stitch_over,
+            strands: self.grid_state.strands,
+            cut_long_travel: self.grid_state.cut_long_travel,
+            consistent_top_legs: self.grid_state.consistent_top_legs,
+            restart_policy: self.grid_state.restart_policy,
+            svg_options: self.svg_options.clone(),
+            last_export_directory: self.last_export_directory.clone(),
+        }
+    }
+
     fn update(&mut self, message: Message) -> Task<Message> {
+        let changes_settings = message.changes_settings();
+        let task = self.handle_message(message);
+        if changes_settings {
+            self.save_settings();
+        }
+        task
+    }
+
+    /// Write the settings to the settings file, if they have changed since they were last saved.
+    fn save_settings(&mut self) {
+        let settings = self.settings();
+        if settings != self.saved_settings {
+            settings.save();
+            self.saved_settings = settings;
+        }
+    }
+
+    fn handle_message(&mut self, message: Message) -> Task<Message> {
         match message {
+            Message::Grid(grid::Message::Shortcut(shortcut)) => {
+                let message = match shortcut {
+                    Shortcut::Clear => Message::ClearGrid,
+                    Shortcut::Export => Message::GenerateSVG,
+                    Shortcut::CycleBottomStitchCorner => Message::ChangeBottomStitchCorner(
+                        self.grid_state.bottom_stitch_corner.next(),
+                    ),
+                    Shortcut::CycleTopStitchCorner => {
+                        let [first, second] = self
+                            .grid_state
+                            .bottom_stitch_corner
+                            .get_possible_top_stitch_corners();
+                        Message::ChangeTopStitchCorner(
+                            if self.grid_state.top_stitch_corner == first {
+                                second
+                            } else {
+                                first
+                            },
+                        )
+                    }
+                };
+                return self.handle_message(message);
+            }
             Message::Grid(message) => {
                 self.grid_state.update(message);
             }
@@ -50,43 +225,334 @@ impl CrossStitchSolver {
             Message::ChangeCalculationSpecificity(check_box) => {
                 self.grid_state.precise_cost = check_box;
             }
+            Message::ToggleConfetti(check_box) => {
+                self.grid_state.highlight_confetti = check_box;
+                self.grid_state.update_stitches();
+            }
             Message::ChangeBottomStitchCorner(first_stitch_corner) => {
                 self.grid_state.bottom_stitch_corner = first_stitch_corner;
                 self.grid_state.top_stitch_corner =
                     first_stitch_corner.get_possible_top_stitch_corners()[0];
-                self.grid_state.clear_cache();
+                // Legs flipped by hand may no longer cross the new top legs.
+                self.grid_state.program_state.corner_overrides.clear();
+                self.grid_state.update_stitches();
             }
             Message::ChangeTopStitchCorner(second_stitch_corner) => {
                 self.grid_state.top_stitch_corner = second_stitch_corner;
+                self.grid_state.program_state.corner_overrides.clear();
+                self.grid_state.update_stitches();
+            }
+            Message::ChangeConsistentTopLegs(check_box) => {
+                self.grid_state.consistent_top_legs = check_box;
+                self.grid_state.update_stitches();
+            }
+            Message::OptimiseCorners => self.grid_state.optimise_corners(),
+            Message::ChangeTheme(theme) => self.grid_state.change_theme(theme),
+            Message::ChangeFabric(fabric) => {
+                self.grid_state.fabric = fabric;
+                self.update_fabric();
+            }
+            Message::ChangeStitchOver(stitch_over) => {
+                self.grid_state.stitch_over = stitch_over;
+                self.update_fabric();
+            }
+            Message::ChangeStrands(strands) => {
+                self.grid_state.strands = strands;
                 self.grid_state.clear_cache();
             }
+            Message::ChangeCutLongTravel(check_box) => {
+                self.grid_state.cut_long_travel = check_box;
+                self.grid_state.update_stitches();
+            }
+            Message::ChangeMaxTravelDistance(distance) => {
+                self.grid_state.restart_policy.max_travel_distance = distance;
+                self.grid_state.update_stitches();
+            }
+            // The settings are saved once the message has been handled.
+            Message::MaxTravelDistanceChosen => {}
+            Message::ChangeRestartPenalty(penalty) => {
+                self.grid_state.restart_policy.restart_penalty = penalty;
+                self.grid_state.update_stitches();
+            }
+            Message::PinBaseline => self.grid_state.pin_baseline(),
+            Message::ClearBaseline => self.grid_state.baseline = None,
+            Message::ChangeConnectivity(connectivity) => self.connectivity = connectivity,
+            Message::SequenceByRegion => self.grid_state.sequence_by_region(self.connectivity),
+            Message::ChangeThreadColour(colour) => self.grid_state.thread_colour = colour,
+            Message::ChangeParkingBlockSize(size) => self.parking_block_size = size,
+            Message::SequenceByParking => {
+                self.grid_state.sequence_by_parking(self.parking_block_size)
+            }
+            Message::ChangeSvgOptions(options) => self.svg_options = options,
+            Message::ToggleSettings => self.show_settings = !self.show_settings,
+            Message::ResetSettings => self.apply_settings(Settings {
+                last_export_directory: self.last_export_directory.clone(),
+                ..Settings::default()
+            }),
             Message::GenerateSVG => {
-                let stitches = HalfStitch::convert_grid_cells(
-                    self.grid_state.program_state.selected_cells.iter(),
-                    self.grid_state.bottom_stitch_corner,
-                    self.grid_state.top_stitch_corner,
+                let mut dialog = rfd::AsyncFileDialog::new()
+                    .set_title("Export SVG")
+                    .set_file_name("stitches.svg")
+                    .add_filter("SVG", &["svg"])
+                    .add_filter("Interactive HTML", &["html"])
+                    .add_filter("Text instructions", &["txt"])
+                    .add_filter("Markdown instructions", &["md"]);
+                if let Some(directory) = &self.last_export_directory {
+                    dialog = dialog.set_directory(directory);
+                }
+                return Task::perform(
+                    async move {
+                        dialog
+                            .save_file()
+                            .await
+                            .map(|file| file.path().to_path_buf())
+                    },
+                    Message::SaveSVG,
                 );
-                let document = create_graphic(&stitches);
-                svg::save("stitches.svg", &document)
-                    .unwrap_or_else(|_| error!("Failed to write SVG file"));
+            }
+            // The export dialog was cancelled.
+            Message::SaveSVG(None) => {}
+            Message::SaveSVG(Some(path)) => {
+                let stitches = self.grid_state.stitches();
+                let thread_estimates = self.grid_state.thread_estimates(&stitches);
+                let summary =
+                    describe_thread_estimates(&thread_estimates, self.grid_state.fabric_grid())
+                        .join("; ");
+                let description = Description::new().add(svg::node::Text::new(summary.as_str()));
+                let restart_policy = self.grid_state.active_restart_policy();
+                let colours = &self.grid_state.program_state.cell_colours;
+                let palette = self.grid_state.theme.palette();
+                let file_stem = path
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| "stitches".to_string());
+                let extension = path
+                    .extension()
+                    .map(|extension| extension.to_string_lossy().to_lowercase());
+                match (extension.as_deref(), self.svg_options.tile_size) {
+                    (Some(extension @ ("txt" | "md")), _) => {
+                        let format = if extension == "md" {
+                            InstructionFormat::Markdown
+                        } else {
+                            InstructionFormat::PlainText
+                        };
+                        let instructions =
+                            write_instructions(&stitches, colours, restart_policy, format);
+                        std::fs::write(&path, instructions)
+                            .unwrap_or_else(|_| error!("Failed to write instructions"));
+                    }
+                    (Some("html"), _) => {
+                        let html = create_interactive_html(
+                            &stitches,
+                            colours,
+                            restart_policy,
+                            &palette,
+                            &self.svg_options,
+                            &file_stem,
+                            &summary,
+                        );
+                        std::fs::write(&path, html)
+                            .unwrap_or_else(|_| error!("Failed to write HTML file"));
+                    }
+                    (_, None) => {
+                        let document = create_graphic(
+                            &stitches,
+                            colours,
+                            restart_policy,
+                            &palette,
+                            &self.svg_options,
+                        )
+                        .add(description);
+                        svg::save(&path, &document)
+                            .unwrap_or_else(|_| error!("Failed to write SVG file"));
+                    }
+                    // The chosen file becomes the index, with the tiles and a web page beside it.
+                    (_, Some(tile_size)) => {
+                        let tiled = create_tiles(
+                            &stitches,
+                            colours,
+                            restart_policy,
+                            &palette,
+                            &self.svg_options,
+                            tile_size,
+                            &file_stem,
+                        );
+                        for tile in &tiled.tiles {
+                            svg::save(path.with_file_name(&tile.file_name), &tile.document)
+                                .unwrap_or_else(|_| {
+                                    error!("Failed to write SVG tile {}", tile.file_name)
+                                });
+                        }
+                        svg::save(&path, &tiled.index_svg.add(description))
+                            .unwrap_or_else(|_| error!("Failed to write SVG file"));
+                        std::fs::write(path.with_extension("html"), tiled.index_html)
+                            .unwrap_or_else(|_| error!("Failed to write tile index"));
+                    }
+                }
+                self.last_export_directory = path.parent().map(|directory| directory.to_path_buf());
             }
         }
         Task::none()
     }
-    fn view(&self) -> Element<Message> {
+    fn view(&self) -> Element<'_, Message> {
+        let mut controls = column![
+            row![
+                button("Clear")
+                    .on_press(Message::ClearGrid)
+                    .style(button::danger),
+                button("Fit Selection").on_press(Message::Grid(grid::Message::FitSelection)),
+                button("Reset View").on_press(Message::Grid(grid::Message::ResetView)),
+                button(if self.show_settings {
+                    "Hide Settings"
+                } else {
+                    "Settings"
+                })
+                .on_press(Message::ToggleSettings),
+            ]
+            .spacing(5),
+            row![
+                button("Pin Baseline").on_press(Message::PinBaseline),
+                button("Clear Baseline").on_press_maybe(
+                    self.grid_state
+                        .baseline
+                        .as_ref()
+                        .map(|_| Message::ClearBaseline)
+                ),
+            ]
+            .spacing(5),
+            row![
+                button("Optimise Corners").on_press(Message::OptimiseCorners),
+                button("Sequence by Region").on_press(Message::SequenceByRegion),
+                pick_list(
+                    Connectivity::ALL,
+                    Some(&self.connectivity),
+                    Message::ChangeConnectivity
+                ),
+            ]
+            .spacing(5),
+            row![
+                "Colour: ",
+                pick_list(
+                    (1..=THREAD_COLOURS).collect::<Vec<_>>(),
+                    Some(self.grid_state.thread_colour),
+                    Message::ChangeThreadColour
+                ),
+                button("Sequence by Parking").on_press(Message::SequenceByParking),
+                pick_list(
+                    [5, 10, 20, 50],
+                    Some(self.parking_block_size),
+                    Message::ChangeParkingBlockSize
+                ),
+            ]
+            .spacing(5),
+            button("Create SVG").on_press(Message::GenerateSVG),
+        ]
+        .width(Fill);
+        if self.show_settings {
+            controls = controls.push(self.settings_panel());
+        }
+        let content = column![
+            self.grid_state.view().map(Message::Grid),
+            row![controls, self.grid_state.minimap().map(Message::Grid)].spacing(10),
+        ]
+        .height(Fill);
+
+        container(content).width(Fill).height(Fill).into()
+    }
+
+    /// Keep the exported size and the fabric grid in step with a change of fabric.
+    fn update_fabric(&mut self) {
+        if self.svg_options.cell_size_mm.is_some() {
+            self.svg_options.cell_size_mm = Some(self.grid_state.fabric_grid().cell_size_mm());
+        }
+        self.grid_state.clear_cache();
+    }
+
+    /// The controls for how exported SVGs are drawn.
+    fn svg_options_panel(&self) -> Element<'_, Message> {
+        let options = &self.svg_options;
+        let change = |change_options: fn(&mut SvgOptions, bool)| {
+            move |value| {
+                let mut options = options.clone();
+                change_options(&mut options, value);
+                Message::ChangeSvgOptions(options)
+            }
+        };
+        let fabric = self.grid_state.fabric_grid();
+        row![
+            "SVG: ",
+            checkbox("Numbers", options.sequence_numbers)
+                .on_toggle(change(|options, value| options.sequence_numbers = value)),
+            checkbox("Travel", options.travel_lines)
+                .on_toggle(change(|options, value| options.travel_lines = value)),
+            checkbox("Intersection Mask", options.intersection_mask)
+                .on_toggle(change(|options, value| options.intersection_mask = value)),
+            checkbox("Legend", options.legend)
+                .on_toggle(change(|options, value| options.legend = value)),
+            checkbox("Confetti", options.confetti)
+                .on_toggle(change(|options, value| options.confetti = value)),
+            checkbox("Fabric Size", options.cell_size_mm.is_some()).on_toggle(move |value| {
+                Message::ChangeSvgOptions(SvgOptions {
+                    cell_size_mm: value.then(|| fabric.cell_size_mm()),
+                    ..options.clone()
+                })
+            }),
+            checkbox("Tiles", options.tile_size.is_some()).on_toggle(move |value| {
+                Message::ChangeSvgOptions(SvgOptions {
+                    tile_size: value.then_some(DEFAULT_TILE_SIZE),
+                    ..options.clone()
+                })
+            }),
+            pick_list([10, 20, 50], options.tile_size, |tile_size| {
+                Message::ChangeSvgOptions(SvgOptions {
+                    tile_size: Some(tile_size),
+                    ..options.clone()
+                })
+            })
+            .placeholder("Tile Size"),
+            "Arrows: ",
+            pick_list(ArrowStyle::ALL, Some(&options.arrow_style), |arrow_style| {
+                Message::ChangeSvgOptions(SvgOptions {
+                    arrow_style,
+                    ..options.clone()
+                })
+            }),
+            "Font Size: ",
+            pick_list(
+                [30, 40, 50, 60, 80, 100],
+                Some(options.font_size as usize),
+                |font_size| Message::ChangeSvgOptions(SvgOptions {
+                    font_size: font_size as f64,
+                    ..options.clone()
+                })
+            ),
+        ]
+        .spacing(5)
+        .into()
+    }
+
+    /// The controls for the settings that are saved between launches.
+    fn settings_panel(&self) -> Element<'_, Message> {
         let bottom_stitch_directions = [
             StartingStitchCorner::BottomLeft,
             StartingStitchCorner::BottomRight,
             StartingStitchCorner::TopLeft,
             StartingStitchCorner::TopRight,
         ];
-        let content = column![
-            self.grid_state.view().map(Message::Grid),
-            button("Clear")
-                .on_press(Message::ClearGrid)
-                .style(button::danger),
-            checkbox("Precise Cost", self.grid_state.precise_cost)
-                .on_toggle(Message::ChangeCalculationSpecificity),
+        column![
+            row![
+                checkbox("Precise Cost", self.grid_state.precise_cost)
+                    .on_toggle(Message::ChangeCalculationSpecificity),
+                checkbox("Highlight Confetti", self.grid_state.highlight_confetti)
+                    .on_toggle(Message::ToggleConfetti),
+                "Theme: ",
+                pick_list(
+                    ColourTheme::ALL,
+                    Some(&self.grid_state.theme),
+                    Message::ChangeTheme
+                ),
+            ]
+            .spacing(5),
             row![
                 "Bottom Stitch Start Corner: ",
                 pick_list(
@@ -102,70 +568,153 @@ impl CrossStitchSolver {
                     Some(&self.grid_state.top_stitch_corner),
                     Message::ChangeTopStitchCorner
                 ),
+                checkbox("Consistent Top Legs", self.grid_state.consistent_top_legs)
+                    .on_toggle(Message::ChangeConsistentTopLegs),
             ]
             .spacing(5)
             .width(Fill),
-            button("Create SVG").on_press(Message::GenerateSVG),
+            row![
+                "Fabric: ",
+                pick_list(
+                    Fabric::ALL,
+                    Some(&self.grid_state.fabric),
+                    Message::ChangeFabric
+                ),
+                "Strands: ",
+                pick_list(
+                    [1, 2, 3, 4, 5, 6],
+                    Some(&self.grid_state.strands),
+                    Message::ChangeStrands
+                ),
+            ]
+            // Aida is always stitched over one block.
+            .push_maybe((!self.grid_state.fabric.is_aida()).then(|| {
+                row![
+                    "Over: ",
+                    pick_list(
+                        (1..=FabricGrid::MAX_OVER).collect::<Vec<_>>(),
+                        Some(self.grid_state.stitch_over),
+                        Message::ChangeStitchOver
+                    ),
+                ]
+                .spacing(5)
+            }))
+            .spacing(5)
+            .width(Fill),
+            row![
+                checkbox("Cut Long Travel", self.grid_state.cut_long_travel)
+                    .on_toggle(Message::ChangeCutLongTravel),
+                text!(
+                    "Max Travel: {:.1}",
+                    self.grid_state.restart_policy.max_travel_distance
+                ),
+                slider(
+                    RestartPolicy::MIN_TRAVEL..=RestartPolicy::MAX_TRAVEL,
+                    self.grid_state.restart_policy.max_travel_distance,
+                    Message::ChangeMaxTravelDistance
+                )
+                .on_release(Message::MaxTravelDistanceChosen)
+                .step(0.5)
+                .width(200),
+                "Restart Penalty: ",
+                pick_list(
+                    RestartPolicy::PENALTIES,
+                    Some(&self.grid_state.restart_policy.restart_penalty),
+                    Message::ChangeRestartPenalty
+                ),
+            ]
+            .spacing(5)
+            .width(Fill),
+            self.svg_options_panel(),
+            button("Reset to Defaults").on_press(Message::ResetSettings),
         ]
-        .height(Fill);
-
-        container(content).width(Fill).height(Fill).into()
+        .spacing(5)
+        .into()
     }
 }
 
 #[derive(Debug, Clone, Default)]
 struct ProgramState {
-    pub selected_cells: VecDeque<GridCell>,
-    cell_counts: HashMap<GridCell, usize>,
+    /// The selected cells in the order they were selected, keyed by when they were selected,
+    /// so a leg can be removed from anywhere in the sequence without shifting the rest.
+    pub selected_cells: BTreeMap<usize, GridCell>,
+
+    /// The keys of the legs in each selected cell, the bottom leg first.
+    cell_keys: HashMap<GridCell, Vec<usize>>,
+
+    /// The key given to the next leg selected.
+    next_key: usize,
+
+    /// The thread colour of each selected cell, counting from 1.
+    pub cell_colours: HashMap<GridCell, usize>,
+
+    /// The corners of legs that don't start from the corner chosen for every cell.
+    pub corner_overrides: HashMap<(GridCell, Leg), StartingStitchCorner>,
 }
 
 impl ProgramState {
-    fn select_cell(&mut self, cell: GridCell) {
-        match self.cell_counts.get(&cell).unwrap_or(&0) {
+    /// Select the cell again, returning the leg that was added and its key if it changed.
+    fn select_cell(&mut self, cell: GridCell) -> Option<(Leg, usize)> {
+        let keys = self.cell_keys.entry(cell).or_default();
+        let leg = match keys.len() {
+            0 => Leg::Bottom,
+            1 => Leg::Top,
+            _ => return None,
+        };
+        let key = self.next_key;
+        self.next_key += 1;
+        keys.push(key);
+        self.selected_cells.insert(key, cell);
+        Some((leg, key))
+    }
+
+    /// Unselect the cell once, returning the key of the leg that was removed.
+    /// The top leg is removed first.
+    fn unselect_cell(&mut self, cell: GridCell) -> Option<usize> {
+        let keys = self.cell_keys.get_mut(&cell)?;
+        let key = keys.pop()?;
+        match keys.len() {
             0 => {
-                self.cell_counts.insert(cell, 1);
-                self.selected_cells.push_back(cell);
+                self.cell_keys.remove(&cell);
+                self.cell_colours.remove(&cell);
+                self.corner_overrides.remove(&(cell, Leg::Bottom));
             }
-            1 => {
-                self.cell_counts.insert(cell, 2);
-                self.selected_cells.push_back(cell);
+            _ => {
+                self.corner_overrides.remove(&(cell, Leg::Top));
             }
-            _ => {}
         }
+        self.selected_cells.remove(&key);
+        Some(key)
     }
 
-    fn unselect_cell(&mut self, cell: GridCell) {
-        match self.cell_counts.get(&cell).unwrap_or(&0) {
-            1 => {
-                self.cell_counts.remove(&cell);
-                let first_position = self
-                    .selected_cells
-                    .iter()
-                    .position(|&x| x == cell)
-                    .unwrap_or_else(|| {
-                        panic!("Cell {:?} in cell count map but not in vector ", cell)
-                    });
-                self.selected_cells.remove(first_position);
-            }
-            2 => {
-                self.cell_counts.insert(cell, 1);
-                self._remove_last_cell_in_vec(cell);
-            }
-            _ => {}
-        }
-    }
-    fn _remove_last_cell_in_vec(&mut self, cell: GridCell) {
-        let reversed_position = self
-            .selected_cells
-            .iter()
-            .rev()
-            .position(|&x| x == cell)
-            .unwrap();
-        let real_position = self.selected_cells.len() - reversed_position - 1;
-        self.selected_cells.remove(real_position);
+    /// How many times the cell is selected.
+    fn selection_count(&self, cell: GridCell) -> usize {
+        self.cell_keys.get(&cell).map_or(0, |keys| keys.len())
     }
+
     pub fn clear(&mut self) {
         self.selected_cells.clear();
-        self.cell_counts.clear();
+        self.cell_keys.clear();
+        self.next_key = 0;
+        self.cell_colours.clear();
+        self.corner_overrides.clear();
+    }
+
+    /// Select the cells again in a new order, keeping their colours.
+    /// Any flipped legs are put back, since they were chosen for the old order.
+    fn replace_sequence(&mut self, cells: impl Iterator<Item = GridCell>) {
+        let colours = std::mem::take(&mut self.cell_colours);
+        self.clear();
+        for cell in cells {
+            self.select_cell(cell);
+        }
+        self.cell_colours = colours;
+    }
+
+    fn colour_of(&self, cell: GridCell) -> usize {
+        self.cell_colours
+            .get(&cell)
+            .copied()
+            .unwrap_or(DEFAULT_COLOUR)
     }
 }
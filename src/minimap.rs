@@ -0,0 +1,295 @@
+use crate::grid::{GridState, Message};
+use crate::grid_cell::GridCell;
+use iced::event::Status;
+use iced::mouse::Cursor;
+use iced::widget::canvas::{Cache, Event, Frame, Geometry, Path, Stroke, Style};
+use iced::widget::{canvas, Canvas};
+use iced::{mouse, Element, Point, Rectangle, Renderer, Size, Theme, Vector};
+use std::collections::BTreeMap;
+
+/// The smallest rectangle of cells covering the selection, kept up to date as cells change.
+/// Each column and row is counted, so the bounds shrink again when the last cell on an edge
+/// is unselected without looking at every other cell.
+#[derive(Debug, Clone, Default)]
+pub struct SelectionBounds {
+    columns: BTreeMap<isize, usize>,
+    rows: BTreeMap<isize, usize>,
+}
+
+impl SelectionBounds {
+    pub fn add(&mut self, cell: GridCell) {
+        *self.columns.entry(cell.x).or_default() += 1;
+        *self.rows.entry(cell.y).or_default() += 1;
+    }
+
+    pub fn remove(&mut self, cell: GridCell) {
+        for (map, value) in [(&mut self.columns, cell.x), (&mut self.rows, cell.y)] {
+            if let Some(count) = map.get_mut(&value) {
+                *count -= 1;
+                if *count == 0 {
+                    map.remove(&value);
+                }
+            }
+        }
+    }
+
+    /// The bottom left and top right cells, if any cells are selected.
+    pub fn min_max(&self) -> Option<(GridCell, GridCell)> {
+        let (left, right) = (
+            self.columns.keys().next()?,
+            self.columns.keys().next_back()?,
+        );
+        let (bottom, top) = (self.rows.keys().next()?, self.rows.keys().next_back()?);
+        Some((GridCell::new(*left, *bottom), GridCell::new(*right, *top)))
+    }
+}
+
+/// An overview of the whole design, showing which part is visible on the main grid.
+/// Clicking or dragging in the overview moves the main grid to that point.
+pub struct Minimap<'a> {
+    pub grid_state: &'a GridState,
+
+    /// Cache for the drawn cells, which only change when cells are selected.
+    pub cell_cache: &'a Cache,
+}
+
+impl<'a> Minimap<'a> {
+    const WIDTH: f32 = 200.0;
+    const HEIGHT: f32 = 150.0;
+
+    /// The smallest area shown, in cells, so a small design isn't blown up to fill the overview.
+    const MIN_EXTENT_CELLS: isize = 40;
+
+    pub fn view(self) -> Element<'a, Message> {
+        Canvas::new(self)
+            .width(Self::WIDTH)
+            .height(Self::HEIGHT)
+            .into()
+    }
+
+    /// The area of the grid shown in the overview, covering every selected cell.
+    fn extent(&self) -> Rectangle {
+        let (min, max) = self
+            .grid_state
+            .selection_bounds
+            .min_max()
+            .unwrap_or((GridCell::new(0, 0), GridCell::new(0, 0)));
+        let cell_extent = |low: isize, high: isize| {
+            let padding = (Self::MIN_EXTENT_CELLS - (high - low)).max(2) / 2;
+            (low - padding, high + 1 + padding)
+        };
+        let (left, right) = cell_extent(min.x, max.x);
+        let (bottom, top) = cell_extent(min.y, max.y);
+
+        // The y-axis of the grid points down the screen, so the cells are flipped.
+        let cell_size = GridCell::SIZE as f32;
+        Rectangle {
+            x: left as f32 * cell_size,
+            y: -top as f32 * cell_size,
+            width: (right - left) as f32 * cell_size,
+            height: (top - bottom) as f32 * cell_size,
+        }
+    }
+}
+
+/// Maps between points on the grid and points in the overview.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Projection {
+    extent: Rectangle,
+    scale: f32,
+    offset: Vector,
+}
+
+impl Projection {
+    /// Fit the extent into the overview, keeping its aspect ratio.
+    fn new(extent: Rectangle, size: Size) -> Self {
+        let scale = (size.width / extent.width).min(size.height / extent.height);
+        let offset = Vector::new(
+            (size.width - extent.width * scale) / 2.0,
+            (size.height - extent.height * scale) / 2.0,
+        );
+        Projection {
+            extent,
+            scale,
+            offset,
+        }
+    }
+
+    fn to_overview(self, point: Point) -> Point {
+        Point::new(
+            (point.x - self.extent.x) * self.scale,
+            (point.y - self.extent.y) * self.scale,
+        ) + self.offset
+    }
+
+    fn to_grid(self, point: Point) -> Point {
+        let point = point - self.offset;
+        Point::new(
+            point.x / self.scale + self.extent.x,
+            point.y / self.scale + self.extent.y,
+        )
+    }
+
+    /// The translation of the main grid that centres the view on the given point of the overview.
+    fn translation_to_centre(self, point: Point) -> Vector {
+        Point::ORIGIN - self.to_grid(point)
+    }
+}
+
+impl canvas::Program<Message> for Minimap<'_> {
+    /// Whether the view is being dragged around the overview.
+    type State = bool;
+
+    fn update(
+        &self,
+        dragging: &mut Self::State,
+        event: Event,
+        bounds: Rectangle,
+        cursor: Cursor,
+    ) -> (Status, Option<Message>) {
+        if let Event::Mouse(mouse::Event::ButtonReleased(mouse::Button::Left)) = event {
+            *dragging = false;
+        }
+        let Some(position) = cursor.position_in(bounds) else {
+            return (Status::Ignored, None);
+        };
+        let projection = Projection::new(self.extent(), bounds.size());
+
+        match event {
+            Event::Mouse(mouse::Event::ButtonPressed(mouse::Button::Left)) => {
+                *dragging = true;
+                let translation = projection.translation_to_centre(position);
+                (Status::Captured, Some(Message::Translated(translation)))
+            }
+            Event::Mouse(mouse::Event::CursorMoved { .. }) if *dragging => {
+                let translation = projection.translation_to_centre(position);
+                (Status::Captured, Some(Message::Translated(translation)))
+            }
+            _ => (Status::Ignored, None),
+        }
+    }
+
+    fn draw(
+        &self,
+        _state: &Self::State,
+        renderer: &Renderer,
+        _theme: &Theme,
+        bounds: Rectangle,
+        _cursor: Cursor,
+    ) -> Vec<Geometry<Renderer>> {
+        let projection = Projection::new(self.extent(), bounds.size());
+        let palette = self.grid_state.theme.palette();
+
+        let cells = self.cell_cache.draw(renderer, bounds.size(), |frame| {
+            let background = Path::rectangle(Point::ORIGIN, frame.size());
+            frame.fill(&background, palette.background);
+
+            // Keep every cell at least a pixel wide so small cells don't disappear.
+            let grid_cell_size = GridCell::SIZE as f32;
+            let cell_size = (grid_cell_size * projection.scale).max(1.0);
+            for cell in self.grid_state.program_state.selected_cells.values() {
+                let top_left = Point::new(
+                    cell.x as f32 * grid_cell_size,
+                    -(cell.y + 1) as f32 * grid_cell_size,
+                );
+                frame.fill_rectangle(
+                    projection.to_overview(top_left),
+                    Size::new(cell_size, cell_size),
+                    palette.thread_cell(self.grid_state.program_state.colour_of(*cell)),
+                );
+            }
+        });
+
+        let visible_region = {
+            let mut frame = Frame::new(renderer, bounds.size());
+            let region = self.grid_state.visible_rectangle();
+            let top_left = projection.to_overview(region.position());
+            let size = Size::new(
+                region.width * projection.scale,
+                region.height * projection.scale,
+            );
+            frame.stroke(
+                &Path::rectangle(top_left, size),
+                Stroke {
+                    width: 2.0,
+                    style: Style::Solid(palette.cursor),
+                    ..Default::default()
+                },
+            );
+            frame.into_geometry()
+        };
+
+        vec![cells, visible_region]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_projection_round_trip() {
+        let extent = Rectangle::new(Point::new(-100.0, -60.0), Size::new(400.0, 200.0));
+        let projection = Projection::new(extent, Size::new(200.0, 150.0));
+        assert_eq!(projection.scale, 0.5);
+        let point = Point::new(30.0, 10.0);
+        assert_eq!(projection.to_grid(projection.to_overview(point)), point);
+    }
+
+    #[test]
+    fn test_projection_centres_extent() {
+        let extent = Rectangle::new(Point::new(0.0, 0.0), Size::new(100.0, 100.0));
+        let projection = Projection::new(extent, Size::new(200.0, 100.0));
+        assert_eq!(projection.to_overview(Point::ORIGIN), Point::new(50.0, 0.0));
+    }
+
+    #[test]
+    fn test_clicking_centre_of_overview_centres_extent() {
+        let extent = Rectangle::new(Point::new(20.0, -80.0), Size::new(100.0, 100.0));
+        let projection = Projection::new(extent, Size::new(100.0, 100.0));
+        let translation = projection.translation_to_centre(Point::new(50.0, 50.0));
+        assert_eq!(translation, Vector::new(-70.0, 30.0));
+    }
+
+    #[test]
+    fn test_bounds_shrink_when_edge_cell_unselected() {
+        let mut bounds = SelectionBounds::default();
+        let cells = [
+            GridCell::new(0, 0),
+            GridCell::new(5, -2),
+            GridCell::new(5, -2),
+        ];
+        for cell in cells {
+            bounds.add(cell);
+        }
+        bounds.remove(GridCell::new(5, -2));
+        assert_eq!(
+            bounds.min_max(),
+            Some((GridCell::new(0, -2), GridCell::new(5, 0)))
+        );
+        bounds.remove(GridCell::new(5, -2));
+        assert_eq!(
+            bounds.min_max(),
+            Some((GridCell::new(0, 0), GridCell::new(0, 0)))
+        );
+        bounds.remove(GridCell::new(0, 0));
+        assert_eq!(bounds.min_max(), None);
+    }
+
+    #[test]
+    fn test_extent_covers_selected_cells() {
+        let mut grid_state = GridState::default();
+        grid_state.update(Message::Select(GridCell::new(-50, 3)));
+        grid_state.update(Message::Select(GridCell::new(20, 70)));
+        let cache = Cache::default();
+        let minimap = Minimap {
+            grid_state: &grid_state,
+            cell_cache: &cache,
+        };
+        let extent = minimap.extent();
+        for cell in [GridCell::new(-50, 3), GridCell::new(20, 70)] {
+            let centre = Point::new(cell.x as f32 * 20.0 + 10.0, -cell.y as f32 * 20.0 - 10.0);
+            assert!(extent.contains(centre));
+        }
+    }
+}
@@ -0,0 +1,390 @@
+use crate::grid_cell::GridCell;
+use crate::regions::{region_variants, travel};
+use crate::stitch::{HalfStitch, RestartPolicy, SequenceCost, StartingStitchCorner};
+use itertools::Itertools;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fmt::Formatter;
+
+/// The colour of a cell that hasn't been given one.
+pub const DEFAULT_COLOUR: usize = 1;
+
+/// The width and height in cells of the blocks, a common size for a page of a chart.
+pub const DEFAULT_BLOCK_SIZE: usize = 10;
+
+/// The travel on the back for each colour, where each colour's thread is parked
+/// at the hole its last stitch went down while the other colours are stitched.
+pub fn parked_travel(
+    stitches: &[HalfStitch],
+    colours: &HashMap<GridCell, usize>,
+    restart_policy: Option<RestartPolicy>,
+) -> BTreeMap<usize, f64> {
+    let mut parked = HashMap::new();
+    let mut total = BTreeMap::new();
+    for stitch in stitches {
+        let colour = colour_of(colours, stitch.cell());
+        let colour_total = total.entry(colour).or_insert(0.0);
+        if let Some(from) = parked.insert(colour, stitch.get_end_location()) {
+            *colour_total += travel(from, stitch.start, restart_policy);
+        }
+    }
+    total
+}
+
+pub fn colour_of(colours: &HashMap<GridCell, usize>, cell: GridCell) -> usize {
+    colours.get(&cell).copied().unwrap_or(DEFAULT_COLOUR)
+}
+
+/// The position in the sequence of the next stitch in the same colour as each stitch.
+pub fn next_of_colour(
+    stitches: &[HalfStitch],
+    colours: &HashMap<GridCell, usize>,
+) -> Vec<Option<usize>> {
+    let mut next = vec![None; stitches.len()];
+    let mut last = HashMap::new();
+    for (i, stitch) in stitches.iter().enumerate() {
+        if let Some(previous) = last.insert(colour_of(colours, stitch.cell()), i) {
+            next[previous] = Some(i);
+        }
+    }
+    next
+}
+
+/// The position in the sequence of the next stitch sewn by the same thread as each stitch,
+/// or `None` where its thread ends. Each colour has its own thread, which is parked while
+/// the other colours are stitched and is cut where the restart policy says.
+pub fn next_on_thread(
+    stitches: &[HalfStitch],
+    colours: &HashMap<GridCell, usize>,
+    restart_policy: Option<RestartPolicy>,
+) -> Vec<Option<usize>> {
+    next_of_colour(stitches, colours)
+        .into_iter()
+        .enumerate()
+        .map(|(i, next)| {
+            next.filter(|&j| {
+                !restart_policy.is_some_and(|policy| {
+                    policy.is_restart(stitches[i].get_end_location(), stitches[j].start)
+                })
+            })
+        })
+        .collect()
+}
+
+/// Each colour's stitches in the order they are stitched, with their positions in the sequence.
+pub fn stitches_by_colour(
+    stitches: &[HalfStitch],
+    colours: &HashMap<GridCell, usize>,
+) -> BTreeMap<usize, (Vec<usize>, Vec<HalfStitch>)> {
+    let mut by_colour: BTreeMap<usize, (Vec<usize>, Vec<HalfStitch>)> = BTreeMap::new();
+    for (i, stitch) in stitches.iter().enumerate() {
+        let (positions, thread) = by_colour
+            .entry(colour_of(colours, stitch.cell()))
+            .or_default();
+        positions.push(i);
+        thread.push(*stitch);
+    }
+    by_colour
+}
+
+/// The runs of the sequence stitched by separate threads, in the order they are started.
+pub fn parked_runs(
+    stitches: &[HalfStitch],
+    colours: &HashMap<GridCell, usize>,
+    restart_policy: Option<RestartPolicy>,
+) -> Vec<Vec<HalfStitch>> {
+    let mut runs = Vec::new();
+    for (positions, thread) in stitches_by_colour(stitches, colours).values() {
+        let mut offset = 0;
+        for run in HalfStitch::split_into_runs(thread, restart_policy) {
+            runs.push((positions[offset], run.to_vec()));
+            offset += run.len();
+        }
+    }
+    runs.sort_by_key(|(start, _)| *start);
+    runs.into_iter().map(|(_, run)| run).collect()
+}
+
+/// The cost of the sequence stitched with a parked thread for each colour, or the first pair
+/// of cells where a thread comes back up through the hole it went down, taking the colours in order.
+pub fn parked_cost(
+    stitches: &[HalfStitch],
+    colours: &HashMap<GridCell, usize>,
+    restart_policy: Option<RestartPolicy>,
+) -> Result<SequenceCost, (GridCell, GridCell)> {
+    let mut cost = SequenceCost::default();
+    for (_, thread) in stitches_by_colour(stitches, colours).values() {
+        let thread_cost = HalfStitch::calculate_cost(thread, restart_policy)?;
+        cost.float += thread_cost.float;
+        cost.symbolic = cost.symbolic + thread_cost.symbolic;
+    }
+    Ok(cost)
+}
+
+/// A sequence that works through the design a block at a time,
+/// stitching every colour in the block before moving on to the next one.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ParkingPlan {
+    pub stitches: Vec<HalfStitch>,
+
+    /// The travel of each colour's thread from where it was parked, in cells.
+    pub travel: BTreeMap<usize, f64>,
+    pub block_size: usize,
+}
+
+impl ParkingPlan {
+    /// Plan the stitches for the selected cells, where a cell selected twice is crossed.
+    /// The blocks are taken a row at a time from the top left, going back the other way
+    /// along each row of blocks, and the colours in each block are stitched in order.
+    /// Each colour's cells in the block are stitched in rows,
+    /// starting from the end closest to where its thread is parked.
+    pub fn new<'a>(
+        selected_cells: impl Iterator<Item = &'a GridCell>,
+        colours: &HashMap<GridCell, usize>,
+        block_size: usize,
+        corners: (StartingStitchCorner, StartingStitchCorner),
+        restart_policy: Option<RestartPolicy>,
+    ) -> Self {
+        let size = block_size.max(1) as isize;
+        let counts = selected_cells.copied().counts();
+        // Blocks are keyed by their row from the top, then their column.
+        let mut blocks: BTreeMap<(isize, isize), Vec<GridCell>> = BTreeMap::new();
+        for cell in counts.keys() {
+            blocks
+                .entry((-cell.y.div_euclid(size), cell.x.div_euclid(size)))
+                .or_default()
+                .push(*cell);
+        }
+        let block_order = blocks
+            .iter()
+            .chunk_by(|((row, _), _)| *row)
+            .into_iter()
+            .enumerate()
+            .flat_map(|(k, (_, row))| {
+                let row = row.map(|(_, cells)| cells).collect_vec();
+                match k % 2 {
+                    0 => row,
+                    _ => row.into_iter().rev().collect(),
+                }
+            })
+            .collect_vec();
+
+        let mut parked: HashMap<usize, GridCell> = HashMap::new();
+        let mut stitches: Vec<HalfStitch> = Vec::new();
+        for block in block_order {
+            let by_colour = block
+                .iter()
+                .into_group_map_by(|cell| colour_of(colours, **cell))
+                .into_iter()
+                .sorted_by_key(|(colour, _)| *colour);
+            for (colour, cells) in by_colour {
+                let cells = cells
+                    .into_iter()
+                    .copied()
+                    .sorted_by_key(|cell| (cell.y, cell.x))
+                    .collect_vec();
+                let previous_end = stitches.last().map(|stitch| stitch.get_end_location());
+                // A new thread starts near wherever the last colour stopped.
+                let anchor = parked.get(&colour).copied().or(previous_end);
+                let best = region_variants(&cells, &counts, corners)
+                    .into_iter()
+                    .min_by(|a, b| {
+                        let key = |stitches: &[HalfStitch]| {
+                            let entry = stitches[0].start;
+                            (
+                                // Going straight back up a hole looks like a mistake,
+                                // even when the thread is a different colour.
+                                Some(entry) == anchor || Some(entry) == previous_end,
+                                anchor.map_or(0.0, |hole| travel(hole, entry, restart_policy)),
+                            )
+                        };
+                        let (a, b) = (key(a), key(b));
+                        a.0.cmp(&b.0).then(a.1.total_cmp(&b.1))
+                    })
+                    .unwrap();
+                parked.insert(colour, best[best.len() - 1].get_end_location());
+                stitches.extend(best);
+            }
+        }
+        for (i, stitch) in stitches.iter_mut().enumerate() {
+            stitch.order = 2 * i + 1;
+        }
+
+        Self {
+            travel: parked_travel(&stitches, colours, restart_policy),
+            stitches,
+            block_size,
+        }
+    }
+}
+
+impl fmt::Display for ParkingPlan {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Parking in {0}x{0} blocks: {1} colour{2}, {3:.4} travel from parked threads",
+            self.block_size,
+            self.travel.len(),
+            if self.travel.len() == 1 { "" } else { "s" },
+            self.travel.values().sum::<f64>()
+        )
+    }
+}
+
+/// Describe the travel of each colour, such as "Parked travel: 1: 4.0000, 2: 1.4142".
+pub fn describe_parked_travel(travel: &BTreeMap<usize, f64>) -> String {
+    format!(
+        "Parked travel: {}",
+        travel
+            .iter()
+            .map(|(colour, distance)| format!("{}: {:.4}", colour, distance))
+            .join(", ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CORNERS: (StartingStitchCorner, StartingStitchCorner) = (
+        StartingStitchCorner::BottomLeft,
+        StartingStitchCorner::BottomRight,
+    );
+
+    fn _crossed(cells: &[(isize, isize)]) -> Vec<GridCell> {
+        cells
+            .iter()
+            .flat_map(|&(x, y)| [GridCell::new(x, y); 2])
+            .collect()
+    }
+
+    #[test]
+    fn test_travel_is_from_the_parked_thread() {
+        // Colour 1, then colour 2 a long way off, then colour 1 next to where it stopped.
+        let cells = [(0, 0), (30, 0), (1, 0)].map(|(x, y)| GridCell::new(x, y));
+        let stitches = HalfStitch::convert_grid_cells(cells.iter(), CORNERS.0, CORNERS.0);
+        let colours = HashMap::from([(GridCell::new(30, 0), 2)]);
+        let travel = parked_travel(&stitches, &colours, None);
+        // Colour 1 goes down at (1, 1) and comes back up at (1, 0).
+        assert_eq!(travel, BTreeMap::from([(1, 1.0), (2, 0.0)]));
+        assert_eq!(
+            describe_parked_travel(&travel),
+            "Parked travel: 1: 1.0000, 2: 0.0000"
+        );
+    }
+
+    #[test]
+    fn test_each_colour_is_its_own_thread() {
+        let cells = [(0, 0), (30, 0), (1, 0), (31, 0)].map(|(x, y)| GridCell::new(x, y));
+        let stitches = HalfStitch::convert_grid_cells(cells.iter(), CORNERS.0, CORNERS.0);
+        let colours = HashMap::from([(GridCell::new(30, 0), 2), (GridCell::new(31, 0), 2)]);
+        assert_eq!(
+            next_on_thread(&stitches, &colours, None),
+            [Some(2), Some(3), None, None]
+        );
+        let runs = parked_runs(&stitches, &colours, None);
+        assert_eq!(
+            runs,
+            [
+                vec![stitches[0], stitches[2]],
+                vec![stitches[1], stitches[3]]
+            ]
+        );
+        let cost = parked_cost(&stitches, &colours, None).unwrap();
+        assert_eq!(cost.float, 2.0);
+        assert_eq!(
+            cost.float,
+            parked_travel(&stitches, &colours, None)
+                .values()
+                .sum::<f64>()
+        );
+        // With one colour the thread travels between the two groups, so is cut.
+        let policy = Some(RestartPolicy::default());
+        assert_eq!(parked_runs(&stitches, &HashMap::new(), policy).len(), 4);
+        assert_eq!(parked_runs(&stitches, &colours, policy).len(), 2);
+        assert_eq!(
+            parked_cost(&stitches, &HashMap::new(), policy),
+            HalfStitch::calculate_cost(&stitches, policy)
+        );
+    }
+
+    #[test]
+    fn test_same_hole_only_matters_within_a_colour() {
+        // The second stitch comes up where the first went down.
+        let stitches = vec![
+            HalfStitch {
+                start: GridCell::new(0, 0),
+                stitch_corner: StartingStitchCorner::BottomLeft,
+                order: 1,
+            },
+            HalfStitch {
+                start: GridCell::new(1, 1),
+                stitch_corner: StartingStitchCorner::TopLeft,
+                order: 3,
+            },
+        ];
+        assert!(parked_cost(&stitches, &HashMap::new(), None).is_err());
+        let colours = HashMap::from([(stitches[1].cell(), 2)]);
+        assert!(parked_cost(&stitches, &colours, None).is_ok());
+    }
+
+    #[test]
+    fn test_blocks_are_finished_before_moving_on() {
+        // Two colours in each of two blocks, side by side.
+        let cells = _crossed(&[(0, 0), (1, 0), (2, 0), (3, 0)]);
+        let colours = HashMap::from([(GridCell::new(1, 0), 2), (GridCell::new(3, 0), 2)]);
+        let plan = ParkingPlan::new(cells.iter(), &colours, 2, CORNERS, None);
+        let visited = plan
+            .stitches
+            .iter()
+            .map(|stitch| stitch.cell().x)
+            .dedup()
+            .collect_vec();
+        assert_eq!(visited, [0, 1, 2, 3]);
+        assert_eq!(plan.stitches.len(), 8);
+        assert!(plan
+            .stitches
+            .iter()
+            .enumerate()
+            .all(|(i, stitch)| stitch.order == 2 * i + 1));
+        assert_eq!(plan.travel.len(), 2);
+    }
+
+    #[test]
+    fn test_plan_beats_a_scattered_order() {
+        // Two rows in alternating colours, selected in no particular order.
+        let scattered = [5, 1, 7, 3, 0, 6, 2, 4]
+            .iter()
+            .flat_map(|&x| [(x, 0), (x, 1)])
+            .collect_vec();
+        // Every bottom leg first, then every top leg.
+        let cells = scattered
+            .iter()
+            .chain(scattered.iter())
+            .map(|&(x, y)| GridCell::new(x, y))
+            .collect_vec();
+        let colours: HashMap<GridCell, usize> = scattered
+            .iter()
+            .map(|&(x, y)| (GridCell::new(x, y), 1 + (x % 2) as usize))
+            .collect();
+        let plan = ParkingPlan::new(cells.iter(), &colours, 4, CORNERS, None);
+        let planned = plan.travel.values().sum::<f64>();
+        let selected = HalfStitch::convert_grid_cells(cells.iter(), CORNERS.0, CORNERS.1);
+        let unplanned = parked_travel(&selected, &colours, None)
+            .values()
+            .sum::<f64>();
+        assert!(
+            planned < unplanned,
+            "{} should be less than {}",
+            planned,
+            unplanned
+        );
+        assert_eq!(
+            plan.to_string(),
+            format!(
+                "Parking in 4x4 blocks: 2 colours, {:.4} travel from parked threads",
+                planned
+            )
+        );
+    }
+}
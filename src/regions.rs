@@ -0,0 +1,494 @@
+use crate::grid_cell::GridCell;
+use crate::spatial_index::SpatialIndex;
+use crate::stitch::{HalfStitch, RestartPolicy, StartingStitchCorner};
+use itertools::Itertools;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fmt::Formatter;
+
+/// Which cells count as touching when the selection is split into regions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Connectivity {
+    /// Cells touch when they share an edge.
+    Four,
+
+    /// Cells touch when they share an edge or a corner.
+    #[default]
+    Eight,
+}
+
+impl Connectivity {
+    pub const ALL: [Connectivity; 2] = [Connectivity::Four, Connectivity::Eight];
+
+    fn neighbours(self, cell: GridCell) -> Vec<GridCell> {
+        match self {
+            Connectivity::Four => cell.edge_neighbours().collect(),
+            Connectivity::Eight => cell.neighbours().collect(),
+        }
+    }
+}
+
+impl fmt::Display for Connectivity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Connectivity::Four => "4-connected",
+            Connectivity::Eight => "8-connected",
+        })
+    }
+}
+
+/// Split the cells into groups that touch each other.
+/// Each group is sorted, and the groups are in the order of their first cell.
+pub fn connected_components(
+    cells: &HashSet<GridCell>,
+    connectivity: Connectivity,
+) -> Vec<Vec<GridCell>> {
+    let mut seen = HashSet::new();
+    let mut components = Vec::new();
+    for start in cells.iter().sorted_by_key(|cell| (cell.y, cell.x)) {
+        if !seen.insert(*start) {
+            continue;
+        }
+        let mut component = vec![*start];
+        let mut to_visit = vec![*start];
+        while let Some(cell) = to_visit.pop() {
+            for neighbour in connectivity.neighbours(cell) {
+                if cells.contains(&neighbour) && seen.insert(neighbour) {
+                    component.push(neighbour);
+                    to_visit.push(neighbour);
+                }
+            }
+        }
+        component.sort_by_key(|cell| (cell.y, cell.x));
+        components.push(component);
+    }
+    components
+}
+
+/// One region, stitched as a block on its own.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegionSequence {
+    pub cells: Vec<GridCell>,
+    pub stitches: Vec<HalfStitch>,
+
+    /// The travel within the region, in cells.
+    pub cost: f64,
+}
+
+/// The selection stitched one region at a time,
+/// with the regions toured in the order that keeps the jumps between them short.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RegionPlan {
+    pub regions: Vec<RegionSequence>,
+
+    /// The travel from the end of each region to the start of the next, in cells.
+    pub jump_cost: f64,
+}
+
+impl RegionPlan {
+    /// Plan the stitches for the selected cells, where a cell selected twice is crossed.
+    /// Each region is stitched in rows, going along each run of cells with the bottom legs
+    /// and back with the top legs. The rows can be taken from the top or the bottom,
+    /// starting at either end, which gives each region a few places to enter and leave it.
+    pub fn new<'a>(
+        selected_cells: impl Iterator<Item = &'a GridCell>,
+        connectivity: Connectivity,
+        corners: (StartingStitchCorner, StartingStitchCorner),
+        restart_policy: Option<RestartPolicy>,
+    ) -> Self {
+        let counts = selected_cells.copied().counts();
+        let cells = counts.keys().copied().collect();
+        let components = connected_components(&cells, connectivity);
+        let variants = components
+            .iter()
+            .map(|region| region_variants(region, &counts, corners))
+            .collect_vec();
+
+        let order = tour_regions(&variants);
+        let chosen = choose_variants(&order, &variants, restart_policy);
+
+        let mut jump_cost = 0.0;
+        let mut regions: Vec<RegionSequence> = Vec::new();
+        for (&r, v) in order.iter().zip(chosen) {
+            let stitches = variants[r][v].clone();
+            if let Some(previous) = regions.last() {
+                jump_cost += travel(
+                    previous.stitches[previous.stitches.len() - 1].get_end_location(),
+                    stitches[0].start,
+                    restart_policy,
+                );
+            }
+            regions.push(RegionSequence {
+                cells: components[r].clone(),
+                cost: sequence_cost(&stitches, restart_policy),
+                stitches,
+            });
+        }
+        Self { regions, jump_cost }
+    }
+
+    /// The whole sequence, numbered in order.
+    pub fn stitches(&self) -> Vec<HalfStitch> {
+        self.regions
+            .iter()
+            .flat_map(|region| region.stitches.iter())
+            .enumerate()
+            .map(|(i, stitch)| HalfStitch {
+                order: 2 * i + 1,
+                ..*stitch
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for RegionPlan {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} region{}: {:.4} within regions, {:.4} between them",
+            self.regions.len(),
+            if self.regions.len() == 1 { "" } else { "s" },
+            self.regions.iter().map(|region| region.cost).sum::<f64>(),
+            self.jump_cost
+        )
+    }
+}
+
+/// The ways of stitching the region in rows, without any repeats.
+/// Sequences that would come straight back up through the hole they went down are left out,
+/// unless every way of stitching the region does that.
+pub(crate) fn region_variants(
+    region: &[GridCell],
+    counts: &HashMap<GridCell, usize>,
+    (bottom_corner, top_corner): (StartingStitchCorner, StartingStitchCorner),
+) -> Vec<Vec<HalfStitch>> {
+    let mut rows: BTreeMap<isize, Vec<isize>> = BTreeMap::new();
+    for cell in region {
+        rows.entry(cell.y).or_default().push(cell.x);
+    }
+
+    let mut variants: Vec<Vec<HalfStitch>> = Vec::new();
+    for (upwards, first_rightwards) in [(true, true), (true, false), (false, true), (false, false)]
+    {
+        let row_order = match upwards {
+            true => rows.iter().collect_vec(),
+            false => rows.iter().rev().collect_vec(),
+        };
+        let mut stitches = Vec::new();
+        for (k, (&y, xs)) in row_order.into_iter().enumerate() {
+            // Each row goes back the way the last one came.
+            let rightwards = first_rightwards == (k % 2 == 0);
+            let mut runs: Vec<Vec<isize>> = Vec::new();
+            for &x in xs.iter().sorted() {
+                match runs.last_mut() {
+                    Some(run) if run[run.len() - 1] + 1 == x => run.push(x),
+                    _ => runs.push(vec![x]),
+                }
+            }
+            if !rightwards {
+                runs.reverse();
+                runs.iter_mut().for_each(|run| run.reverse());
+            }
+            for run in runs {
+                for &x in &run {
+                    stitches.push(HalfStitch::new_in_cell(
+                        GridCell::new(x, y),
+                        bottom_corner,
+                        0,
+                    ));
+                }
+                for &x in run.iter().rev() {
+                    let cell = GridCell::new(x, y);
+                    if counts[&cell] > 1 {
+                        stitches.push(HalfStitch::new_in_cell(cell, top_corner, 0));
+                    }
+                }
+            }
+        }
+        if !variants.contains(&stitches) {
+            variants.push(stitches);
+        }
+    }
+
+    let valid = variants
+        .iter()
+        .filter(|stitches| HalfStitch::is_valid_sequence(stitches))
+        .cloned()
+        .collect_vec();
+    if valid.is_empty() {
+        variants.truncate(1);
+        variants
+    } else {
+        valid
+    }
+}
+
+/// Visit the regions from the first one, always going to the closest way into a region
+/// that hasn't been visited yet. Returns the regions in the order they are visited.
+fn tour_regions(variants: &[Vec<Vec<HalfStitch>>]) -> Vec<usize> {
+    let mut index = SpatialIndex::default();
+    let mut remaining = 0;
+    for (r, region_variants) in variants.iter().enumerate() {
+        for (v, stitches) in region_variants.iter().enumerate() {
+            index.insert(stitches[0].start, (r, v));
+            remaining += 1;
+        }
+    }
+
+    let mut order = Vec::new();
+    let mut current = (!variants.is_empty()).then_some((0, 0));
+    while let Some((r, v)) = current {
+        order.push(r);
+        for (other, stitches) in variants[r].iter().enumerate() {
+            index.remove(stitches[0].start, &(r, other));
+            remaining -= 1;
+        }
+        let exit = variants[r][v][variants[r][v].len() - 1].get_end_location();
+        current = nearest_entry(&index, remaining, exit);
+    }
+    order
+}
+
+/// Find the closest way into a region, searching further out until one is found.
+/// Coming back up through the hole the thread went down is only used if there is nothing else.
+fn nearest_entry(
+    index: &SpatialIndex<(usize, usize)>,
+    remaining: usize,
+    point: GridCell,
+) -> Option<(usize, usize)> {
+    if remaining == 0 {
+        return None;
+    }
+    let same_hole = index
+        .query(point.x..=point.x, point.y..=point.y)
+        .map(|(_, entry)| *entry)
+        .min();
+    if index.query(point.x..=point.x, point.y..=point.y).count() == remaining {
+        return same_hole;
+    }
+    let mut radius: isize = 1;
+    loop {
+        let columns = point.x - radius..=point.x + radius;
+        let rows = point.y - radius..=point.y + radius;
+        let closest = index
+            .query(columns, rows)
+            .filter(|(cell, _)| *cell != point)
+            .min_by_key(|(cell, (r, v))| (point.euclidean_distance_squared(cell), *r, *v));
+        // Anything outside the square is further away than its half width.
+        if let Some((cell, entry)) = closest {
+            if point.euclidean_distance(cell) <= radius as f64 {
+                return Some(*entry);
+            }
+        }
+        radius *= 2;
+    }
+}
+
+/// Choose how to stitch each region, given the order they are visited in,
+/// so that the travel within the regions and between them is as short as possible.
+fn choose_variants(
+    order: &[usize],
+    variants: &[Vec<Vec<HalfStitch>>],
+    restart_policy: Option<RestartPolicy>,
+) -> Vec<usize> {
+    if order.is_empty() {
+        return vec![];
+    }
+    let internal = |r: usize, v: usize| sequence_cost(&variants[r][v], restart_policy);
+
+    // The cheapest cost of reaching the end of each region, and the variant it came from.
+    let mut best: Vec<Vec<(f64, usize)>> = vec![(0..variants[order[0]].len())
+        .map(|v| (internal(order[0], v), 0))
+        .collect()];
+    for pair in order.windows(2) {
+        let (previous, next) = (pair[0], pair[1]);
+        let costs = (0..variants[next].len())
+            .map(|v| {
+                let entry = variants[next][v][0].start;
+                let (cost, from) = best[best.len() - 1]
+                    .iter()
+                    .enumerate()
+                    .map(|(u, (cost, _))| {
+                        let stitches = &variants[previous][u];
+                        let exit = stitches[stitches.len() - 1].get_end_location();
+                        // Coming straight back up through the same hole isn't allowed.
+                        let jump = match exit == entry {
+                            true => f64::INFINITY,
+                            false => travel(exit, entry, restart_policy),
+                        };
+                        (cost + jump, u)
+                    })
+                    .min_by(|a, b| a.0.total_cmp(&b.0))
+                    .unwrap();
+                (cost + internal(next, v), from)
+            })
+            .collect();
+        best.push(costs);
+    }
+
+    let mut v = (0..best[best.len() - 1].len())
+        .min_by(|&a, &b| {
+            best[best.len() - 1][a]
+                .0
+                .total_cmp(&best[best.len() - 1][b].0)
+        })
+        .unwrap();
+    let mut chosen = vec![v; order.len()];
+    for i in (1..order.len()).rev() {
+        v = best[i][v].1;
+        chosen[i - 1] = v;
+    }
+    chosen
+}
+
+/// The cost of travelling between two holes, or of restarting if the policy cuts the thread.
+pub(crate) fn travel(from: GridCell, to: GridCell, restart_policy: Option<RestartPolicy>) -> f64 {
+    match restart_policy {
+        Some(policy) if policy.is_restart(from, to) => policy.restart_penalty as f64,
+        _ => from.euclidean_distance(&to),
+    }
+}
+
+fn sequence_cost(stitches: &[HalfStitch], restart_policy: Option<RestartPolicy>) -> f64 {
+    match restart_policy {
+        Some(policy) => HalfStitch::calculate_cost_with_restarts_float(stitches, policy),
+        None => HalfStitch::calculate_cost_float(stitches),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CORNERS: (StartingStitchCorner, StartingStitchCorner) = (
+        StartingStitchCorner::BottomLeft,
+        StartingStitchCorner::BottomRight,
+    );
+
+    fn _cells(cells: &[(isize, isize)]) -> Vec<GridCell> {
+        cells.iter().map(|&(x, y)| GridCell::new(x, y)).collect()
+    }
+
+    /// Every cell selected twice, so it is crossed.
+    fn _crossed(cells: &[GridCell]) -> Vec<GridCell> {
+        cells.iter().flat_map(|&cell| [cell, cell]).collect()
+    }
+
+    #[test]
+    fn test_diagonal_cells_only_touch_with_eight_connectivity() {
+        let cells = _cells(&[(0, 0), (1, 1), (5, 5), (5, 6)])
+            .into_iter()
+            .collect();
+        assert_eq!(
+            connected_components(&cells, Connectivity::Eight),
+            [_cells(&[(0, 0), (1, 1)]), _cells(&[(5, 5), (5, 6)])]
+        );
+        assert_eq!(
+            connected_components(&cells, Connectivity::Four),
+            [
+                _cells(&[(0, 0)]),
+                _cells(&[(1, 1)]),
+                _cells(&[(5, 5), (5, 6)])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_is_an_ordinary_sequence() {
+        let cells = _crossed(&_cells(&[(0, 0), (1, 0), (2, 0), (0, 1), (1, 1), (7, 3)]));
+        let plan = RegionPlan::new(cells.iter(), Connectivity::Four, CORNERS, None);
+        let stitches = plan.stitches();
+
+        // Selecting the cells in the order of the plan gives the same stitches.
+        let replayed = HalfStitch::convert_grid_cells(
+            stitches
+                .iter()
+                .map(|stitch| stitch.cell())
+                .collect_vec()
+                .iter(),
+            CORNERS.0,
+            CORNERS.1,
+        );
+        assert_eq!(replayed, stitches);
+        assert!(HalfStitch::is_valid_sequence(&stitches));
+        assert_eq!(
+            stitches.iter().map(|stitch| stitch.cell()).counts(),
+            cells.iter().copied().counts()
+        );
+
+        // The costs of the regions and the jumps add up to the cost of the whole sequence.
+        let total = plan.regions.iter().map(|region| region.cost).sum::<f64>() + plan.jump_cost;
+        assert!((total - HalfStitch::calculate_cost_float(&stitches)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_regions_are_stitched_as_blocks() {
+        let cells = _crossed(&_cells(&[(0, 0), (1, 0), (0, 1), (1, 1), (4, 0), (5, 0)]));
+        let plan = RegionPlan::new(cells.iter(), Connectivity::Eight, CORNERS, None);
+        assert_eq!(plan.regions.len(), 2);
+        for region in &plan.regions {
+            assert_eq!(region.stitches.len(), 2 * region.cells.len());
+            assert!(region
+                .stitches
+                .iter()
+                .all(|stitch| region.cells.contains(&stitch.cell())));
+        }
+        // A row of two crossed cells is three holes of travel, there and back.
+        let row = plan.regions.iter().find(|r| r.cells.len() == 2).unwrap();
+        assert!((row.cost - 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_tour_visits_the_closest_region_next() {
+        let cells = _crossed(&_cells(&[
+            (0, 0),
+            (1, 0),
+            (20, 0),
+            (21, 0),
+            (10, 0),
+            (11, 0),
+        ]));
+        let plan = RegionPlan::new(cells.iter(), Connectivity::Four, CORNERS, None);
+        let first_columns = plan
+            .regions
+            .iter()
+            .map(|region| region.cells[0].x)
+            .collect_vec();
+        assert_eq!(first_columns, [0, 10, 20]);
+        assert_eq!(
+            plan.to_string(),
+            format!(
+                "3 regions: {:.4} within regions, {:.4} between them",
+                plan.regions.iter().map(|region| region.cost).sum::<f64>(),
+                plan.jump_cost
+            )
+        );
+    }
+
+    #[test]
+    fn test_nearest_entry_skips_same_hole_while_others_remain() {
+        let point = GridCell::new(0, 0);
+        let mut index = SpatialIndex::default();
+        index.insert(point, (1, 0));
+        index.insert(GridCell::new(5, 0), (2, 0));
+        assert_eq!(nearest_entry(&index, 2, point), Some((2, 0)));
+        index.remove(GridCell::new(5, 0), &(2, 0));
+        assert_eq!(nearest_entry(&index, 1, point), Some((1, 0)));
+    }
+
+    #[test]
+    fn test_half_stitches_stay_half_stitches() {
+        let cells = _cells(&[(0, 0), (1, 0), (1, 0)]);
+        let plan = RegionPlan::new(cells.iter(), Connectivity::Eight, CORNERS, None);
+        let stitches = plan.stitches();
+        assert_eq!(stitches.len(), 3);
+        assert_eq!(
+            stitches
+                .iter()
+                .filter(|stitch| stitch.stitch_corner == CORNERS.1)
+                .map(|stitch| stitch.cell())
+                .collect_vec(),
+            [GridCell::new(1, 0)]
+        );
+    }
+}
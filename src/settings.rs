@@ -0,0 +1,261 @@
+use crate::stitch::{RestartPolicy, StartingStitchCorner};
+use crate::svg_output::svg_options::SvgOptions;
+use crate::theme::ColourTheme;
+use crate::thread_usage::{Fabric, FabricGrid, STRANDS_PER_SKEIN};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::{fs, io::ErrorKind};
+
+/// Preferences that are kept between launches, stored as TOML in the user's config directory.
+/// Anything missing from the file takes its default value.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub bottom_stitch_corner: StartingStitchCorner,
+    pub top_stitch_corner: StartingStitchCorner,
+
+    /// Whether the top legs of crosses all go the same way, even where bottom legs are flipped.
+    pub consistent_top_legs: bool,
+    pub precise_cost: bool,
+    pub theme: ColourTheme,
+    pub fabric: Fabric,
+
+    /// How many fabric threads each stitch crosses on evenweave and linen.
+    pub stitch_over: usize,
+    pub strands: usize,
+    pub cut_long_travel: bool,
+    pub restart_policy: RestartPolicy,
+    pub svg_options: SvgOptions,
+
+    /// Where the last SVG was exported to, so the next export starts there.
+    pub last_export_directory: Option<PathBuf>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            bottom_stitch_corner: StartingStitchCorner::BottomLeft,
+            top_stitch_corner: StartingStitchCorner::BottomRight,
+            consistent_top_legs: true,
+            precise_cost: false,
+            theme: ColourTheme::default(),
+            fabric: Fabric::default(),
+            stitch_over: 2,
+            strands: 2,
+            cut_long_travel: false,
+            restart_policy: RestartPolicy::default(),
+            svg_options: SvgOptions::default(),
+            last_export_directory: None,
+        }
+    }
+}
+
+impl Settings {
+    /// The settings file, following the XDG base directory spec on Linux.
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir()
+            .map(|directory| directory.join("cross_stitch_solver").join("settings.toml"))
+    }
+
+    /// Load the saved settings, falling back to the defaults if there are none or they can't be read.
+    pub fn load() -> Settings {
+        let Some(path) = Self::path() else {
+            return Settings::default();
+        };
+        match Self::load_from(&path) {
+            Ok(settings) => settings,
+            Err(error) if error.kind() == ErrorKind::NotFound => Settings::default(),
+            Err(error) => {
+                error!("Failed to read settings from {}: {}", path.display(), error);
+                Settings::default()
+            }
+        }
+    }
+
+    pub fn load_from(path: &Path) -> io::Result<Settings> {
+        let contents = fs::read_to_string(path)?;
+        toml::from_str(&contents)
+            .map(Settings::validated)
+            .map_err(|error| io::Error::new(ErrorKind::InvalidData, error))
+    }
+
+    /// Bring values that can't be chosen in the settings panel, such as from a hand-edited file,
+    /// back into range so the sequence can still be stitched.
+    fn validated(mut self) -> Settings {
+        let defaults = Settings::default();
+        let top_stitch_corners = self.bottom_stitch_corner.get_possible_top_stitch_corners();
+        if !top_stitch_corners.contains(&self.top_stitch_corner) {
+            self.top_stitch_corner = top_stitch_corners[0];
+        }
+        self.stitch_over = self.stitch_over.clamp(1, FabricGrid::MAX_OVER);
+        self.strands = self.strands.clamp(1, STRANDS_PER_SKEIN);
+
+        let travel = &mut self.restart_policy.max_travel_distance;
+        *travel = match travel.is_nan() {
+            true => defaults.restart_policy.max_travel_distance,
+            false => travel.clamp(RestartPolicy::MIN_TRAVEL, RestartPolicy::MAX_TRAVEL),
+        };
+
+        let penalty = &mut self.restart_policy.restart_penalty;
+        *penalty = (*penalty).clamp(RestartPolicy::MIN_PENALTY, RestartPolicy::MAX_PENALTY);
+
+        let fabric_cell_size = FabricGrid::new(self.fabric, self.stitch_over).cell_size_mm();
+        let svg_defaults = defaults.svg_options;
+        let svg_options = &mut self.svg_options;
+        for (length, default) in [
+            (&mut svg_options.dot_spacing, svg_defaults.dot_spacing),
+            (&mut svg_options.dot_radius, svg_defaults.dot_radius),
+            (&mut svg_options.line_width, svg_defaults.line_width),
+            (&mut svg_options.font_size, svg_defaults.font_size),
+        ] {
+            if !(length.is_finite() && *length > 0.0) {
+                *length = default;
+            }
+        }
+        if let Some(length) = &mut svg_options.cell_size_mm {
+            if !(length.is_finite() && *length > 0.0) {
+                *length = fabric_cell_size;
+            }
+        }
+        self
+    }
+
+    pub fn save(&self) {
+        let Some(path) = Self::path() else {
+            error!("No config directory to save settings to");
+            return;
+        };
+        self.save_to(&path).unwrap_or_else(|error| {
+            error!("Failed to save settings to {}: {}", path.display(), error)
+        });
+    }
+
+    pub fn save_to(&self, path: &Path) -> io::Result<()> {
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+        let contents =
+            toml::to_string(self).map_err(|error| io::Error::new(ErrorKind::InvalidData, error))?;
+        fs::write(path, contents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn _temporary_path(name: &str) -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("cross_stitch_solver_test_{}", std::process::id()))
+            .join(name)
+    }
+
+    #[test]
+    fn test_round_trip_through_file() {
+        let settings = Settings {
+            bottom_stitch_corner: StartingStitchCorner::TopRight,
+            top_stitch_corner: StartingStitchCorner::TopLeft,
+            consistent_top_legs: false,
+            precise_cost: true,
+            theme: ColourTheme::HighContrast,
+            fabric: Fabric::Evenweave28,
+            stitch_over: 1,
+            strands: 3,
+            cut_long_travel: true,
+            restart_policy: RestartPolicy {
+                max_travel_distance: 7.5,
+                restart_penalty: 10,
+            },
+            svg_options: SvgOptions {
+                sequence_numbers: false,
+                cell_size_mm: Some(1.8),
+                ..SvgOptions::default()
+            },
+            last_export_directory: Some(PathBuf::from("/tmp/patterns")),
+        };
+        let path = _temporary_path("round_trip.toml");
+        settings.save_to(&path).unwrap();
+        assert_eq!(Settings::load_from(&path).unwrap(), settings);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_missing_values_use_defaults() {
+        let settings: Settings = toml::from_str("precise_cost = true").unwrap();
+        assert_eq!(
+            settings,
+            Settings {
+                precise_cost: true,
+                ..Settings::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_old_evenweave_names_still_load() {
+        let settings: Settings = toml::from_str("fabric = \"Evenweave32OverTwo\"").unwrap();
+        assert_eq!(settings.fabric, Fabric::Evenweave32);
+        assert_eq!(settings.stitch_over, 2);
+    }
+
+    #[test]
+    fn test_out_of_range_values_are_corrected() {
+        let settings: Settings = toml::from_str(
+            "bottom_stitch_corner = \"BottomLeft\"\n\
+             top_stitch_corner = \"TopRight\"\n\
+             stitch_over = 9\n\
+             strands = 0\n\
+             [restart_policy]\n\
+             max_travel_distance = -3.0\n\
+             restart_penalty = 0\n\
+             [svg_options]\n\
+             font_size = nan\n\
+             cell_size_mm = -2.0\n",
+        )
+        .unwrap();
+        let settings = settings.validated();
+        assert_eq!(settings.top_stitch_corner, StartingStitchCorner::TopLeft);
+        assert_eq!(settings.stitch_over, FabricGrid::MAX_OVER);
+        assert_eq!(settings.strands, 1);
+        assert_eq!(
+            settings.restart_policy.max_travel_distance,
+            RestartPolicy::MIN_TRAVEL
+        );
+        assert_eq!(
+            settings.restart_policy.restart_penalty,
+            RestartPolicy::MIN_PENALTY
+        );
+        assert_eq!(
+            settings.svg_options.font_size,
+            SvgOptions::default().font_size
+        );
+        assert_eq!(
+            settings.svg_options.cell_size_mm,
+            Some(FabricGrid::new(settings.fabric, settings.stitch_over).cell_size_mm())
+        );
+
+        let settings: Settings =
+            toml::from_str("[restart_policy]\nmax_travel_distance = nan").unwrap();
+        assert_eq!(
+            settings.validated().restart_policy,
+            RestartPolicy::default()
+        );
+    }
+
+    #[test]
+    fn test_default_settings_are_valid() {
+        assert_eq!(Settings::default().validated(), Settings::default());
+    }
+
+    #[test]
+    fn test_invalid_file_is_an_error() {
+        let path = _temporary_path("invalid.toml");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "theme = \"Purple\"").unwrap();
+        let result = Settings::load_from(&path);
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+        fs::remove_file(path).unwrap();
+    }
+}
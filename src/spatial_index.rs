@@ -0,0 +1,102 @@
+use crate::grid_cell::GridCell;
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+/// Items placed on the grid, bucketed by square chunks of cells
+/// so that the items in a region can be found without looking at every item.
+#[derive(Debug, Clone)]
+pub struct SpatialIndex<T> {
+    chunks: HashMap<GridCell, Vec<(GridCell, T)>>,
+}
+
+impl<T> Default for SpatialIndex<T> {
+    fn default() -> Self {
+        Self {
+            chunks: HashMap::new(),
+        }
+    }
+}
+
+impl<T: PartialEq> SpatialIndex<T> {
+    /// The number of cells along each side of a chunk.
+    const CHUNK_SIZE: isize = 16;
+
+    fn chunk_of(cell: GridCell) -> GridCell {
+        GridCell::new(
+            cell.x.div_euclid(Self::CHUNK_SIZE),
+            cell.y.div_euclid(Self::CHUNK_SIZE),
+        )
+    }
+
+    pub fn insert(&mut self, cell: GridCell, item: T) {
+        self.chunks
+            .entry(Self::chunk_of(cell))
+            .or_default()
+            .push((cell, item));
+    }
+
+    /// Remove one copy of the item from the cell, if it is there.
+    pub fn remove(&mut self, cell: GridCell, item: &T) {
+        let chunk = Self::chunk_of(cell);
+        if let Some(items) = self.chunks.get_mut(&chunk) {
+            if let Some(position) = items.iter().position(|(c, i)| *c == cell && i == item) {
+                items.swap_remove(position);
+            }
+            if items.is_empty() {
+                self.chunks.remove(&chunk);
+            }
+        }
+    }
+
+    /// Find every item in the given columns and rows of cells.
+    pub fn query(
+        &self,
+        columns: RangeInclusive<isize>,
+        rows: RangeInclusive<isize>,
+    ) -> impl Iterator<Item = &(GridCell, T)> {
+        let first_chunk = Self::chunk_of(GridCell::new(*columns.start(), *rows.start()));
+        let last_chunk = Self::chunk_of(GridCell::new(*columns.end(), *rows.end()));
+        (first_chunk.x..=last_chunk.x)
+            .flat_map(move |x| (first_chunk.y..=last_chunk.y).map(move |y| GridCell::new(x, y)))
+            .filter_map(|chunk| self.chunks.get(&chunk))
+            .flatten()
+            .filter(move |(cell, _)| columns.contains(&cell.x) && rows.contains(&cell.y))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_only_returns_cells_in_region() {
+        let mut index = SpatialIndex::default();
+        index.insert(GridCell::new(0, 0), 'a');
+        index.insert(GridCell::new(5, 5), 'b');
+        index.insert(GridCell::new(40, 0), 'c');
+        index.insert(GridCell::new(-3, -20), 'd');
+        let mut result: Vec<char> = index.query(-5..=10, -5..=10).map(|(_, i)| *i).collect();
+        result.sort();
+        assert_eq!(result, vec!['a', 'b']);
+    }
+
+    #[test]
+    fn test_query_negative_region() {
+        let mut index = SpatialIndex::default();
+        index.insert(GridCell::new(-3, -20), 'd');
+        let result: Vec<char> = index.query(-5..=-1, -20..=-17).map(|(_, i)| *i).collect();
+        assert_eq!(result, vec!['d']);
+    }
+
+    #[test]
+    fn test_remove_one_copy() {
+        let mut index = SpatialIndex::default();
+        index.insert(GridCell::new(1, 1), 'a');
+        index.insert(GridCell::new(1, 1), 'a');
+        index.remove(GridCell::new(1, 1), &'a');
+        assert_eq!(index.query(0..=2, 0..=2).count(), 1);
+        index.remove(GridCell::new(1, 1), &'a');
+        assert_eq!(index.query(0..=2, 0..=2).count(), 0);
+        assert!(index.chunks.is_empty());
+    }
+}
@@ -4,11 +4,24 @@ use iced::widget::canvas::Path;
 use iced::Point;
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::{FromPrimitive, ToPrimitive};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fmt;
 use std::fmt::Formatter;
 
-#[derive(Debug, Clone, PartialEq, Eq, Copy, Hash, Default, FromPrimitive, ToPrimitive)]
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    Copy,
+    Hash,
+    Default,
+    FromPrimitive,
+    ToPrimitive,
+    Serialize,
+    Deserialize,
+)]
 pub enum StartingStitchCorner {
     #[default]
     BottomLeft = 0,
@@ -38,6 +51,12 @@ impl StartingStitchCorner {
         }
     }
 
+    /// The next corner going clockwise, for cycling through the corners.
+    pub fn next(&self) -> StartingStitchCorner {
+        let next: Option<StartingStitchCorner> = FromPrimitive::from_u8((*self as u8 + 1) % 4);
+        next.unwrap_or_else(|| panic!("Not a valid stitch corner"))
+    }
+
     pub fn get_opposite_corner(&self) -> StartingStitchCorner {
         let opposite: Option<StartingStitchCorner> = FromPrimitive::from_u8((*self as u8 + 2) % 4);
         opposite.unwrap_or_else(|| panic!("Not a valid stitch corner"))
@@ -55,6 +74,77 @@ impl fmt::Display for StartingStitchCorner {
     }
 }
 
+/// When the thread is cut and started again instead of travelling on the back.
+/// The thread is cut when the travel is longer than allowed, or when it would cost more than
+/// the restart penalty, so a lower penalty trades long jumps for more restarts.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RestartPolicy {
+    /// The longest distance the thread may travel on the back before it is cut, in cells.
+    pub max_travel_distance: f64,
+
+    /// The cost charged for each restart, in cells.
+    pub restart_penalty: usize,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            max_travel_distance: 5.0,
+            restart_penalty: 5,
+        }
+    }
+}
+
+impl RestartPolicy {
+    /// The range of maximum travel distances that can be chosen, in cells.
+    pub const MIN_TRAVEL: f64 = 1.0;
+    pub const MAX_TRAVEL: f64 = 20.0;
+
+    /// The range of restart penalties, in cells, and the ones offered in the settings panel.
+    /// A penalty of 0 would cut the thread for every jump and make every sequence free.
+    pub const MIN_PENALTY: usize = 1;
+    pub const MAX_PENALTY: usize = 20;
+    pub const PENALTIES: [usize; 10] = [1, 2, 3, 4, 5, 6, 8, 10, 15, 20];
+
+    /// Whether the thread should be cut rather than travel between the two points.
+    pub fn is_restart(&self, from: GridCell, to: GridCell) -> bool {
+        let distance = from.euclidean_distance(&to);
+        distance > self.max_travel_distance || distance > self.restart_penalty as f64
+    }
+}
+
+/// The cost of a sequence, both as a float and in exact terms.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SequenceCost {
+    pub float: f64,
+    pub symbolic: SymbolicSum,
+}
+
+impl SequenceCost {
+    /// The change in cost going from the baseline to this cost.
+    pub fn difference_from(&self, baseline: &SequenceCost) -> SequenceCost {
+        SequenceCost {
+            float: self.float - baseline.float,
+            symbolic: self.symbolic.clone() - baseline.symbolic.clone(),
+        }
+    }
+}
+
+impl fmt::Display for SequenceCost {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.4} = {}", self.float, self.symbolic)
+    }
+}
+
+/// Which of the two half stitches in a cell a stitch is.
+/// The first time a cell is selected is its bottom leg, and the second is its top leg.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Hash)]
+pub enum Leg {
+    Bottom,
+    Top,
+}
+
 #[derive(PartialEq, Eq, Copy, Clone, Debug, Hash, Default)]
 pub struct HalfStitch {
     // The start is the cell of the stitch, from the bottom left corner.
@@ -64,9 +154,13 @@ pub struct HalfStitch {
 }
 
 impl HalfStitch {
+    /// The cell the stitch crosses.
+    pub fn cell(&self) -> GridCell {
+        self.start - self.stitch_corner.get_offset_from_bottom_left()
+    }
+
     pub fn get_end_location(&self) -> GridCell {
-        let origin = self.start - self.stitch_corner.get_offset_from_bottom_left();
-        origin
+        self.cell()
             + self
                 .stitch_corner
                 .get_opposite_corner()
@@ -79,72 +173,118 @@ impl HalfStitch {
         Path::line(Point::from(first_corner), Point::from(second_corner))
     }
 
+    /// Number the stitch by the key it was selected with,
+    /// leaving the even numbers for the travel between stitches.
+    pub fn with_key(stitch: HalfStitch, key: usize) -> Self {
+        HalfStitch {
+            order: 2 * key + 1,
+            ..stitch
+        }
+    }
+
+    /// Make the half-stitch across a cell, starting from the given corner.
+    pub fn new_in_cell(cell: GridCell, stitch_corner: StartingStitchCorner, order: usize) -> Self {
+        HalfStitch {
+            start: cell + stitch_corner.get_offset_from_bottom_left(),
+            stitch_corner,
+            order,
+        }
+    }
+
+    /// Convert the selected cells into stitches with the same corners in every cell.
+    /// The grid can flip legs in single cells, so this is only needed to set up tests.
+    #[cfg(test)]
     pub fn convert_grid_cells<'a>(
         cells: impl Iterator<Item = &'a GridCell>,
         first_stitch_direction: StartingStitchCorner,
         second_stitch_direction: StartingStitchCorner,
+    ) -> Vec<HalfStitch> {
+        Self::convert_grid_cells_with_corners(cells, |_, leg| match leg {
+            Leg::Bottom => first_stitch_direction,
+            Leg::Top => second_stitch_direction,
+        })
+    }
+
+    /// Convert the selected cells into stitches, with the corner each leg starts from
+    /// chosen for its cell.
+    pub fn convert_grid_cells_with_corners<'a>(
+        cells: impl Iterator<Item = &'a GridCell>,
+        corner: impl Fn(GridCell, Leg) -> StartingStitchCorner,
     ) -> Vec<HalfStitch> {
         let mut seen_cells = HashMap::new();
         let mut out = Vec::new();
         for (i, cell) in cells.enumerate() {
             match seen_cells.contains_key(cell) {
                 false => {
-                    out.push(HalfStitch {
-                        start: *cell + first_stitch_direction.get_offset_from_bottom_left(),
-                        stitch_corner: first_stitch_direction,
-                        order: i,
-                    });
+                    out.push(HalfStitch::new_in_cell(
+                        *cell,
+                        corner(*cell, Leg::Bottom),
+                        i,
+                    ));
                     seen_cells.insert(cell, true);
                 }
                 true => {
-                    out.push(HalfStitch {
-                        start: *cell + second_stitch_direction.get_offset_from_bottom_left(),
-                        stitch_corner: second_stitch_direction,
-                        order: i,
-                    });
+                    out.push(HalfStitch::new_in_cell(*cell, corner(*cell, Leg::Top), i));
                 }
             }
         }
+        // Stitches take the odd numbers so that the travel between them can take the even ones.
         for stitch in out.iter_mut() {
-            if stitch.order == 0 {
-                stitch.order = 1;
-            } else {
-                stitch.order += 2;
-            }
+            stitch.order = 2 * stitch.order + 1;
         }
         out
     }
 
-    pub fn check_valid_sequence_float(
+    /// Calculate the cost of a valid sequence in both representations.
+    pub fn calculate_cost(
         stitches: &[HalfStitch],
-    ) -> Result<String, (GridCell, GridCell)> {
+        restart_policy: Option<RestartPolicy>,
+    ) -> Result<SequenceCost, (GridCell, GridCell)> {
         Self::_check_valid_sequence(stitches)?;
-        Ok(format!(
-            "{:.4}",
-            HalfStitch::_calculate_cost_float(stitches)
-        ))
+        Ok(match restart_policy {
+            None => SequenceCost {
+                float: HalfStitch::calculate_cost_float(stitches),
+                symbolic: HalfStitch::_calculate_cost_symbolic(stitches),
+            },
+            Some(policy) => SequenceCost {
+                float: HalfStitch::calculate_cost_with_restarts_float(stitches, policy),
+                symbolic: HalfStitch::_calculate_cost_with_restarts_symbolic(stitches, policy),
+            },
+        })
     }
 
-    pub fn check_valid_sequence_symbolic(
+    /// Split the sequence into the runs stitched by separate threads.
+    /// Without a restart policy, the whole sequence is stitched with one thread.
+    pub fn split_into_runs(
         stitches: &[HalfStitch],
-    ) -> Result<String, (GridCell, GridCell)> {
-        Self::_check_valid_sequence(stitches)?;
-        Ok(HalfStitch::_calculate_cost_symbolic(stitches).to_string())
+        restart_policy: Option<RestartPolicy>,
+    ) -> Vec<&[HalfStitch]> {
+        if stitches.is_empty() {
+            return vec![];
+        }
+        match restart_policy {
+            None => vec![stitches],
+            Some(policy) => stitches
+                .chunk_by(|first, second| {
+                    !policy.is_restart(first.get_end_location(), second.start)
+                })
+                .collect(),
+        }
+    }
+
+    /// Whether no stitch comes back up through the hole the last one went down.
+    pub fn is_valid_sequence(stitches: &[HalfStitch]) -> bool {
+        Self::_check_valid_sequence(stitches).is_ok()
     }
 
     fn _check_valid_sequence(stitches: &[HalfStitch]) -> Result<(), (GridCell, GridCell)> {
         let mut last_stitch: Option<&HalfStitch> = None;
         for stitch in stitches {
             match last_stitch {
-                None => {}
-                Some(&last) => {
-                    if last.get_end_location() == stitch.start {
-                        return Err((
-                            last.start - last.stitch_corner.get_offset_from_bottom_left(),
-                            stitch.start - stitch.stitch_corner.get_offset_from_bottom_left(),
-                        ));
-                    }
+                Some(&last) if last.get_end_location() == stitch.start => {
+                    return Err((last.cell(), stitch.cell()));
                 }
+                _ => {}
             }
             last_stitch = Some(stitch);
         }
@@ -155,7 +295,7 @@ impl HalfStitch {
     /// This is in units, where one unit is the distance between cells.
     /// It does not include the length of the actual stitch, just distance on the 'back'.
     /// Calculated as a float.
-    fn _calculate_cost_float(stitches: &[HalfStitch]) -> f64 {
+    pub fn calculate_cost_float(stitches: &[HalfStitch]) -> f64 {
         let mut total = 0.0;
         for stitch in stitches.windows(2) {
             let first_point = stitch[0].get_end_location();
@@ -172,6 +312,43 @@ impl HalfStitch {
         }
         distance
     }
+
+    /// Calculate the total cost of the sequence of half-stitches,
+    /// where any travel longer than the policy allows is replaced with a restart.
+    /// Each restart costs the fixed penalty instead of the distance travelled.
+    pub fn calculate_cost_with_restarts_float(
+        stitches: &[HalfStitch],
+        restart_policy: RestartPolicy,
+    ) -> f64 {
+        let mut total = 0.0;
+        for stitch in stitches.windows(2) {
+            let first_point = stitch[0].get_end_location();
+            let second_point = stitch[1].start;
+            total += if restart_policy.is_restart(first_point, second_point) {
+                restart_policy.restart_penalty as f64
+            } else {
+                first_point.euclidean_distance(&second_point)
+            };
+        }
+        total
+    }
+
+    fn _calculate_cost_with_restarts_symbolic(
+        stitches: &[HalfStitch],
+        restart_policy: RestartPolicy,
+    ) -> SymbolicSum {
+        let mut distance = SymbolicSum::default();
+        for stitch in stitches.windows(2) {
+            let first_point = stitch[0].get_end_location();
+            let second_point = stitch[1].start;
+            if restart_policy.is_restart(first_point, second_point) {
+                distance.add_constant(restart_policy.restart_penalty);
+            } else {
+                distance.add_distance(first_point, second_point);
+            }
+        }
+        distance
+    }
 }
 
 #[cfg(test)]
@@ -227,7 +404,7 @@ mod test {
             HalfStitch {
                 start: GridCell { x: 0, y: 0 },
                 stitch_corner: StartingStitchCorner::BottomLeft,
-                order: 0
+                order: 1
             }
         )
     }
@@ -245,12 +422,12 @@ mod test {
                 HalfStitch {
                     start: GridCell { x: 0, y: 0 },
                     stitch_corner: StartingStitchCorner::BottomLeft,
-                    order: 0
+                    order: 1
                 },
                 HalfStitch {
                     start: GridCell { x: 1, y: 0 },
                     stitch_corner: StartingStitchCorner::BottomRight,
-                    order: 0
+                    order: 3
                 },
             ]
         )
@@ -274,27 +451,55 @@ mod test {
                 HalfStitch {
                     start: GridCell { x: 0, y: 0 },
                     stitch_corner: StartingStitchCorner::BottomLeft,
-                    order: 0
+                    order: 1
                 },
                 HalfStitch {
                     start: GridCell { x: 1, y: 0 },
                     stitch_corner: StartingStitchCorner::BottomRight,
-                    order: 0
+                    order: 3
                 },
                 HalfStitch {
                     start: GridCell { x: 1, y: 0 },
                     stitch_corner: StartingStitchCorner::BottomLeft,
-                    order: 0
+                    order: 5
                 },
             ]
         )
     }
 
+    #[test]
+    fn test_convert_grid_cells_with_corner_per_cell() {
+        let cells = [
+            GridCell::new(0, 0),
+            GridCell::new(1, 0),
+            GridCell::new(0, 0),
+        ];
+        let result =
+            HalfStitch::convert_grid_cells_with_corners(cells.iter(), |cell, leg| {
+                match (cell.x, leg) {
+                    (1, _) => StartingStitchCorner::TopRight,
+                    (_, Leg::Bottom) => StartingStitchCorner::BottomLeft,
+                    (_, Leg::Top) => StartingStitchCorner::TopLeft,
+                }
+            });
+        assert_eq!(
+            result
+                .iter()
+                .map(|stitch| (stitch.cell(), stitch.stitch_corner, stitch.order))
+                .collect::<Vec<_>>(),
+            [
+                (GridCell::new(0, 0), StartingStitchCorner::BottomLeft, 1),
+                (GridCell::new(1, 0), StartingStitchCorner::TopRight, 3),
+                (GridCell::new(0, 0), StartingStitchCorner::TopLeft, 5),
+            ]
+        );
+    }
+
     /// The distance of a single full stitch on a single full cell.
     #[test]
     fn test_stitch_distance_one_full_stitch() {
         let stitches = [GridCell { x: 0, y: 0 }, GridCell { x: 0, y: 0 }];
-        let result = HalfStitch::_calculate_cost_float(&HalfStitch::convert_grid_cells(
+        let result = HalfStitch::calculate_cost_float(&HalfStitch::convert_grid_cells(
             stitches.iter(),
             StartingStitchCorner::BottomLeft,
             StartingStitchCorner::BottomRight,
@@ -306,7 +511,7 @@ mod test {
     #[test]
     fn test_stitch_distance_two_consecutive_half_stitches() {
         let stitches = [GridCell { x: 0, y: 0 }, GridCell { x: 1, y: 0 }];
-        let result = HalfStitch::_calculate_cost_float(&HalfStitch::convert_grid_cells(
+        let result = HalfStitch::calculate_cost_float(&HalfStitch::convert_grid_cells(
             stitches.iter(),
             StartingStitchCorner::BottomLeft,
             StartingStitchCorner::BottomRight,
@@ -322,7 +527,7 @@ mod test {
             GridCell { x: 1, y: 0 },
             GridCell { x: 2, y: 0 },
         ];
-        let result = HalfStitch::_calculate_cost_float(&HalfStitch::convert_grid_cells(
+        let result = HalfStitch::calculate_cost_float(&HalfStitch::convert_grid_cells(
             stitches.iter(),
             StartingStitchCorner::BottomLeft,
             StartingStitchCorner::BottomRight,
@@ -338,7 +543,7 @@ mod test {
             GridCell { x: 0, y: 0 },
             GridCell { x: 1, y: 0 },
         ];
-        let result = HalfStitch::_calculate_cost_float(&HalfStitch::convert_grid_cells(
+        let result = HalfStitch::calculate_cost_float(&HalfStitch::convert_grid_cells(
             stitches.iter(),
             StartingStitchCorner::BottomLeft,
             StartingStitchCorner::BottomRight,
@@ -354,7 +559,7 @@ mod test {
             GridCell { x: 0, y: 0 },
             GridCell { x: 1, y: 1 },
         ];
-        let result = HalfStitch::_calculate_cost_float(&HalfStitch::convert_grid_cells(
+        let result = HalfStitch::calculate_cost_float(&HalfStitch::convert_grid_cells(
             stitches.iter(),
             StartingStitchCorner::BottomLeft,
             StartingStitchCorner::BottomRight,
@@ -366,7 +571,7 @@ mod test {
     #[test]
     fn test_stitch_distance_two_half_stitches_column_up() {
         let stitches = [GridCell { x: 0, y: 0 }, GridCell { x: 0, y: 1 }];
-        let result = HalfStitch::_calculate_cost_float(&HalfStitch::convert_grid_cells(
+        let result = HalfStitch::calculate_cost_float(&HalfStitch::convert_grid_cells(
             stitches.iter(),
             StartingStitchCorner::BottomLeft,
             StartingStitchCorner::BottomRight,
@@ -378,7 +583,7 @@ mod test {
     #[test]
     fn test_stitch_distance_two_half_stitches_column_down() {
         let stitches = [GridCell { x: 0, y: 0 }, GridCell { x: 0, y: -1 }];
-        let result = HalfStitch::_calculate_cost_float(&HalfStitch::convert_grid_cells(
+        let result = HalfStitch::calculate_cost_float(&HalfStitch::convert_grid_cells(
             stitches.iter(),
             StartingStitchCorner::BottomLeft,
             StartingStitchCorner::BottomRight,
@@ -386,6 +591,118 @@ mod test {
         assert_eq!(_round_float(result), 2.236);
     }
 
+    fn _single_row_with_gap() -> Vec<HalfStitch> {
+        HalfStitch::convert_grid_cells(
+            [
+                GridCell { x: 0, y: 0 },
+                GridCell { x: 1, y: 0 },
+                GridCell { x: 10, y: 0 },
+            ]
+            .iter(),
+            StartingStitchCorner::BottomLeft,
+            StartingStitchCorner::BottomRight,
+        )
+    }
+
+    #[test]
+    fn test_split_into_runs_no_policy() {
+        let stitches = _single_row_with_gap();
+        let result = HalfStitch::split_into_runs(&stitches, None);
+        assert_eq!(result, vec![stitches.as_slice()]);
+    }
+
+    #[test]
+    fn test_split_into_runs_long_travel() {
+        let stitches = _single_row_with_gap();
+        let result = HalfStitch::split_into_runs(&stitches, Some(RestartPolicy::default()));
+        assert_eq!(result, vec![&stitches[..2], &stitches[2..]]);
+    }
+
+    #[test]
+    fn test_split_into_runs_empty() {
+        let result = HalfStitch::split_into_runs(&[], Some(RestartPolicy::default()));
+        assert!(result.is_empty());
+    }
+
+    /// A long jump is replaced by the restart penalty.
+    #[test]
+    fn test_stitch_distance_with_restart() {
+        let stitches = _single_row_with_gap();
+        let policy = RestartPolicy {
+            max_travel_distance: 5.0,
+            restart_penalty: 3,
+        };
+        let result = HalfStitch::calculate_cost_with_restarts_float(&stitches, policy);
+        assert_eq!(_round_float(result), 4.0);
+        let symbolic = HalfStitch::_calculate_cost_with_restarts_symbolic(&stitches, policy);
+        assert_eq!(symbolic.to_string(), "4");
+    }
+
+    /// A jump shorter than the maximum travel and the penalty costs the distance as usual.
+    #[test]
+    fn test_stitch_distance_restart_not_needed() {
+        let stitches = _single_row_with_gap();
+        let policy = RestartPolicy {
+            max_travel_distance: 20.0,
+            restart_penalty: 20,
+        };
+        let result = HalfStitch::calculate_cost_with_restarts_float(&stitches, policy);
+        assert_eq!(
+            _round_float(result),
+            _round_float(HalfStitch::calculate_cost_float(&stitches))
+        );
+    }
+
+    /// A jump that costs more than the penalty is cut, even when it is short enough to travel.
+    #[test]
+    fn test_restart_when_cheaper_than_travel() {
+        let stitches = _single_row_with_gap();
+        let policy = RestartPolicy {
+            max_travel_distance: 20.0,
+            restart_penalty: 3,
+        };
+        assert_eq!(
+            HalfStitch::split_into_runs(&stitches, Some(policy)).len(),
+            2
+        );
+        let result = HalfStitch::calculate_cost_with_restarts_float(&stitches, policy);
+        assert_eq!(_round_float(result), 4.0);
+    }
+
+    #[test]
+    fn test_calculate_cost_both_representations() {
+        let stitches = HalfStitch::convert_grid_cells(
+            [
+                GridCell { x: 0, y: 0 },
+                GridCell { x: 0, y: 0 },
+                GridCell { x: 1, y: 0 },
+            ]
+            .iter(),
+            StartingStitchCorner::BottomLeft,
+            StartingStitchCorner::BottomRight,
+        );
+        let result = HalfStitch::calculate_cost(&stitches, None).unwrap();
+        assert_eq!(_round_float(result.float), 2.414);
+        assert_eq!(result.symbolic.to_string(), "1 + √2");
+    }
+
+    #[test]
+    fn test_cost_difference_from_baseline() {
+        let baseline = HalfStitch::calculate_cost(&_single_row_with_gap(), None).unwrap();
+        let current = HalfStitch::calculate_cost(
+            &_single_row_with_gap(),
+            Some(RestartPolicy {
+                max_travel_distance: 5.0,
+                restart_penalty: 3,
+            }),
+        )
+        .unwrap();
+        let result = current.difference_from(&baseline);
+        assert_eq!(_round_float(result.float), _round_float(3.0 - 65f64.sqrt()));
+        assert_eq!(result.symbolic.to_string(), "3 - √65");
+        assert!(result.symbolic < SymbolicSum::default());
+    }
+
     #[test]
     fn test_get_opposite_corner_from_bottom_left() {
         let result = StartingStitchCorner::BottomLeft.get_opposite_corner();
@@ -409,4 +726,13 @@ mod test {
             ]
         );
     }
+
+    #[test]
+    fn test_next_corner_cycles_through_all() {
+        let mut corner = StartingStitchCorner::BottomRight;
+        corner = corner.next();
+        assert_eq!(corner, StartingStitchCorner::BottomLeft);
+        corner = corner.next();
+        assert_eq!(corner, StartingStitchCorner::TopLeft);
+    }
 }
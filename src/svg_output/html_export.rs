@@ -0,0 +1,185 @@
+use crate::grid_cell::GridCell;
+use crate::stitch::{HalfStitch, RestartPolicy};
+use crate::svg_output::svg_construction::create_graphic;
+use crate::svg_output::svg_options::SvgOptions;
+use crate::theme::Palette;
+use std::collections::HashMap;
+
+/// Styles for the page, the tooltip, and the highlighted step.
+const STYLE: &str = r#"
+body { font-family: sans-serif; margin: 1em; }
+#controls { position: sticky; top: 0; background: white; padding: 0.5em 0; display: flex; gap: 1em; align-items: center; flex-wrap: wrap; }
+#step-slider { flex: 1; min-width: 15em; }
+#diagram svg { width: 100%; height: auto; }
+#tooltip { position: fixed; pointer-events: none; background: #222; color: white; padding: 0.3em 0.6em; border-radius: 0.3em; font-size: 0.9em; }
+[data-tooltip] { cursor: help; }
+.highlight { stroke: #ff8c00; fill: #ff8c00; }
+"#;
+
+/// Reveals the steps up to the slider, finds a step from the search box,
+/// and shows the tooltip of whatever is under the mouse.
+const SCRIPT: &str = r#"
+const steps = Array.from(document.querySelectorAll('#diagram [data-step]'));
+const maxStep = Math.max(1, ...steps.map(element => Number(element.dataset.step)));
+const slider = document.getElementById('step-slider');
+const stepLabel = document.getElementById('step-label');
+const tooltip = document.getElementById('tooltip');
+slider.max = maxStep;
+slider.value = maxStep;
+
+function showSteps(last) {
+  for (const element of steps) {
+    element.style.display = Number(element.dataset.step) <= last ? '' : 'none';
+  }
+  stepLabel.textContent = `Step ${last} of ${maxStep}`;
+}
+
+function highlightStep(step) {
+  let found = null;
+  for (const element of steps) {
+    const matches = Number(element.dataset.step) === step;
+    element.classList.toggle('highlight', matches);
+    if (matches && element.dataset.tooltip) {
+      found = element;
+    }
+  }
+  if (found) {
+    found.scrollIntoView({ block: 'center', inline: 'center' });
+    stepLabel.textContent = found.dataset.tooltip;
+  }
+}
+
+slider.addEventListener('input', () => showSteps(Number(slider.value)));
+
+document.getElementById('step-search').addEventListener('submit', event => {
+  event.preventDefault();
+  const step = Number(document.getElementById('step-number').value);
+  if (!Number.isInteger(step) || step < 1 || step > maxStep) {
+    stepLabel.textContent = `There is no step ${step}`;
+    return;
+  }
+  slider.value = step;
+  showSteps(step);
+  highlightStep(step);
+});
+
+const diagram = document.getElementById('diagram');
+diagram.addEventListener('mouseover', event => {
+  const element = event.target.closest('[data-tooltip]');
+  tooltip.hidden = !element;
+  if (element) {
+    tooltip.textContent = element.dataset.tooltip;
+  }
+});
+diagram.addEventListener('mousemove', event => {
+  tooltip.style.left = `${event.clientX + 12}px`;
+  tooltip.style.top = `${event.clientY + 12}px`;
+});
+diagram.addEventListener('mouseleave', () => { tooltip.hidden = true; });
+
+showSteps(maxStep);
+"#;
+
+/// Wrap the diagram in a web page that can step through the sequence,
+/// with the details of each stitch and travel line when the mouse is over it.
+/// Everything is inline, so the page works without a network connection.
+pub fn create_interactive_html(
+    stitches: &[HalfStitch],
+    colours: &HashMap<GridCell, usize>,
+    restart_policy: Option<RestartPolicy>,
+    palette: &Palette,
+    options: &SvgOptions,
+    title: &str,
+    description: &str,
+) -> String {
+    let document = create_graphic(stitches, colours, restart_policy, palette, options);
+    let title = escape_html(title);
+    let description = escape_html(description);
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str(&format!("<title>{title}</title>\n<style>{STYLE}</style>\n"));
+    html.push_str("</head>\n<body>\n");
+    html.push_str(&format!("<h1>{title}</h1>\n<p>{description}</p>\n"));
+    html.push_str(concat!(
+        "<div id=\"controls\">\n",
+        "<label for=\"step-slider\">Show steps</label>\n",
+        "<input id=\"step-slider\" type=\"range\" min=\"1\" max=\"1\" value=\"1\">\n",
+        "<span id=\"step-label\"></span>\n",
+        "<form id=\"step-search\">\n",
+        "<input id=\"step-number\" type=\"number\" min=\"1\" placeholder=\"Step\">\n",
+        "<button type=\"submit\">Go to step</button>\n",
+        "</form>\n",
+        "</div>\n",
+    ));
+    html.push_str(&format!("<div id=\"diagram\">\n{}\n</div>\n", document));
+    html.push_str("<div id=\"tooltip\" hidden></div>\n");
+    html.push_str(&format!("<script>{SCRIPT}</script>\n</body>\n</html>\n"));
+    html
+}
+
+pub(super) fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stitch::StartingStitchCorner;
+    use crate::theme::ColourTheme;
+
+    fn _page(stitches: &[HalfStitch]) -> String {
+        create_interactive_html(
+            stitches,
+            &HashMap::new(),
+            None,
+            &ColourTheme::default().palette(),
+            &SvgOptions::default(),
+            "Hearts & Flowers",
+            "2 m of thread",
+        )
+    }
+
+    fn _row() -> Vec<HalfStitch> {
+        let cells = [
+            GridCell::new(0, 0),
+            GridCell::new(1, 0),
+            GridCell::new(2, 0),
+        ];
+        HalfStitch::convert_grid_cells(
+            cells.iter(),
+            StartingStitchCorner::BottomLeft,
+            StartingStitchCorner::BottomRight,
+        )
+    }
+
+    #[test]
+    fn test_every_step_can_be_revealed() {
+        let page = _page(&_row());
+        // Three stitches and two travel lines, each with its sequence number.
+        for step in 1..=5 {
+            let count = page.matches(&format!("data-step=\"{}\"", step)).count();
+            assert_eq!(count, 2, "step {}", step);
+        }
+        assert!(page.contains("id=\"step-slider\""));
+        assert!(page.contains("id=\"step-number\""));
+    }
+
+    #[test]
+    fn test_tooltips_describe_stitches_and_travel() {
+        let page = _page(&_row());
+        assert!(page.contains("Step 3: half stitch in cell (1, 0) from the bottom left corner"));
+        assert!(page.contains("Step 2: travel from (1, 1) to (1, 0), 1.00 cells"));
+    }
+
+    #[test]
+    fn test_page_is_self_contained() {
+        let page = _page(&_row());
+        assert!(page.contains("<title>Hearts &amp; Flowers</title>"));
+        assert!(!page.contains(" src="));
+        assert!(!page.contains("<link"));
+        assert!(!page.contains("@import"));
+    }
+}
@@ -0,0 +1,354 @@
+use crate::grid_cell::GridCell;
+use crate::spatial_index::SpatialIndex;
+use std::ops::RangeInclusive;
+
+/// A point in SVG coordinates.
+pub type Point = (f64, f64);
+
+/// A straight line between two points in SVG coordinates.
+pub type Segment = (Point, Point);
+
+/// The box taken up by a label, where `y` is the baseline of the text as in SVG.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LabelBox {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+/// Where a label ended up, with a leader line back to where it wanted to be if it moved far.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Placement {
+    pub x: f64,
+    pub y: f64,
+    pub leader: Option<Segment>,
+}
+
+/// How many rings of candidate positions are tried around the preferred position.
+const MAX_RINGS: usize = 8;
+
+/// Labels moved beyond this many rings get a leader line.
+const LEADER_RINGS: usize = 3;
+
+/// The directions tried on each ring, starting straight along the line of text.
+const DIRECTIONS: [Point; 8] = [
+    (1.0, 0.0),
+    (0.0, -1.0),
+    (-1.0, 0.0),
+    (0.0, 1.0),
+    (1.0, -1.0),
+    (-1.0, -1.0),
+    (-1.0, 1.0),
+    (1.0, 1.0),
+];
+
+impl LabelBox {
+    /// The box for the text at the given position, estimating the width of monospace characters.
+    pub fn for_text(text: &str, x: f64, y: f64, font_size: f64) -> LabelBox {
+        LabelBox {
+            x,
+            y,
+            width: text.chars().count() as f64 * font_size * 0.6,
+            height: font_size,
+        }
+    }
+
+    fn top(&self) -> f64 {
+        self.y - self.height
+    }
+
+    fn centre(&self) -> Point {
+        (self.x + self.width / 2.0, self.y - self.height / 2.0)
+    }
+
+    fn moved(&self, dx: f64, dy: f64) -> LabelBox {
+        LabelBox {
+            x: self.x + dx,
+            y: self.y + dy,
+            ..*self
+        }
+    }
+
+    pub fn overlaps(&self, other: &LabelBox) -> bool {
+        self.x < other.x + other.width
+            && other.x < self.x + self.width
+            && self.top() < other.y
+            && other.top() < self.y
+    }
+
+    fn contains(&self, (x, y): Point) -> bool {
+        self.x < x && x < self.x + self.width && self.top() < y && y < self.y
+    }
+
+    fn within(&self, (width, height): Point) -> bool {
+        self.x >= 0.0 && self.top() >= 0.0 && self.x + self.width <= width && self.y <= height
+    }
+
+    pub fn intersects(&self, segment: &Segment) -> bool {
+        let (left, right, top, bottom) = (self.x, self.x + self.width, self.top(), self.y);
+        let edges = [
+            ((left, top), (right, top)),
+            ((right, top), (right, bottom)),
+            ((right, bottom), (left, bottom)),
+            ((left, bottom), (left, top)),
+        ];
+        self.contains(segment.0)
+            || self.contains(segment.1)
+            || edges.iter().any(|edge| segments_cross(edge, segment))
+    }
+}
+
+/// Whether two segments cross or touch.
+fn segments_cross(first: &Segment, second: &Segment) -> bool {
+    fn orientation(a: Point, b: Point, c: Point) -> f64 {
+        (b.0 - a.0) * (c.1 - a.1) - (b.1 - a.1) * (c.0 - a.0)
+    }
+    fn on_segment(a: Point, b: Point, c: Point) -> bool {
+        c.0 >= a.0.min(b.0) && c.0 <= a.0.max(b.0) && c.1 >= a.1.min(b.1) && c.1 <= a.1.max(b.1)
+    }
+    let (a, b) = *first;
+    let (c, d) = *second;
+    let (o1, o2, o3, o4) = (
+        orientation(a, b, c),
+        orientation(a, b, d),
+        orientation(c, d, a),
+        orientation(c, d, b),
+    );
+    if o1 * o2 < 0.0 && o3 * o4 < 0.0 {
+        return true;
+    }
+    (o1 == 0.0 && on_segment(a, b, c))
+        || (o2 == 0.0 && on_segment(a, b, d))
+        || (o3 == 0.0 && on_segment(c, d, a))
+        || (o4 == 0.0 && on_segment(c, d, b))
+}
+
+/// Boxes and lines bucketed into square buckets of SVG space,
+/// so that only the things near a candidate position have to be checked.
+struct Obstacles<'a> {
+    bucket_size: f64,
+    lines: &'a [Segment],
+    line_index: SpatialIndex<usize>,
+    placed: Vec<LabelBox>,
+    placed_index: SpatialIndex<usize>,
+}
+
+impl<'a> Obstacles<'a> {
+    fn new(lines: &'a [Segment], bucket_size: f64) -> Obstacles<'a> {
+        let mut obstacles = Obstacles {
+            bucket_size,
+            lines,
+            line_index: SpatialIndex::default(),
+            placed: Vec::new(),
+            placed_index: SpatialIndex::default(),
+        };
+        for (i, &(start, end)) in lines.iter().enumerate() {
+            // Walk the line in pieces no longer than a bucket, so a long diagonal line
+            // only lands in the buckets along it rather than every bucket in its bounding box.
+            let length = ((end.0 - start.0).powi(2) + (end.1 - start.1).powi(2)).sqrt();
+            let pieces = (length / bucket_size).ceil().max(1.0) as usize;
+            let point_at = |t: f64| {
+                (
+                    start.0 + (end.0 - start.0) * t,
+                    start.1 + (end.1 - start.1) * t,
+                )
+            };
+            for piece in 0..pieces {
+                let (a, b) = (
+                    point_at(piece as f64 / pieces as f64),
+                    point_at((piece + 1) as f64 / pieces as f64),
+                );
+                let (columns, rows) =
+                    obstacles.buckets(a.0.min(b.0), a.1.min(b.1), a.0.max(b.0), a.1.max(b.1));
+                for x in columns {
+                    for y in rows.clone() {
+                        obstacles.line_index.insert(GridCell::new(x, y), i);
+                    }
+                }
+            }
+        }
+        obstacles
+    }
+
+    fn bucket(&self, value: f64) -> isize {
+        (value / self.bucket_size).floor() as isize
+    }
+
+    fn buckets(
+        &self,
+        left: f64,
+        top: f64,
+        right: f64,
+        bottom: f64,
+    ) -> (RangeInclusive<isize>, RangeInclusive<isize>) {
+        (
+            self.bucket(left)..=self.bucket(right),
+            self.bucket(top)..=self.bucket(bottom),
+        )
+    }
+
+    fn buckets_of(&self, label: &LabelBox) -> (RangeInclusive<isize>, RangeInclusive<isize>) {
+        self.buckets(label.x, label.top(), label.x + label.width, label.y)
+    }
+
+    fn is_free(&self, candidate: &LabelBox) -> bool {
+        let (columns, rows) = self.buckets_of(candidate);
+        !self
+            .placed_index
+            .query(columns.clone(), rows.clone())
+            .any(|(_, i)| self.placed[*i].overlaps(candidate))
+            && !self
+                .line_index
+                .query(columns, rows)
+                .any(|(_, i)| candidate.intersects(&self.lines[*i]))
+    }
+
+    fn place(&mut self, label: LabelBox) {
+        let (columns, rows) = self.buckets_of(&label);
+        for x in columns {
+            for y in rows.clone() {
+                self.placed_index
+                    .insert(GridCell::new(x, y), self.placed.len());
+            }
+        }
+        self.placed.push(label);
+    }
+}
+
+/// Move each label from its preferred position so that it overlaps neither the lines
+/// nor any label placed before it, staying inside the bounds of the drawing.
+/// Labels that have to move a long way get a leader line back to their preferred position,
+/// and a label with no free space nearby is left where it wanted to be.
+pub fn place_labels(labels: &[LabelBox], lines: &[Segment], bounds: Point) -> Vec<Placement> {
+    let bucket_size = labels
+        .iter()
+        .map(|label| label.width.max(label.height))
+        .fold(0.0, f64::max);
+    let mut obstacles = Obstacles::new(lines, if bucket_size > 0.0 { bucket_size } else { 1.0 });
+    let mut placements = Vec::with_capacity(labels.len());
+
+    for label in labels {
+        let step = label.height * 0.75;
+        let mut candidates = std::iter::once((0, *label)).chain((1..=MAX_RINGS).flat_map(|ring| {
+            DIRECTIONS.iter().map(move |(dx, dy)| {
+                let distance = ring as f64 * step;
+                (ring, label.moved(dx * distance, dy * distance))
+            })
+        }));
+
+        let (ring, chosen) = candidates
+            .find(|(_, candidate)| candidate.within(bounds) && obstacles.is_free(candidate))
+            .unwrap_or((0, *label));
+        let leader = (ring >= LEADER_RINGS).then(|| (label.centre(), chosen.centre()));
+
+        obstacles.place(chosen);
+        placements.push(Placement {
+            x: chosen.x,
+            y: chosen.y,
+            leader,
+        });
+    }
+    placements
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FONT_SIZE: f64 = 50.0;
+    const BOUNDS: Point = (10_000.0, 10_000.0);
+
+    fn _as_box(placement: &Placement, label: &LabelBox) -> LabelBox {
+        LabelBox {
+            x: placement.x,
+            y: placement.y,
+            ..*label
+        }
+    }
+
+    #[test]
+    fn test_free_label_stays_in_place() {
+        let label = LabelBox::for_text("1", 100.0, 100.0, FONT_SIZE);
+        let result = place_labels(&[label], &[], BOUNDS);
+        assert_eq!(
+            result,
+            vec![Placement {
+                x: 100.0,
+                y: 100.0,
+                leader: None
+            }]
+        );
+    }
+
+    #[test]
+    fn test_label_moves_off_line() {
+        let label = LabelBox::for_text("12", 100.0, 100.0, FONT_SIZE);
+        let line = ((0.0, 80.0), (500.0, 80.0));
+        let result = place_labels(&[label], &[line], BOUNDS);
+        assert!(!_as_box(&result[0], &label).intersects(&line));
+    }
+
+    #[test]
+    fn test_labels_at_same_place_are_separated() {
+        let labels = vec![LabelBox::for_text("1", 500.0, 500.0, FONT_SIZE); 5];
+        let result = place_labels(&labels, &[], BOUNDS);
+        let boxes: Vec<LabelBox> = result
+            .iter()
+            .zip(&labels)
+            .map(|(placement, label)| _as_box(placement, label))
+            .collect();
+        for (i, first) in boxes.iter().enumerate() {
+            for second in &boxes[i + 1..] {
+                assert!(!first.overlaps(second));
+            }
+        }
+    }
+
+    #[test]
+    fn test_far_move_gets_leader_line() {
+        // Surround the label with lines so the nearby positions are taken.
+        let label = LabelBox::for_text("1", 500.0, 500.0, FONT_SIZE);
+        let lines: Vec<Segment> = (0..8)
+            .map(|i| {
+                let y = 400.0 + i as f64 * 25.0;
+                ((350.0, y), (700.0, y))
+            })
+            .collect();
+        let result = place_labels(&[label], &lines, BOUNDS);
+        assert!(result[0].leader.is_some());
+        assert!(!lines
+            .iter()
+            .any(|line| _as_box(&result[0], &label).intersects(line)));
+    }
+
+    #[test]
+    fn test_labels_stay_in_bounds() {
+        let label = LabelBox::for_text("1", -20.0, 10.0, FONT_SIZE);
+        let result = place_labels(&[label], &[], BOUNDS);
+        assert!(_as_box(&result[0], &label).within(BOUNDS));
+    }
+
+    #[test]
+    fn test_label_moves_off_long_diagonal_line() {
+        // The label sits far from either end of the line, so it is only found through
+        // the buckets along the middle of the line.
+        let label = LabelBox::for_text("1", 5_000.0, 5_020.0, FONT_SIZE);
+        let line = ((0.0, 0.0), (10_000.0, 10_000.0));
+        let result = place_labels(&[label], &[line], BOUNDS);
+        assert!(label.intersects(&line));
+        assert!(!_as_box(&result[0], &label).intersects(&line));
+    }
+
+    #[test]
+    fn test_segments_cross() {
+        assert!(segments_cross(
+            &((0.0, 0.0), (10.0, 10.0)),
+            &((0.0, 10.0), (10.0, 0.0))
+        ));
+        assert!(!segments_cross(
+            &((0.0, 0.0), (10.0, 0.0)),
+            &((0.0, 5.0), (10.0, 5.0))
+        ));
+    }
+}
@@ -1,7 +1,7 @@
 use crate::grid_cell::GridCell;
 use std::cmp::{max, min};
 
-/// A struct for working with lines that are orthogonal to a grid i.e. straight between grid points.
+/// A struct for working with lines that are straight between grid points.
 #[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
 pub struct LineSegment {
     start: GridCell,
@@ -9,10 +9,14 @@ pub struct LineSegment {
     pub order: usize,
 }
 
-#[derive(Debug, PartialEq, Eq)]
-enum Axis {
-    Horizontal,
-    Vertical,
+/// The infinite line that a segment lies on.
+/// Segments can only overlap if they are on the same line.
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Line {
+    // The smallest step between grid points along the line.
+    direction: (isize, isize),
+    // Which of the parallel lines with that direction this is.
+    offset: isize,
 }
 
 impl LineSegment {
@@ -25,60 +29,93 @@ impl LineSegment {
     }
 
     /// Determines if two LineSegments overlap.
+    /// They must lie on the same line, at any angle, and share more than an end point.
     pub fn overlaps(&self, other: &LineSegment) -> bool {
-        // Determine if both segments are horizontal or vertical
-        let self_orientation = self.orientation();
-        let other_orientation = other.orientation();
-
-        match (self_orientation, other_orientation) {
-            (Some(self_dir), Some(other_dir)) => {
-                // We don't consider lines of different orientations to be overlapping.
-                if self_dir != other_dir {
-                    return false;
-                }
-
-                if self_dir == Axis::Horizontal {
-                    // Check if they are on the same y-coordinate
-                    if self.start.y != other.start.y {
-                        return false;
-                    }
-                    // Check if their x ranges overlap
-                    let (self_min_x, self_max_x) =
-                        (min(self.start.x, self.end.x), max(self.start.x, self.end.x));
-                    let (other_min_x, other_max_x) = (
-                        min(other.start.x, other.end.x),
-                        max(other.start.x, other.end.x),
-                    );
-                    max(self_min_x, other_min_x) < min(self_max_x, other_max_x)
-                } else {
-                    // Check if they are on the same x-coordinate
-                    if self.start.x != other.start.x {
-                        return false;
-                    }
-                    // Check if their y ranges overlap
-                    let (self_min_y, self_max_y) =
-                        (min(self.start.y, self.end.y), max(self.start.y, self.end.y));
-                    let (other_min_y, other_max_y) = (
-                        min(other.start.y, other.end.y),
-                        max(other.start.y, other.end.y),
-                    );
-                    max(self_min_y, other_min_y) < min(self_max_y, other_max_y)
-                }
-            }
-            _ => false, // One or both segments are not strictly horizontal or vertical
+        let direction = self.vector_to(&self.end);
+        let length_squared = dot(direction, direction);
+        if length_squared == 0 || other.start == other.end {
+            return false;
+        }
+
+        // Both ends of the other segment must be on this segment's line.
+        let to_other_start = self.vector_to(&other.start);
+        let to_other_end = self.vector_to(&other.end);
+        if cross(direction, to_other_start) != 0 || cross(direction, to_other_end) != 0 {
+            return false;
         }
+
+        // Check if their ranges along the line overlap.
+        let other_start = dot(direction, to_other_start);
+        let other_end = dot(direction, to_other_end);
+        max(0, min(other_start, other_end)) < min(length_squared, max(other_start, other_end))
     }
 
-    /// Determines the orientation of a line segment.
-    fn orientation(&self) -> Option<Axis> {
-        if self.start.y == self.end.y {
-            Some(Axis::Horizontal)
-        } else if self.start.x == self.end.x {
-            Some(Axis::Vertical)
+    /// The unit vector along the line.
+    /// It points rightwards, or downwards for vertical lines,
+    /// so that lines on top of each other share a direction whichever way they were stitched.
+    pub fn direction(&self) -> (f64, f64) {
+        let (x, y) = self.vector_to(&self.end);
+        let (x, y) = if x > 0 || (x == 0 && y < 0) {
+            (x, y)
         } else {
-            None
+            (-x, -y)
+        };
+        let length = ((x * x + y * y) as f64).sqrt();
+        if length == 0.0 {
+            return (0.0, 0.0);
+        }
+        (x as f64 / length, y as f64 / length)
+    }
+
+    /// The unit vector perpendicular to the line, used to move overlapping lines apart.
+    pub fn normal(&self) -> (f64, f64) {
+        let (x, y) = self.direction();
+        (-y, x)
+    }
+
+    /// The line that the segment is on, and where the segment starts and ends along it.
+    /// Segments on the same line overlap exactly when these ranges overlap,
+    /// so they can be used to look segments up without comparing every pair.
+    /// Segments with no length are not on any line.
+    pub fn line_span(&self) -> Option<(Line, (isize, isize))> {
+        let (x, y) = self.vector_to(&self.end);
+        let divisor = gcd(x, y);
+        if divisor == 0 {
+            return None;
         }
+        let direction = if x > 0 || (x == 0 && y < 0) {
+            (x / divisor, y / divisor)
+        } else {
+            (-x / divisor, -y / divisor)
+        };
+        let line = Line {
+            direction,
+            offset: cross(direction, (self.start.x, self.start.y)),
+        };
+        let start = dot(direction, (self.start.x, self.start.y));
+        let end = dot(direction, (self.end.x, self.end.y));
+        Some((line, (min(start, end), max(start, end))))
+    }
+
+    fn vector_to(&self, point: &GridCell) -> (isize, isize) {
+        (point.x - self.start.x, point.y - self.start.y)
+    }
+}
+
+fn dot(a: (isize, isize), b: (isize, isize)) -> isize {
+    a.0 * b.0 + a.1 * b.1
+}
+
+fn cross(a: (isize, isize), b: (isize, isize)) -> isize {
+    a.0 * b.1 - a.1 * b.0
+}
+
+fn gcd(a: isize, b: isize) -> isize {
+    let (mut a, mut b) = (a.abs(), b.abs());
+    while b != 0 {
+        (a, b) = (b, a % b);
     }
+    a
 }
 
 impl From<(GridCell, GridCell)> for LineSegment {
@@ -91,9 +128,9 @@ impl From<(GridCell, GridCell)> for LineSegment {
     }
 }
 
-impl Into<(GridCell, GridCell)> for LineSegment {
-    fn into(self) -> (GridCell, GridCell) {
-        (self.start, self.end)
+impl From<LineSegment> for (GridCell, GridCell) {
+    fn from(segment: LineSegment) -> Self {
+        (segment.start, segment.end)
     }
 }
 
@@ -101,7 +138,9 @@ impl Into<(GridCell, GridCell)> for LineSegment {
 mod test {
     use super::*;
 
-    fn assert_commutative<T>(a: T, b: T, function: Box<dyn Fn(&T, &T) -> bool>, expected: bool) {
+    type Relation<T> = Box<dyn Fn(&T, &T) -> bool>;
+
+    fn assert_commutative<T>(a: T, b: T, function: Relation<T>, expected: bool) {
         assert_eq!(function(&a, &b), expected);
         assert_eq!(function(&b, &a), expected);
     }
@@ -189,4 +228,109 @@ mod test {
             true,
         );
     }
+
+    #[test]
+    fn test_contains_segment_diagonal_overlap() {
+        let first_segment = LineSegment::new(GridCell::new(0, 0), GridCell::new(2, 2), 0);
+        let second_segment = LineSegment::new(GridCell::new(3, 3), GridCell::new(1, 1), 0);
+        assert_commutative(
+            first_segment,
+            second_segment,
+            Box::from(LineSegment::overlaps),
+            true,
+        );
+    }
+
+    #[test]
+    fn test_contains_segment_steep_collinear_overlap() {
+        let first_segment = LineSegment::new(GridCell::new(0, 0), GridCell::new(1, 2), 0);
+        let second_segment = LineSegment::new(GridCell::new(-1, -2), GridCell::new(2, 4), 0);
+        assert_commutative(
+            first_segment,
+            second_segment,
+            Box::from(LineSegment::overlaps),
+            true,
+        );
+    }
+
+    #[test]
+    fn test_contains_segment_diagonal_end_touching_no_overlap() {
+        let first_segment = LineSegment::new(GridCell::new(0, 0), GridCell::new(1, 1), 0);
+        let second_segment = LineSegment::new(GridCell::new(1, 1), GridCell::new(3, 3), 0);
+        assert_commutative(
+            first_segment,
+            second_segment,
+            Box::from(LineSegment::overlaps),
+            false,
+        );
+    }
+
+    #[test]
+    fn test_contains_segment_parallel_diagonals_no_overlap() {
+        let first_segment = LineSegment::new(GridCell::new(0, 0), GridCell::new(2, 2), 0);
+        let second_segment = LineSegment::new(GridCell::new(1, 0), GridCell::new(3, 2), 0);
+        assert_commutative(
+            first_segment,
+            second_segment,
+            Box::from(LineSegment::overlaps),
+            false,
+        );
+    }
+
+    #[test]
+    fn test_contains_segment_crossing_diagonals_no_overlap() {
+        let first_segment = LineSegment::new(GridCell::new(0, 0), GridCell::new(2, 2), 0);
+        let second_segment = LineSegment::new(GridCell::new(0, 2), GridCell::new(2, 0), 0);
+        assert_commutative(
+            first_segment,
+            second_segment,
+            Box::from(LineSegment::overlaps),
+            false,
+        );
+    }
+
+    #[test]
+    fn test_normal_is_shared_by_reversed_lines() {
+        let forward = LineSegment::new(GridCell::new(0, 0), GridCell::new(3, 3), 0);
+        let backward = LineSegment::new(GridCell::new(2, 2), GridCell::new(1, 1), 0);
+        let (x, y) = forward.normal();
+        let (backward_x, backward_y) = backward.normal();
+        assert!((x - backward_x).abs() < 1e-9 && (y - backward_y).abs() < 1e-9);
+        assert!((x * x + y * y - 1.0).abs() < 1e-9);
+        assert!((x * 3.0 + y * 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_normal_of_axis_lines() {
+        let horizontal = LineSegment::new(GridCell::new(4, 0), GridCell::new(0, 0), 0);
+        let vertical = LineSegment::new(GridCell::new(0, 0), GridCell::new(0, 4), 0);
+        assert_eq!(horizontal.normal(), (-0.0, 1.0));
+        assert_eq!(vertical.normal(), (1.0, 0.0));
+    }
+
+    #[test]
+    fn test_line_span_matches_overlaps() {
+        let segments = [
+            LineSegment::new(GridCell::new(0, 0), GridCell::new(2, 2), 0),
+            LineSegment::new(GridCell::new(3, 3), GridCell::new(1, 1), 0),
+            LineSegment::new(GridCell::new(2, 2), GridCell::new(4, 4), 0),
+            LineSegment::new(GridCell::new(1, 0), GridCell::new(3, 2), 0),
+            LineSegment::new(GridCell::new(0, 4), GridCell::new(0, 1), 0),
+            LineSegment::new(GridCell::new(0, 2), GridCell::new(0, 3), 0),
+            LineSegment::new(GridCell::new(-2, -4), GridCell::new(1, 2), 0),
+            LineSegment::new(GridCell::new(5, 5), GridCell::new(5, 5), 0),
+        ];
+        for first in &segments {
+            for second in &segments {
+                let span_overlap = match (first.line_span(), second.line_span()) {
+                    (Some((first_line, first_span)), Some((second_line, second_span))) => {
+                        first_line == second_line
+                            && max(first_span.0, second_span.0) < min(first_span.1, second_span.1)
+                    }
+                    _ => false,
+                };
+                assert_eq!(span_overlap, first.overlaps(second), "{first:?} {second:?}");
+            }
+        }
+    }
 }
@@ -1,22 +1,33 @@
-use crate::grid_cell::GridCell;
-use crate::svg_output::line_segment::LineSegment;
+use crate::svg_output::line_segment::{Line, LineSegment};
+use itertools::Itertools;
+use std::collections::{BTreeMap, HashMap};
 use std::mem;
 
 #[derive(Debug, Clone)]
 pub struct LineSegmentTreeNode {
     line_segment: LineSegment,
+    // Where the node sits among its siblings, which is also the order they are drawn in.
+    placement: (usize, usize),
+    // The children in the order they were added.
     children: Vec<LineSegmentTreeNode>,
+    // Every child lies on this node's line, so one span map finds the child a segment overlaps.
+    child_spans: LineSpans,
 }
 
 impl LineSegmentTreeNode {
     pub fn new(line_segment: LineSegment) -> Self {
         Self {
             line_segment,
+            placement: (0, 0),
             children: vec![],
+            child_spans: LineSpans::default(),
         }
     }
+
     pub fn add_child(&mut self, child: LineSegment) {
-        Self::insert_segment(&mut self.children, child);
+        // Children are placed shortest first, and after any child of the same length.
+        let placement = (child.get_length(), self.children.len());
+        Self::insert_segment(&mut self.children, &mut self.child_spans, child, placement);
     }
 
     fn _prioritise_node_lengths(line_segment: LineSegment, parent_node: &mut LineSegmentTreeNode) {
@@ -30,28 +41,33 @@ impl LineSegmentTreeNode {
         }
     }
 
-    fn insert_segment(children: &mut Vec<LineSegmentTreeNode>, child: LineSegment) {
-        // If there is a containing node, add to as a child.
-        if let Some(parent_node) = children
-            .iter_mut()
-            .find(|node| node.line_segment.overlaps(&child))
-        {
-            Self::_prioritise_node_lengths(child, parent_node);
-        } else {
-            // There is no containing node, so we must find the best place to put the child.
-            // It should be in order of size,
-            // and we add to the series of same-sized elements at the end
-            // to avoid moving a lot of elements down.
-            let new_node = LineSegmentTreeNode::new(child);
-
-            match children.binary_search_by(|node| {
-                // For descending order, reverse the comparison.
-                node.line_segment
-                    .get_length()
-                    .cmp(&new_node.line_segment.get_length())
-            }) {
-                Ok(pos) | Err(pos) => children.insert(pos, new_node),
+    /// Add the segment under the first of the nodes that it overlaps,
+    /// or as a new node with the given placement.
+    fn insert_segment(
+        nodes: &mut Vec<LineSegmentTreeNode>,
+        spans: &mut LineSpans,
+        line_segment: LineSegment,
+        placement: (usize, usize),
+    ) {
+        let (_, span) = line_segment
+            .line_span()
+            .expect("Only segments with a length are grouped");
+
+        if let Some(index) = spans.first_overlapping(nodes, span) {
+            let parent_node = &mut nodes[index];
+            let old_span = LineSpans::span(parent_node);
+            Self::_prioritise_node_lengths(line_segment, parent_node);
+            let new_span = LineSpans::span(parent_node);
+            if new_span != old_span {
+                spans.remove(old_span, index);
+                spans.insert(new_span, index);
             }
+        } else {
+            spans.insert(span, nodes.len());
+            nodes.push(LineSegmentTreeNode {
+                placement,
+                ..LineSegmentTreeNode::new(line_segment)
+            });
         }
     }
 }
@@ -59,11 +75,16 @@ impl LineSegmentTreeNode {
 #[derive(Debug, Clone)]
 pub struct LineSegmentTree {
     pub root_nodes: Vec<LineSegmentTreeNode>,
+    // The root nodes on each line, so that only nearby roots are compared with a new segment.
+    lines: HashMap<Line, LineSpans>,
 }
 
 impl LineSegmentTree {
     pub fn new() -> Self {
-        Self { root_nodes: vec![] }
+        Self {
+            root_nodes: vec![],
+            lines: HashMap::new(),
+        }
     }
 
     pub fn depth_iter(&self) -> LineSegmentTreeIterator<'_> {
@@ -71,27 +92,75 @@ impl LineSegmentTree {
         LineSegmentTreeIterator { stack }
     }
 
+    /// Add the segment under the earliest root node that it overlaps, or as a new root node.
     pub fn add_child(&mut self, line_segment: LineSegment) {
-        if let Some(parent_node) = self
-            .root_nodes
-            .iter_mut()
-            .find(|node| node.line_segment.overlaps(&line_segment))
-        {
-            LineSegmentTreeNode::_prioritise_node_lengths(line_segment, parent_node);
-        } else {
-            let new_node = LineSegmentTreeNode::new(line_segment);
-            self.root_nodes.push(new_node);
+        // Root nodes are kept in the order they were added.
+        let placement = (0, self.root_nodes.len());
+        let Some((line, _)) = line_segment.line_span() else {
+            // A segment with no length can't overlap anything.
+            self.root_nodes.push(LineSegmentTreeNode {
+                placement,
+                ..LineSegmentTreeNode::new(line_segment)
+            });
+            return;
+        };
+        let roots = self.lines.entry(line).or_default();
+        LineSegmentTreeNode::insert_segment(&mut self.root_nodes, roots, line_segment, placement);
+    }
+}
+
+/// The nodes that lie on one line, keyed by where they start along it.
+#[derive(Debug, Clone, Default)]
+struct LineSpans {
+    by_start: BTreeMap<isize, Vec<usize>>,
+    // No node is longer than this, so an overlapping node can't start further back.
+    longest: isize,
+}
+
+impl LineSpans {
+    fn span(node: &LineSegmentTreeNode) -> (isize, isize) {
+        node.line_segment
+            .line_span()
+            .map(|(_, span)| span)
+            .expect("Nodes on a line have a length")
+    }
+
+    /// The index of the first node by placement that overlaps the span, to match a linear search.
+    fn first_overlapping(
+        &self,
+        nodes: &[LineSegmentTreeNode],
+        (start, end): (isize, isize),
+    ) -> Option<usize> {
+        self.by_start
+            .range(start - self.longest + 1..end)
+            .flat_map(|(_, indices)| indices)
+            .copied()
+            .filter(|&index| {
+                let (node_start, node_end) = Self::span(&nodes[index]);
+                node_start < end && start < node_end
+            })
+            .min_by_key(|&index| nodes[index].placement)
+    }
+
+    fn insert(&mut self, (start, end): (isize, isize), index: usize) {
+        self.by_start.entry(start).or_default().push(index);
+        self.longest = self.longest.max(end - start);
+    }
+
+    fn remove(&mut self, (start, _): (isize, isize), index: usize) {
+        if let Some(indices) = self.by_start.get_mut(&start) {
+            indices.retain(|&i| i != index);
+            if indices.is_empty() {
+                self.by_start.remove(&start);
+            }
         }
     }
 }
 
-pub fn group_lines(lines: Vec<(GridCell, GridCell)>) -> LineSegmentTree {
+/// Group overlapping line segments, keeping the order that each segment carries.
+pub fn group_lines(segments: impl IntoIterator<Item = LineSegment>) -> LineSegmentTree {
     let mut tree = LineSegmentTree::new();
-    for segment in lines
-        .into_iter()
-        .enumerate()
-        .map(|(i, (start, end))| LineSegment::new(start, end, i))
-    {
+    for segment in segments {
         tree.add_child(segment);
     }
     tree
@@ -106,9 +175,12 @@ impl<'a> Iterator for LineSegmentTreeIterator<'a> {
 
     fn next(&mut self) -> Option<Self::Item> {
         if let Some((node, depth)) = self.stack.pop() {
-            for child in node.children.iter().rev() {
-                self.stack.push((child, depth + 1));
-            }
+            let children = node
+                .children
+                .iter()
+                .sorted_by_key(|child| child.placement)
+                .rev();
+            self.stack.extend(children.map(|child| (child, depth + 1)));
             Some((&node.line_segment, depth))
         } else {
             None
@@ -119,6 +191,15 @@ impl<'a> Iterator for LineSegmentTreeIterator<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::grid_cell::GridCell;
+
+    fn _ordered_segments(lines: Vec<(GridCell, GridCell)>) -> Vec<LineSegment> {
+        lines
+            .into_iter()
+            .enumerate()
+            .map(|(i, (start, end))| LineSegment::new(start, end, i))
+            .collect()
+    }
 
     #[test]
     fn test_group_lines_by_segment_no_overlapping() {
@@ -126,7 +207,7 @@ mod tests {
             (GridCell::new(0, 0), GridCell::new(0, 1)),
             (GridCell::new(0, 0), GridCell::new(1, 0)),
         ];
-        let result = group_lines(segments);
+        let result = group_lines(_ordered_segments(segments));
         assert_eq!(result.root_nodes.len(), 2)
     }
 
@@ -137,7 +218,7 @@ mod tests {
             (GridCell::new(0, 0), GridCell::new(0, 2)),
             (GridCell::new(0, 0), GridCell::new(1, 0)),
         ];
-        let result = group_lines(segments);
+        let result = group_lines(_ordered_segments(segments));
         assert_eq!(result.root_nodes.len(), 2)
     }
 
@@ -151,7 +232,7 @@ mod tests {
             (GridCell::new(0, 0), GridCell::new(1, 0)),
             (GridCell::new(0, 0), GridCell::new(2, 0)),
         ];
-        let result = group_lines(segments);
+        let result = group_lines(_ordered_segments(segments));
         assert_eq!(result.root_nodes.len(), 2)
     }
 
@@ -164,7 +245,7 @@ mod tests {
             (GridCell::new(0, 2), GridCell::new(0, 3)),
             (GridCell::new(0, 0), GridCell::new(1, 0)),
         ];
-        let result = group_lines(segments);
+        let result = group_lines(_ordered_segments(segments));
         assert_eq!(result.root_nodes.len(), 2);
         assert_eq!(result.root_nodes[0].line_segment.get_length(), 3);
         assert_eq!(result.root_nodes[1].line_segment.get_length(), 1);
@@ -177,12 +258,12 @@ mod tests {
             (GridCell::new(0, 0), GridCell::new(0, 2)),
             (GridCell::new(0, 0), GridCell::new(0, 1)),
         ];
-        let result = group_lines(segments.clone());
+        let result = group_lines(_ordered_segments(segments.clone()));
         assert_eq!(result.root_nodes.len(), 1);
         assert_eq!(result.root_nodes[0].children.len(), 1);
         assert_eq!(
             result.root_nodes[0].children[0].line_segment,
-            segments[1].into()
+            LineSegment::new(segments[1].0, segments[1].1, 1)
         );
     }
 
@@ -192,7 +273,7 @@ mod tests {
             (GridCell::new(0, 0), GridCell::new(0, 1)),
             (GridCell::new(0, 0), GridCell::new(0, 2)),
         ];
-        let result = group_lines(segments.clone());
+        let result = group_lines(_ordered_segments(segments.clone()));
         assert_eq!(result.root_nodes.len(), 1);
         assert_eq!(result.root_nodes[0].children.len(), 1);
         assert_eq!(
@@ -200,4 +281,91 @@ mod tests {
             segments[0].into()
         );
     }
+
+    /// A node of the reference grouping, which compares a segment with every sibling in turn.
+    struct LinearNode {
+        segment: LineSegment,
+        // The length of the segment when the node was added, which places it among its siblings.
+        placed_length: usize,
+        children: Vec<LinearNode>,
+    }
+
+    fn _insert_linearly(nodes: &mut Vec<LinearNode>, segment: LineSegment, by_length: bool) {
+        if let Some(parent) = nodes
+            .iter_mut()
+            .find(|node| node.segment.overlaps(&segment))
+        {
+            let child = match parent.segment.get_length() >= segment.get_length() {
+                true => segment,
+                false => mem::replace(&mut parent.segment, segment),
+            };
+            _insert_linearly(&mut parent.children, child, true);
+        } else {
+            let node = LinearNode {
+                segment,
+                placed_length: segment.get_length(),
+                children: vec![],
+            };
+            // Children are kept shortest first, and root nodes in the order they were added.
+            let position = match by_length {
+                true => nodes.partition_point(|n| n.placed_length <= node.placed_length),
+                false => nodes.len(),
+            };
+            nodes.insert(position, node);
+        }
+    }
+
+    fn _flatten_linearly(node: &LinearNode, depth: usize, out: &mut Vec<(LineSegment, usize)>) {
+        out.push((node.segment, depth));
+        for child in &node.children {
+            _flatten_linearly(child, depth + 1, out);
+        }
+    }
+
+    /// Group the segments by comparing each one with every node, as a reference,
+    /// and list them in the same order as `depth_iter`.
+    fn _group_lines_linearly(segments: Vec<LineSegment>) -> Vec<(LineSegment, usize)> {
+        let mut roots = vec![];
+        for segment in segments {
+            _insert_linearly(&mut roots, segment, false);
+        }
+        let mut out = vec![];
+        for root in roots.iter().rev() {
+            _flatten_linearly(root, 0, &mut out);
+        }
+        out
+    }
+
+    /// Short travel lines scattered over a square, from a fixed seed.
+    fn _random_segments(count: usize, size: isize, max_step: isize) -> Vec<LineSegment> {
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = |limit: isize| {
+            seed = seed
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            ((seed >> 33) % limit as u64) as isize
+        };
+        (0..count)
+            .map(|i| {
+                let start = GridCell::new(next(size), next(size));
+                let end = GridCell::new(
+                    start.x + next(2 * max_step + 1) - max_step,
+                    start.y + next(2 * max_step + 1) - max_step,
+                );
+                LineSegment::new(start, end, i)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_group_lines_matches_linear_grouping() {
+        let segments = _random_segments(5000, 12, 3);
+        let expected = _group_lines_linearly(segments.clone());
+        let result = group_lines(segments)
+            .depth_iter()
+            .map(|(segment, depth)| (*segment, depth))
+            .collect::<Vec<_>>();
+        assert!(expected.iter().any(|(_, depth)| *depth > 1));
+        assert_eq!(result, expected);
+    }
 }
@@ -1,3 +1,7 @@
+pub mod html_export;
+mod label_placement;
 mod line_segment;
 mod line_segment_tree;
 pub mod svg_construction;
+pub mod svg_options;
+pub mod tiling;
@@ -1,80 +1,445 @@
+use crate::confetti::ConfettiReport;
 use crate::grid_cell::GridCell;
-use crate::stitch::HalfStitch;
+use crate::parking::{next_on_thread, parked_cost};
+use crate::stitch::{HalfStitch, RestartPolicy};
+use crate::svg_output::label_placement::{place_labels, LabelBox, Segment};
 use crate::svg_output::line_segment::LineSegment;
 use crate::svg_output::line_segment_tree::group_lines;
+use crate::svg_output::svg_options::{ArrowStyle, SvgOptions};
+use crate::theme::{svg_colour, Palette};
 use itertools::Itertools;
-use svg::node::element::{Circle, Definitions, Group, Line, Marker, Mask, Path, Text};
+use std::collections::{HashMap, HashSet};
+use svg::node::element::{Circle, Definitions, Group, Line, Marker, Mask, Path, Rectangle, Text};
 use svg::{Document, Node};
 
-const DOT_SPACING: f64 = 500.0;
-const DOT_RADIUS: f64 = DOT_SPACING / 10.0;
-const LINE_WIDTH: f64 = DOT_RADIUS / 5.0;
-const FONT_SIZE: isize = DOT_RADIUS as isize;
-
-pub fn create_graphic(stitches: &[HalfStitch]) -> Document {
+pub fn create_graphic(
+    stitches: &[HalfStitch],
+    colours: &HashMap<GridCell, usize>,
+    restart_policy: Option<RestartPolicy>,
+    palette: &Palette,
+    options: &SvgOptions,
+) -> Document {
+    // The threads are found before re-centring, as the colours are keyed by the original cells.
+    let next = next_on_thread(stitches, colours, restart_policy);
+    let mut summary = describe_sequence(stitches, colours, restart_policy);
     let centred_stitches = re_centre_stitches(stitches);
-    let (bottom_stitches, top_stitches): (Vec<HalfStitch>, Vec<HalfStitch>) = centred_stitches
-        .iter()
-        .partition(|s| s.stitch_corner == centred_stitches[0].stitch_corner);
+    let (thread_starts, thread_ends) = thread_ends(&centred_stitches, &next);
+    let confetti = match options.confetti {
+        true => {
+            let report = ConfettiReport::analyse(stitches, colours, restart_policy);
+            summary.push(report.to_string());
+            let offset = centring_offset(stitches);
+            report.cells().map(|cell| *cell - offset).collect()
+        }
+        false => vec![],
+    };
+    let diagram = Diagram {
+        travel: travel_segments(&centred_stitches, &next),
+        thread_starts,
+        thread_ends,
+        extent: furthest_hole(&centred_stitches),
+        summary,
+        confetti,
+        stitches: centred_stitches,
+    };
+    draw_diagram(&diagram, palette, options)
+}
 
-    let max_x = centred_stitches
-        .iter()
-        .flat_map(|s| [s.start.x, s.get_end_location().x])
-        .reduce(isize::max)
-        .unwrap();
-    let max_y = centred_stitches
-        .iter()
-        .flat_map(|s| [s.start.y, s.get_end_location().y])
-        .reduce(isize::max)
-        .unwrap();
+/// Everything drawn in one diagram, placed so that the bottom left hole is at the origin.
+pub(super) struct Diagram {
+    /// The stitches, numbered by the order they are drawn in.
+    pub stitches: Vec<HalfStitch>,
+
+    /// The travel drawn on the back, each numbered by the stitch it follows.
+    pub travel: Vec<LineSegment>,
+    pub thread_starts: Vec<GridCell>,
+    pub thread_ends: Vec<GridCell>,
 
-    let view_width = (max_x as f64) * DOT_SPACING + (2.0 * DOT_RADIUS);
-    let view_height = (max_y as f64) * DOT_SPACING + (2.0 * DOT_RADIUS);
+    /// The top right hole of the view.
+    pub extent: GridCell,
 
-    let mut document = Document::new().set("viewBox", (0, 0, view_width, view_height));
+    /// The lines written under the key, when there is a legend.
+    pub summary: Vec<String>,
+
+    /// The cells shaded as isolated cells and small islands.
+    pub confetti: Vec<GridCell>,
+}
+
+/// The width and height of the view that shows every hole up to the extent.
+pub(super) fn view_size(extent: GridCell, options: &SvgOptions) -> (f64, f64) {
+    (
+        (extent.x as f64) * options.dot_spacing + (2.0 * options.dot_radius),
+        (extent.y as f64) * options.dot_spacing + (2.0 * options.dot_radius),
+    )
+}
+
+/// Where the centre of a hole is drawn.
+pub(super) fn hole_position(point: GridCell, options: &SvgOptions, view_height: f64) -> (f64, f64) {
+    (
+        point.x as f64 * options.dot_spacing + options.dot_radius,
+        view_height - (point.y as f64 * options.dot_spacing + options.dot_radius),
+    )
+}
+
+pub(super) fn draw_diagram(diagram: &Diagram, palette: &Palette, options: &SvgOptions) -> Document {
+    let (bottom_stitches, top_stitches) = split_into_layers(&diagram.stitches);
+    let crossed_cells: HashSet<GridCell> = top_stitches.iter().map(|s| s.cell()).collect();
+    let (view_width, view_height) = view_size(diagram.extent, options);
+
+    // The legend sits underneath the diagram.
+    let legend = options
+        .legend
+        .then(|| draw_legend(&diagram.summary, palette, options, view_height));
+    let (total_width, total_height) = match &legend {
+        Some((_, width, height)) => (view_width.max(*width), view_height + height),
+        None => (view_width, view_height),
+    };
+
+    let mut document = Document::new().set("viewBox", (0, 0, total_width, total_height));
+    if let Some(cell_size_mm) = options.cell_size_mm {
+        let millimetres =
+            |length: f64| format!("{}mm", length / options.dot_spacing * cell_size_mm);
+        document = document
+            .set("width", millimetres(total_width))
+            .set("height", millimetres(total_height));
+    }
 
     let mut defs = Definitions::new();
-    defs = defs.add(create_arrow_marker("arrow-green", "green"));
-    defs = defs.add(create_arrow_marker("arrow-red", "red"));
-    defs = defs.add(create_arrow_marker("arrow-blue", "blue"));
-    defs = defs.add(create_intersection_mask(max_x, max_y));
+    let bottom_colour = svg_colour(palette.svg_bottom_stitch);
+    let top_colour = svg_colour(palette.svg_top_stitch);
+    let travel_colour = svg_colour(palette.svg_travel);
+    if options.arrow_style != ArrowStyle::None {
+        for (id, colour) in [
+            ("arrow-bottom", &bottom_colour),
+            ("arrow-top", &top_colour),
+            ("arrow-travel", &travel_colour),
+        ] {
+            defs = defs.add(create_arrow_marker(id, colour, options.arrow_style));
+        }
+    }
+    if options.intersection_mask {
+        defs = defs.add(create_intersection_mask(
+            &crossed_cells,
+            options,
+            view_height,
+        ));
+    }
     document = document.add(defs);
 
-    let dot_group = draw_grid(max_x, max_y, view_height);
-    let (mut bottom_stitches_group, bottom_stitch_text) =
-        draw_stitches(&bottom_stitches, "blue", view_height);
-    let (inter_stitch_group, inter_stitch_text) =
-        draw_inter_stitch_movement(&centred_stitches, view_height);
-    let (top_stitches_group, top_stitch_text) = draw_stitches(&top_stitches, "red", view_height);
+    let dot_group = draw_grid(
+        diagram.extent.x,
+        diagram.extent.y,
+        &svg_colour(palette.svg_dots),
+        options,
+        view_height,
+    );
+    let mut bottom_layer = draw_stitches(
+        &bottom_stitches,
+        &bottom_colour,
+        "arrow-bottom",
+        options,
+        view_height,
+    );
+    let travel_layer = if options.travel_lines {
+        draw_inter_stitch_movement(&diagram.travel, &travel_colour, options, view_height)
+    } else {
+        DrawnLayer::default()
+    };
+    let top_layer = draw_stitches(
+        &top_stitches,
+        &top_colour,
+        "arrow-top",
+        options,
+        view_height,
+    );
 
-    // Add the intersection mask for the bottom stitches.
-    for (_, line) in bottom_stitches_group.iter_mut() {
-        *line = line.clone().set("mask", "url(#intersection-mask)");
+    // Cut a gap in each bottom stitch where the top stitch crosses over it.
+    if options.intersection_mask {
+        for (stitch, (_, line)) in bottom_stitches.iter().zip(bottom_layer.lines.iter_mut()) {
+            if crossed_cells.contains(&stitch.cell()) {
+                *line = line.clone().set("mask", "url(#intersection-mask)");
+            }
+        }
     }
 
-    let all_lines = bottom_stitches_group
+    let layers = [bottom_layer, travel_layer, top_layer];
+    let all_lines = layers
         .iter()
-        .chain(inter_stitch_group.iter())
-        .chain(top_stitches_group.iter())
+        .flat_map(|layer| layer.lines.iter())
         .sorted_by_key(|l| l.0)
         .map(|l| l.1.clone())
         .collect_vec();
 
+    if !diagram.confetti.is_empty() {
+        document = document.add(draw_confetti(
+            &diagram.confetti,
+            &svg_colour(palette.svg_confetti),
+            options,
+            view_height,
+        ));
+    }
     document = document.add(dot_group);
 
     for l in all_lines {
         document.append(l);
     }
 
-    document = document.add(bottom_stitch_text);
-    document = document.add(inter_stitch_text);
-    document = document.add(top_stitch_text);
+    document = document.add(draw_thread_markers(
+        &diagram.thread_starts,
+        &diagram.thread_ends,
+        palette,
+        options,
+        view_height,
+    ));
+
+    if options.sequence_numbers {
+        let segments = layers
+            .iter()
+            .flat_map(|layer| layer.segments.iter().copied())
+            .collect_vec();
+        let labels = layers
+            .iter()
+            .flat_map(|layer| layer.labels.iter())
+            .collect_vec();
+        document = document.add(draw_sequence_numbers(
+            &labels,
+            &segments,
+            options,
+            (view_width, view_height),
+        ));
+    }
+
+    if let Some((legend_group, _, _)) = legend {
+        document = document.add(legend_group);
+    }
 
     document
 }
 
-fn create_intersection_mask(max_x: isize, max_y: isize) -> Mask {
-    // TODO: if there is only a bottom stitch, don't add the mask for that square.
+/// Shade the given cells, underneath the holes and stitches.
+fn draw_confetti(
+    cells: &[GridCell],
+    colour: &str,
+    options: &SvgOptions,
+    view_height: f64,
+) -> Group {
+    let mut group = Group::new()
+        .set("fill", colour)
+        .set("fill-opacity", 0.3)
+        .set("class", "confetti");
+    for cell in cells {
+        let (x, y) = hole_position(*cell + GridCell::new(0, 1), options, view_height);
+        group = group.add(
+            Rectangle::new()
+                .set("x", x)
+                .set("y", y)
+                .set("width", options.dot_spacing)
+                .set("height", options.dot_spacing),
+        );
+    }
+    group
+}
+
+/// Draw a key for the line styles and the summary, starting at the given height.
+/// Returns the group with its width and height.
+fn draw_legend(
+    summary: &[String],
+    palette: &Palette,
+    options: &SvgOptions,
+    top: f64,
+) -> (Group, f64, f64) {
+    let line_height = options.font_size * 1.5;
+    let sample_length = options.font_size * 3.0;
+    let left = options.dot_radius;
+    let label_x = left + sample_length + options.font_size;
+    let label = |text: String, row: usize| {
+        Text::new(text)
+            .set("x", label_x)
+            .set("y", top + line_height * (row as f64 + 1.0))
+            .set("font-size", options.font_size)
+            .set("font-family", options.font_family.as_str())
+            .set("fill", "black")
+    };
+    // The middle of the row, lined up with the middle of the text.
+    let row_middle = |row: usize| top + line_height * (row as f64 + 1.0) - options.font_size / 3.0;
+
+    let mut legend = Group::new().set("id", "legend");
+    let mut rows: Vec<String> = Vec::new();
+
+    let sample_line = |row: usize, colour: &str, marker_id: &str| {
+        let line = Line::new()
+            .set("x1", left)
+            .set("y1", row_middle(row))
+            .set("x2", left + sample_length)
+            .set("y2", row_middle(row))
+            .set("stroke-width", options.line_width)
+            .set("stroke", colour);
+        add_arrow(line, marker_id, options)
+    };
+    legend = legend.add(sample_line(
+        rows.len(),
+        &svg_colour(palette.svg_bottom_stitch),
+        "arrow-bottom",
+    ));
+    rows.push("Bottom leg, stitched first in each cell".to_string());
+    legend = legend.add(sample_line(
+        rows.len(),
+        &svg_colour(palette.svg_top_stitch),
+        "arrow-top",
+    ));
+    rows.push("Top leg, crossing over the bottom leg".to_string());
+    if options.travel_lines {
+        legend = legend.add(
+            sample_line(rows.len(), &svg_colour(palette.svg_travel), "arrow-travel")
+                .set("stroke-dasharray", "10,10"),
+        );
+        rows.push("Travel on the back between stitches".to_string());
+    }
+    for (colour, description) in [
+        (palette.thread_start, "Thread starts"),
+        (palette.thread_end, "Thread ends"),
+    ] {
+        legend = legend.add(
+            Circle::new()
+                .set("cx", left + sample_length / 2.0)
+                .set("cy", row_middle(rows.len()))
+                .set("r", options.font_size / 3.0)
+                .set("fill", "none")
+                .set("stroke-width", options.line_width)
+                .set("stroke", svg_colour(colour)),
+        );
+        rows.push(description.to_string());
+    }
+
+    // Leave a blank row between the key and the summary.
+    rows.push(String::new());
+    rows.extend(summary.iter().cloned());
+
+    let longest_row = rows
+        .iter()
+        .map(|row| row.chars().count())
+        .max()
+        .unwrap_or(0);
+    let row_count = rows.len();
+    for (row, text) in rows.into_iter().enumerate() {
+        if !text.is_empty() {
+            legend = legend.add(label(text, row));
+        }
+    }
+
+    // Estimate the width of the text, as monospace characters are about 0.6 of the font size wide.
+    let width = label_x + longest_row as f64 * options.font_size * 0.6 + options.dot_radius;
+    let height = line_height * (row_count as f64 + 1.0);
+    (legend, width, height)
+}
+
+/// Summarise the sequence: how many stitches, which corners they start from, and its cost.
+fn describe_sequence(
+    stitches: &[HalfStitch],
+    colours: &HashMap<GridCell, usize>,
+    restart_policy: Option<RestartPolicy>,
+) -> Vec<String> {
+    let (bottom_stitches, top_stitches) = split_into_layers(stitches);
+    let corner =
+        |layer: &[HalfStitch]| match layer.iter().map(|s| s.stitch_corner).dedup().at_most_one() {
+            Ok(Some(corner)) => corner.to_string(),
+            Ok(None) => "none".to_string(),
+            Err(_) => "mixed".to_string(),
+        };
+    let mut lines = vec![
+        format!(
+            "{} half stitch{} in {} cell{}",
+            stitches.len(),
+            if stitches.len() == 1 { "" } else { "es" },
+            bottom_stitches.len(),
+            if bottom_stitches.len() == 1 { "" } else { "s" },
+        ),
+        format!(
+            "Bottom legs start: {}, top legs start: {}",
+            corner(&bottom_stitches),
+            corner(&top_stitches)
+        ),
+    ];
+    match parked_cost(stitches, colours, restart_policy) {
+        Ok(cost) => lines.extend([
+            format!("Travel: {:.4}", cost.float),
+            format!("Exact travel: {}", cost.symbolic),
+            "Valid sequence".to_string(),
+        ]),
+        Err((first, second)) => lines.push(format!(
+            "Invalid sequence: stitches in ({}, {}) and ({}, {}) share a hole",
+            first.x, first.y, second.x, second.y
+        )),
+    }
+    lines
+}
+
+/// The holes where each thread starts, and where each thread ends,
+/// given the next stitch on the same thread as each stitch.
+fn thread_ends(stitches: &[HalfStitch], next: &[Option<usize>]) -> (Vec<GridCell>, Vec<GridCell>) {
+    let continued: HashSet<usize> = next.iter().flatten().copied().collect();
+    let starts = (0..stitches.len())
+        .filter(|i| !continued.contains(i))
+        .map(|i| stitches[i].start)
+        .collect();
+    let ends = (0..stitches.len())
+        .filter(|&i| next[i].is_none())
+        .map(|i| stitches[i].get_end_location())
+        .collect();
+    (starts, ends)
+}
+
+/// Draw rings around the holes where each thread starts and ends.
+fn draw_thread_markers(
+    thread_starts: &[GridCell],
+    thread_ends: &[GridCell],
+    palette: &Palette,
+    options: &SvgOptions,
+    view_height: f64,
+) -> Group {
+    let (start_colour, end_colour) = (
+        svg_colour(palette.thread_start),
+        svg_colour(palette.thread_end),
+    );
+    let mut marker_group = Group::new()
+        .set("fill", "none")
+        .set("stroke-width", options.line_width);
+    let markers = thread_starts
+        .iter()
+        .map(|point| (point, &start_colour))
+        .chain(thread_ends.iter().map(|point| (point, &end_colour)));
+    for (point, colour) in markers {
+        let (cx, cy) = hole_position(*point, options, view_height);
+        let circle = Circle::new()
+            .set("cx", cx)
+            .set("cy", cy)
+            .set("r", options.dot_radius * 1.5)
+            .set("stroke", colour.as_str());
+        marker_group = marker_group.add(circle);
+    }
+    marker_group
+}
+
+/// Split the stitches into the legs stitched first in their cell, and the legs that cross over them.
+fn split_into_layers(stitches: &[HalfStitch]) -> (Vec<HalfStitch>, Vec<HalfStitch>) {
+    let mut first_in_cell: HashMap<GridCell, usize> = HashMap::new();
+    for stitch in stitches {
+        first_in_cell
+            .entry(stitch.cell())
+            .and_modify(|order| *order = (*order).min(stitch.order))
+            .or_insert(stitch.order);
+    }
+    stitches
+        .iter()
+        .partition(|stitch| first_in_cell[&stitch.cell()] == stitch.order)
+}
+
+/// Make a mask with a gap at the centre of every cell where one leg crosses another.
+fn create_intersection_mask(
+    crossed_cells: &HashSet<GridCell>,
+    options: &SvgOptions,
+    view_height: f64,
+) -> Mask {
     let mut mask = Mask::new()
         .set("id", "intersection-mask")
         .set("x", "0")
@@ -90,34 +455,39 @@ fn create_intersection_mask(max_x: isize, max_y: isize) -> Mask {
         .set("fill", "white");
     mask.append(mask_colouring);
 
-    for col in 0..max_x {
-        for row in 0..max_y {
-            let mid_x: f64 = ((DOT_SPACING / 2.0) + DOT_SPACING * col as f64) + DOT_RADIUS;
-            let mid_y: f64 = ((DOT_SPACING / 2.0) + DOT_SPACING * row as f64) + DOT_RADIUS;
-            let cutout = Circle::new()
-                .set("cx", mid_x)
-                .set("cy", mid_y)
-                .set("r", DOT_RADIUS / 4.0)
-                .set("fill", "black");
-            mask.append(cutout);
-        }
+    let spacing = options.dot_spacing;
+    for cell in crossed_cells.iter().sorted_by_key(|cell| (cell.y, cell.x)) {
+        let mid_x = (cell.x as f64 + 0.5) * spacing + options.dot_radius;
+        let mid_y = view_height - ((cell.y as f64 + 0.5) * spacing + options.dot_radius);
+        let cutout = Circle::new()
+            .set("cx", mid_x)
+            .set("cy", mid_y)
+            .set("r", options.dot_radius / 4.0)
+            .set("fill", "black");
+        mask.append(cutout);
     }
 
     mask
 }
 
-fn draw_grid(max_x: isize, max_y: isize, view_height: f64) -> Group {
-    let mut dot_group = Group::new().set("fill", "black");
+fn draw_grid(
+    max_x: isize,
+    max_y: isize,
+    colour: &str,
+    options: &SvgOptions,
+    view_height: f64,
+) -> Group {
+    let mut dot_group = Group::new().set("fill", colour);
     for row in 0..=max_y {
         for col in 0..=max_x {
             // Offset by the radius of a dot so that the dot isn't cut off.
-            let cx = col as f64 * DOT_SPACING + DOT_RADIUS;
-            let cy = view_height - (row as f64 * DOT_SPACING + DOT_RADIUS);
+            let cx = col as f64 * options.dot_spacing + options.dot_radius;
+            let cy = view_height - (row as f64 * options.dot_spacing + options.dot_radius);
 
             let circle = Circle::new()
                 .set("cx", cx)
                 .set("cy", cy)
-                .set("r", DOT_RADIUS);
+                .set("r", options.dot_radius);
 
             dot_group = dot_group.add(circle);
         }
@@ -125,56 +495,124 @@ fn draw_grid(max_x: isize, max_y: isize, view_height: f64) -> Group {
     dot_group
 }
 
+/// The lines drawn for one layer of the diagram, along with their sequence numbers.
+#[derive(Default)]
+struct DrawnLayer {
+    /// Each line with its position in the sequence.
+    lines: Vec<(usize, Line)>,
+
+    /// Where each line is, so that labels can avoid it.
+    segments: Vec<Segment>,
+    labels: Vec<SequenceLabel>,
+}
+
+/// A sequence number, with where it would like to be drawn.
+struct SequenceLabel {
+    number: usize,
+    colour: String,
+    position: (f64, f64),
+}
+
 fn draw_stitches(
     stitches: &[HalfStitch],
     colour: &str,
+    marker_id: &str,
+    options: &SvgOptions,
     view_height: f64,
-) -> (Vec<(usize, Line)>, Group) {
-    let mut stitch_lines = Vec::with_capacity(stitches.len());
-    let mut text_group = Group::new().set("fill", colour).set("stroke", colour);
+) -> DrawnLayer {
+    let mut layer = DrawnLayer::default();
     for stitch in stitches {
-        let line = draw_line(
+        let (start, end) = (stitch.start, stitch.get_end_location());
+        let cell = stitch.cell();
+        let line = draw_line(options, view_height, start, end, (0.0, 0.0))
+            .set("fill", colour)
+            .set("stroke", colour)
+            .set("data-step", stitch.order)
+            .set(
+                "data-tooltip",
+                format!(
+                    "Step {}: half stitch in cell ({}, {}) from the {} corner",
+                    stitch.order,
+                    cell.x,
+                    cell.y,
+                    stitch.stitch_corner.to_string().to_lowercase()
+                ),
+            );
+
+        layer
+            .lines
+            .push((stitch.order, add_arrow(line, marker_id, options)));
+        layer.segments.push(line_coordinates(
+            options,
             view_height,
-            stitch.start,
-            stitch.get_end_location(),
+            start,
+            end,
             (0.0, 0.0),
-        )
-        .set("marker-end", format!("url(#arrow-{})", colour))
-        .set("fill", colour)
-        .set("stroke", colour);
-
-        stitch_lines.push((stitch.order, line));
-        text_group = text_group.add(add_sequence_number(
+        ));
+        layer.labels.push(sequence_label(
             stitch.order,
             colour,
-            stitch.start,
-            stitch.get_end_location(),
+            start,
+            end,
             (0.0, 0.0),
+            options,
             view_height,
         ));
     }
-    (stitch_lines, text_group)
+    layer
+}
+
+/// Where a line between two holes is drawn, moved by the given offset.
+fn line_coordinates(
+    options: &SvgOptions,
+    view_height: f64,
+    first_point: GridCell,
+    second_point: GridCell,
+    offset: (f64, f64),
+) -> Segment {
+    let (spacing, radius) = (options.dot_spacing, options.dot_radius);
+    let y_1 = view_height - (first_point.y as f64 * spacing + radius + offset.1);
+    let y_2 = view_height - (second_point.y as f64 * spacing + radius + offset.1);
+    let x1 = first_point.x as f64 * spacing + radius + offset.0;
+    let x2 = second_point.x as f64 * spacing + radius + offset.0;
+    ((x1, y_1), (x2, y_2))
 }
 
 fn draw_line(
+    options: &SvgOptions,
     view_height: f64,
     first_point: GridCell,
     second_point: GridCell,
     offset: (f64, f64),
 ) -> Line {
-    let y_1 = view_height - (first_point.y as f64 * DOT_SPACING + DOT_RADIUS + offset.1);
-    let y_2 = view_height - (second_point.y as f64 * DOT_SPACING + DOT_RADIUS + offset.1);
-    let x1 = first_point.x as f64 * DOT_SPACING + DOT_RADIUS + offset.0;
-    let x2 = second_point.x as f64 * DOT_SPACING + DOT_RADIUS + offset.0;
+    let ((x1, y_1), (x2, y_2)) =
+        line_coordinates(options, view_height, first_point, second_point, offset);
     Line::new()
         .set("x1", x1)
         .set("y1", y_1)
         .set("x2", x2)
         .set("y2", y_2)
-        .set("stroke-width", LINE_WIDTH)
+        .set("stroke-width", options.line_width)
+}
+
+/// Point the line with the given arrow marker, unless arrows are turned off.
+fn add_arrow(line: Line, marker_id: &str, options: &SvgOptions) -> Line {
+    match options.arrow_style {
+        ArrowStyle::None => line,
+        _ => line.set("marker-end", format!("url(#{})", marker_id)),
+    }
 }
 
-fn create_arrow_marker(id: &str, colour: &str) -> Marker {
+fn create_arrow_marker(id: &str, colour: &str, style: ArrowStyle) -> Marker {
+    let arrow_head = match style {
+        ArrowStyle::Open => Path::new()
+            .set("d", "M 0 0 L 6 3 L 0 6")
+            .set("fill", "none")
+            .set("stroke", colour),
+        _ => Path::new()
+            .set("d", "M 0 0 L 6 3 L 0 6 z")
+            .set("fill", colour),
+    };
     Marker::new()
         .set("id", id)
         .set("viewBox", "0 0 10 10")
@@ -183,114 +621,210 @@ fn create_arrow_marker(id: &str, colour: &str) -> Marker {
         .set("markerWidth", 6)
         .set("markerHeight", 6)
         .set("orient", "auto-start-reverse") // Automatically orient the arrowhead
-        .add(
-            Path::new()
-                .set("d", "M 0 0 L 6 3 L 0 6 z")
-                .set("fill", colour),
-        )
+        .add(arrow_head)
 }
 
-fn add_sequence_number(
+fn sequence_label(
     number: usize,
     colour: &str,
     first_point: GridCell,
     second_point: GridCell,
     text_offset: (f64, f64),
+    options: &SvgOptions,
     view_height: f64,
-) -> Text {
+) -> SequenceLabel {
     // First, find the direction that the text is supposed to go.
     // We want the text to be near the beginning of the stroke,
     // but in the direction the line is going.
-    let (x_pos, y_pos) = calculate_text_coordinates(first_point, second_point, view_height);
+    let (x_pos, y_pos) =
+        calculate_text_coordinates(first_point, second_point, options, view_height);
 
-    Text::new(format!("{}", number))
-        .set("x", x_pos + text_offset.0)
-        .set("y", y_pos + text_offset.1)
-        .set("color", "black")
-        .set("fill", colour)
-        .set("font-size", format!("{}", FONT_SIZE))
-        .set("font", "monospace")
-        .set("stroke", "black")
-        .set("stroke-width", LINE_WIDTH / 3.0)
-        .set("paint-order", "stroke")
+    SequenceLabel {
+        number,
+        colour: colour.to_string(),
+        position: (x_pos + text_offset.0, y_pos + text_offset.1),
+    }
+}
+
+/// Draw the sequence numbers, moving them apart so they don't cover each other or the lines.
+fn draw_sequence_numbers(
+    labels: &[&SequenceLabel],
+    segments: &[Segment],
+    options: &SvgOptions,
+    bounds: (f64, f64),
+) -> Group {
+    let boxes = labels
+        .iter()
+        .map(|label| {
+            let (x, y) = label.position;
+            LabelBox::for_text(&label.number.to_string(), x, y, options.font_size)
+        })
+        .collect_vec();
+    let placements = place_labels(&boxes, segments, bounds);
+
+    let mut text_group = Group::new().set("id", "sequence-numbers");
+    for (label, placement) in labels.iter().zip(placements) {
+        if let Some(((x1, y1), (x2, y2))) = placement.leader {
+            text_group = text_group.add(
+                Line::new()
+                    .set("x1", x1)
+                    .set("y1", y1)
+                    .set("x2", x2)
+                    .set("y2", y2)
+                    .set("stroke", label.colour.as_str())
+                    .set("stroke-width", options.line_width / 3.0)
+                    .set("data-step", label.number),
+            );
+        }
+        text_group = text_group.add(
+            Text::new(format!("{}", label.number))
+                .set("x", placement.x)
+                .set("y", placement.y)
+                .set("color", "black")
+                .set("fill", label.colour.as_str())
+                .set("font-size", format!("{}", options.font_size))
+                .set("font-family", options.font_family.as_str())
+                .set("stroke", "black")
+                .set("stroke-width", options.line_width / 3.0)
+                .set("paint-order", "stroke")
+                .set("data-step", label.number),
+        );
+    }
+    text_group
 }
 
 fn calculate_text_coordinates(
     first_point: GridCell,
     second_point: GridCell,
+    options: &SvgOptions,
     view_height: f64,
 ) -> (f64, f64) {
     let horizontal_direction = second_point.x - first_point.x;
     let vertical_direction = second_point.y - first_point.y;
-    let x_pos = (first_point.x as f64 + (0.1 * horizontal_direction as f64)) * DOT_SPACING
-        + DOT_RADIUS
+    let x_pos = (first_point.x as f64 + (0.1 * horizontal_direction as f64)) * options.dot_spacing
+        + options.dot_radius
         // Add offset to compensate for the text being drawn from the top left.
         // This helps avoid intersections between the text and the lines.
         + if horizontal_direction > 0 {
-            FONT_SIZE as f64
+            options.font_size
         } else {
             5.0
         };
 
     let unadjusted_y_pos = (first_point.y as f64 + (0.1 * (second_point.y - first_point.y) as f64))
-        * DOT_SPACING
-        + (DOT_RADIUS * vertical_direction as f64);
+        * options.dot_spacing
+        + (options.dot_radius * vertical_direction as f64);
     let y_pos = view_height - unadjusted_y_pos;
     (x_pos, y_pos)
 }
 
+/// The travel on the back between each stitch and the next one on the same thread,
+/// numbered by the stitch just before the one it leads to, so it is drawn when it happens.
+/// Travel that is replaced by a restart has no next stitch, so is left out.
+fn travel_segments(stitches: &[HalfStitch], next: &[Option<usize>]) -> Vec<LineSegment> {
+    next.iter()
+        .enumerate()
+        .filter_map(|(i, next)| {
+            next.map(|j| LineSegment::new(stitches[i].get_end_location(), stitches[j].start, j - 1))
+        })
+        .collect()
+}
+
 /// Draw the lines that show where the thread travels on the back of the fabric.
 fn draw_inter_stitch_movement(
-    stitches: &[HalfStitch],
+    travel: &[LineSegment],
+    colour: &str,
+    options: &SvgOptions,
     view_height: f64,
-) -> (Vec<(usize, Line)>, Group) {
-    let lines: Vec<(GridCell, GridCell)> = stitches
-        .windows(2)
-        .map(|w| (w[0].get_end_location(), w[1].start))
-        .collect();
-    let overlapping_lines = group_lines(lines.clone());
+) -> DrawnLayer {
+    let overlapping_lines = group_lines(travel.iter().copied());
 
-    let mut inter_stitch_movements = Vec::with_capacity(stitches.len());
-    let mut text_group = Group::new().set("fill", "green").set("stroke", "green");
+    let mut layer = DrawnLayer::default();
 
     for (line, number) in overlapping_lines.depth_iter() {
-        // Alternate the offset between positive and negative
-        // so that the centre line is the first.
-        let (start, end) = LineSegment::into(*line);
-        let offset: f64 = FONT_SIZE as f64 * (-1.0 * number as f64);
-        let line_offset = if start.x == end.x {
-            (offset, 0.0)
-        } else {
-            (0.0, offset)
-        };
-        let text_offset = if start.x == end.x {
-            (offset, FONT_SIZE as f64 * number as f64)
-        } else {
-            (FONT_SIZE as f64 * number as f64, offset)
-        };
-        let path = draw_line(view_height, start, end, line_offset)
-            .set("stroke-dasharray", "10,10")
-            .set("marker-end", format!("url(#arrow-{})", "green"))
-            .set("fill", "green")
-            .set("stroke", "green");
+        // Move each overlapping line further out, perpendicular to the line,
+        // so that the longest line of a group stays where it is.
+        let (start, end): (GridCell, GridCell) = (*line).into();
+        let offset: f64 = options.font_size * -(number as f64);
+        let (normal_x, normal_y) = line.normal();
+        let line_offset = (normal_x * offset, normal_y * offset);
+        // The labels are also moved along the line, so that they don't stack up.
+        // Text positions are in view coordinates, where y points downwards.
+        let (along_x, along_y) = line.direction();
+        let shift = options.font_size * number as f64;
+        let text_offset = (
+            line_offset.0 + along_x.abs() * shift,
+            -line_offset.1 + along_y.abs() * shift,
+        );
         let line_order = 2 + line.order * 2;
+        let path = draw_line(options, view_height, start, end, line_offset)
+            .set("stroke-dasharray", "10,10")
+            .set("fill", colour)
+            .set("stroke", colour)
+            .set("data-step", line_order)
+            .set(
+                "data-tooltip",
+                format!(
+                    "Step {}: travel from ({}, {}) to ({}, {}), {:.2} cells",
+                    line_order,
+                    start.x,
+                    start.y,
+                    end.x,
+                    end.y,
+                    start.euclidean_distance(&end)
+                ),
+            );
 
-        inter_stitch_movements.push((line_order, path));
-        text_group = text_group.add(add_sequence_number(
+        layer
+            .lines
+            .push((line_order, add_arrow(path, "arrow-travel", options)));
+        layer.segments.push(line_coordinates(
+            options,
+            view_height,
+            start,
+            end,
+            line_offset,
+        ));
+        layer.labels.push(sequence_label(
             line_order,
-            "green",
+            colour,
             start,
             end,
             text_offset,
+            options,
             view_height,
         ));
     }
 
-    (inter_stitch_movements, text_group)
+    layer
+}
+
+/// The top right hole that any stitch uses.
+pub(super) fn furthest_hole(stitches: &[HalfStitch]) -> GridCell {
+    let holes = stitches
+        .iter()
+        .flat_map(|s| [s.start, s.get_end_location()])
+        .collect_vec();
+    GridCell::new(
+        holes.iter().map(|hole| hole.x).max().unwrap(),
+        holes.iter().map(|hole| hole.y).max().unwrap(),
+    )
 }
 
 /// Move the stitches so that the bottommost and leftmost ones are at the origin.
-fn re_centre_stitches(stitches: &[HalfStitch]) -> Vec<HalfStitch> {
+pub(super) fn re_centre_stitches(stitches: &[HalfStitch]) -> Vec<HalfStitch> {
+    let offset = centring_offset(stitches);
+    stitches
+        .iter()
+        .map(|stitch| HalfStitch {
+            start: stitch.start - offset,
+            ..*stitch
+        })
+        .collect()
+}
+
+/// How far the stitches are moved by `re_centre_stitches`, which is the bottom left hole they use.
+pub(super) fn centring_offset(stitches: &[HalfStitch]) -> GridCell {
     let leftmost_x = stitches
         .iter()
         .map(|s| s.start.x)
@@ -301,22 +835,15 @@ fn re_centre_stitches(stitches: &[HalfStitch]) -> Vec<HalfStitch> {
         .map(|s| s.start.y)
         .reduce(isize::min)
         .unwrap();
-    stitches
-        .iter()
-        .map(|stitch| HalfStitch {
-            start: GridCell {
-                x: stitch.start.x - leftmost_x,
-                y: stitch.start.y - bottom_y,
-            },
-            ..*stitch
-        })
-        .collect()
+    GridCell::new(leftmost_x, bottom_y)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::stitch::StartingStitchCorner;
+    use crate::svg_output::svg_options::{DOT_RADIUS, DOT_SPACING};
+    use crate::theme::ColourTheme;
 
     const SINGLE_ROW_VIEW_HEIGHT: f64 = DOT_SPACING + 2.0 * DOT_RADIUS;
 
@@ -457,7 +984,13 @@ mod tests {
                 order: 2,
             },
         ];
-        let document = create_graphic(&test_stitches);
+        let document = create_graphic(
+            &test_stitches,
+            &HashMap::new(),
+            None,
+            &ColourTheme::default().palette(),
+            &SvgOptions::default(),
+        );
         svg::save("stitches.svg", &document).unwrap()
     }
 
@@ -495,7 +1028,13 @@ mod tests {
                 order: 11,
             },
         ];
-        let document = create_graphic(&test_stitches);
+        let document = create_graphic(
+            &test_stitches,
+            &HashMap::new(),
+            None,
+            &ColourTheme::default().palette(),
+            &SvgOptions::default(),
+        );
         svg::save("stitches.svg", &document).unwrap()
     }
 
@@ -533,7 +1072,13 @@ mod tests {
                 order: 11,
             },
         ];
-        let document = create_graphic(&test_stitches);
+        let document = create_graphic(
+            &test_stitches,
+            &HashMap::new(),
+            None,
+            &ColourTheme::default().palette(),
+            &SvgOptions::default(),
+        );
         svg::save("stitches.svg", &document).unwrap()
     }
 
@@ -571,10 +1116,143 @@ mod tests {
                 order: 11,
             },
         ];
-        let document = create_graphic(&test_stitches);
+        let document = create_graphic(
+            &test_stitches,
+            &HashMap::new(),
+            None,
+            &ColourTheme::default().palette(),
+            &SvgOptions::default(),
+        );
         svg::save("stitches.svg", &document).unwrap()
     }
 
+    #[test]
+    fn test_make_svg_and_write_restarted_thread() {
+        let test_stitches = vec![
+            HalfStitch {
+                start: GridCell::new(0, 0),
+                stitch_corner: StartingStitchCorner::BottomLeft,
+                order: 1,
+            },
+            HalfStitch {
+                start: GridCell::new(10, 0),
+                stitch_corner: StartingStitchCorner::BottomLeft,
+                order: 3,
+            },
+        ];
+        let document = create_graphic(
+            &test_stitches,
+            &HashMap::new(),
+            Some(RestartPolicy::default()),
+            &ColourTheme::default().palette(),
+            &SvgOptions::default(),
+        );
+        svg::save("stitches.svg", &document).unwrap()
+    }
+
+    #[test]
+    fn test_overlapping_diagonal_travel_is_offset() {
+        let stitches = vec![
+            HalfStitch::new_in_cell(GridCell::new(0, 0), StartingStitchCorner::BottomLeft, 1),
+            HalfStitch::new_in_cell(GridCell::new(3, 3), StartingStitchCorner::BottomLeft, 3),
+            HalfStitch::new_in_cell(GridCell::new(1, 1), StartingStitchCorner::TopRight, 5),
+        ];
+        let options = SvgOptions::default();
+        let layer = draw_inter_stitch_movement(
+            &travel_segments(&stitches, &next_on_thread(&stitches, &HashMap::new(), None)),
+            "green",
+            &options,
+            2000.0,
+        );
+
+        let [((x1, y1), (x2, y2)), ((x3, y3), (x4, y4))] = layer.segments[..] else {
+            panic!("expected two travel lines, got {:?}", layer.segments);
+        };
+        let direction = (x2 - x1, y2 - y1);
+        let cross = |x: f64, y: f64| direction.0 * (y - y1) - direction.1 * (x - x1);
+        // The lines stay parallel, but are moved off each other perpendicular to the line.
+        assert!((direction.0 * (y4 - y3) - direction.1 * (x4 - x3)).abs() < 1e-6);
+        assert!(cross(x3, y3).abs() > 1e-6);
+        let distance = cross(x3, y3).abs() / direction.0.hypot(direction.1);
+        assert!((distance - options.font_size).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_thread_markers_one_pair_per_run() {
+        let test_stitches = vec![
+            HalfStitch {
+                start: GridCell::new(0, 0),
+                stitch_corner: StartingStitchCorner::BottomLeft,
+                order: 1,
+            },
+            HalfStitch {
+                start: GridCell::new(1, 0),
+                stitch_corner: StartingStitchCorner::BottomLeft,
+                order: 3,
+            },
+            HalfStitch {
+                start: GridCell::new(10, 0),
+                stitch_corner: StartingStitchCorner::BottomLeft,
+                order: 5,
+            },
+        ];
+        let palette = ColourTheme::default().palette();
+        let (starts, ends) = thread_ends(
+            &test_stitches,
+            &next_on_thread(&test_stitches, &HashMap::new(), None),
+        );
+        let single_thread = draw_thread_markers(
+            &starts,
+            &ends,
+            &palette,
+            &SvgOptions::default(),
+            SINGLE_ROW_VIEW_HEIGHT,
+        );
+        assert_eq!(single_thread.to_string().matches("<circle").count(), 2);
+        let (starts, ends) = thread_ends(
+            &test_stitches,
+            &next_on_thread(
+                &test_stitches,
+                &HashMap::new(),
+                Some(RestartPolicy::default()),
+            ),
+        );
+        let restarted = draw_thread_markers(
+            &starts,
+            &ends,
+            &palette,
+            &SvgOptions::default(),
+            SINGLE_ROW_VIEW_HEIGHT,
+        );
+        assert_eq!(restarted.to_string().matches("<circle").count(), 4);
+    }
+
+    #[test]
+    fn test_travel_follows_each_colour() {
+        // A stitch in another colour between two in the first colour.
+        let stitches = [(0, 0), (5, 0), (1, 0)]
+            .iter()
+            .enumerate()
+            .map(|(i, &(x, y))| {
+                HalfStitch::new_in_cell(
+                    GridCell::new(x, y),
+                    StartingStitchCorner::BottomLeft,
+                    2 * i + 1,
+                )
+            })
+            .collect_vec();
+        let colours = HashMap::from([(GridCell::new(5, 0), 2)]);
+        let next = next_on_thread(&stitches, &colours, None);
+        let travel = travel_segments(&stitches, &next);
+        assert_eq!(travel.len(), 1);
+        let (start, end): (GridCell, GridCell) = travel[0].into();
+        assert_eq!((start, end), (GridCell::new(1, 1), GridCell::new(1, 0)));
+        // It is drawn after the other colour's stitch, just before the stitch it leads to.
+        assert_eq!(travel[0].order, 1);
+        let (starts, ends) = thread_ends(&stitches, &next);
+        assert_eq!((starts.len(), ends.len()), (2, 2));
+    }
+
     #[test]
     fn test_calculate_text_position_stitch_bottom_left_to_top_right() {
         let test_stitch = HalfStitch {
@@ -585,6 +1263,7 @@ mod tests {
         let result = calculate_text_coordinates(
             test_stitch.start,
             test_stitch.get_end_location(),
+            &SvgOptions::default(),
             SINGLE_ROW_VIEW_HEIGHT,
         );
         let expected_x = 0.1 * DOT_SPACING + 50.0 + DOT_RADIUS;
@@ -598,6 +1277,7 @@ mod tests {
         let result = calculate_text_coordinates(
             GridCell::new(0, 1),
             GridCell::new(0, 0),
+            &SvgOptions::default(),
             SINGLE_ROW_VIEW_HEIGHT,
         );
         let expected_x = DOT_RADIUS + 5.0;
@@ -611,6 +1291,7 @@ mod tests {
         let result = calculate_text_coordinates(
             GridCell::new(0, 0),
             GridCell::new(0, 1),
+            &SvgOptions::default(),
             SINGLE_ROW_VIEW_HEIGHT,
         );
         let expected_x = DOT_RADIUS + 5.0;
@@ -618,4 +1299,320 @@ mod tests {
         assert_eq!(result.0, expected_x);
         assert_eq!(result.1, expected_y);
     }
+
+    #[test]
+    fn test_svg_uses_theme_colours() {
+        let test_stitches = vec![HalfStitch {
+            start: GridCell::new(0, 0),
+            stitch_corner: StartingStitchCorner::BottomLeft,
+            order: 1,
+        }];
+        let palette = ColourTheme::ColourBlindSafe.palette();
+        let document = create_graphic(
+            &test_stitches,
+            &HashMap::new(),
+            None,
+            &palette,
+            &SvgOptions::default(),
+        )
+        .to_string();
+        assert!(document.contains(&svg_colour(palette.svg_bottom_stitch)));
+        assert!(!document.contains("\"blue\""));
+    }
+
+    fn _single_cross() -> Vec<HalfStitch> {
+        HalfStitch::convert_grid_cells(
+            [GridCell::new(0, 0), GridCell::new(0, 0)].iter(),
+            StartingStitchCorner::BottomLeft,
+            StartingStitchCorner::BottomRight,
+        )
+    }
+
+    #[test]
+    fn test_svg_options_turn_off_extras() {
+        let options = SvgOptions {
+            arrow_style: ArrowStyle::None,
+            intersection_mask: false,
+            sequence_numbers: false,
+            travel_lines: false,
+            legend: false,
+            ..SvgOptions::default()
+        };
+        let palette = ColourTheme::default().palette();
+        let document =
+            create_graphic(&_single_cross(), &HashMap::new(), None, &palette, &options).to_string();
+        assert!(!document.contains("<marker"));
+        assert!(!document.contains("<mask"));
+        assert!(!document.contains("<text"));
+        assert!(!document.contains("stroke-dasharray"));
+    }
+
+    #[test]
+    fn test_svg_physical_size() {
+        let options = SvgOptions {
+            dot_spacing: 100.0,
+            dot_radius: 10.0,
+            cell_size_mm: Some(2.0),
+            legend: false,
+            ..SvgOptions::default()
+        };
+        let palette = ColourTheme::default().palette();
+        let document =
+            create_graphic(&_single_cross(), &HashMap::new(), None, &palette, &options).to_string();
+        // One cell plus a dot radius either side.
+        assert!(document.contains("width=\"2.4mm\""));
+        assert!(document.contains("height=\"2.4mm\""));
+    }
+
+    #[test]
+    fn test_svg_open_arrows() {
+        let options = SvgOptions {
+            arrow_style: ArrowStyle::Open,
+            ..SvgOptions::default()
+        };
+        let palette = ColourTheme::default().palette();
+        let document =
+            create_graphic(&_single_cross(), &HashMap::new(), None, &palette, &options).to_string();
+        assert!(document.contains("d=\"M 0 0 L 6 3 L 0 6\""));
+    }
+
+    #[test]
+    fn test_split_into_layers_by_order_in_cell() {
+        // The second cell is crossed with the corners the other way round.
+        let stitches = vec![
+            HalfStitch::new_in_cell(GridCell::new(0, 0), StartingStitchCorner::BottomLeft, 1),
+            HalfStitch::new_in_cell(GridCell::new(0, 0), StartingStitchCorner::BottomRight, 3),
+            HalfStitch::new_in_cell(GridCell::new(1, 0), StartingStitchCorner::BottomRight, 5),
+            HalfStitch::new_in_cell(GridCell::new(1, 0), StartingStitchCorner::BottomLeft, 7),
+            HalfStitch::new_in_cell(GridCell::new(2, 0), StartingStitchCorner::BottomRight, 9),
+        ];
+        let (bottom, top) = split_into_layers(&stitches);
+        let orders = |layer: &[HalfStitch]| layer.iter().map(|s| s.order).collect_vec();
+        assert_eq!(orders(&bottom), vec![1, 5, 9]);
+        assert_eq!(orders(&top), vec![3, 7]);
+    }
+
+    #[test]
+    fn test_intersection_mask_only_for_crossed_cells() {
+        let crossed_cells = HashSet::from([GridCell::new(1, 0)]);
+        let mask = create_intersection_mask(
+            &crossed_cells,
+            &SvgOptions::default(),
+            SINGLE_ROW_VIEW_HEIGHT,
+        )
+        .to_string();
+        assert_eq!(mask.matches("<circle").count(), 1);
+        assert!(mask.contains(&format!("cx=\"{}\"", 1.5 * DOT_SPACING + DOT_RADIUS)));
+        assert!(mask.contains(&format!("cy=\"{}\"", DOT_SPACING / 2.0 + DOT_RADIUS)));
+    }
+
+    #[test]
+    fn test_mask_not_applied_to_half_stitches() {
+        let mut stitches = _single_cross();
+        stitches.push(HalfStitch::new_in_cell(
+            GridCell::new(1, 0),
+            StartingStitchCorner::BottomLeft,
+            5,
+        ));
+        let palette = ColourTheme::default().palette();
+        let document = create_graphic(
+            &stitches,
+            &HashMap::new(),
+            None,
+            &palette,
+            &SvgOptions::default(),
+        )
+        .to_string();
+        assert_eq!(
+            document.matches("mask=\"url(#intersection-mask)\"").count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_describe_sequence() {
+        let lines = describe_sequence(&_single_cross(), &HashMap::new(), None);
+        assert_eq!(
+            lines,
+            vec![
+                "2 half stitches in 1 cell",
+                "Bottom legs start: Bottom Left, top legs start: Bottom Right",
+                "Travel: 1.0000",
+                "Exact travel: 1",
+                "Valid sequence",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_describe_invalid_sequence() {
+        let stitches = vec![
+            HalfStitch::new_in_cell(GridCell::new(0, 0), StartingStitchCorner::BottomLeft, 1),
+            HalfStitch::new_in_cell(GridCell::new(1, 1), StartingStitchCorner::BottomLeft, 3),
+        ];
+        let lines = describe_sequence(&stitches, &HashMap::new(), None);
+        assert_eq!(
+            lines.last().unwrap(),
+            "Invalid sequence: stitches in (0, 0) and (1, 1) share a hole"
+        );
+    }
+
+    #[test]
+    fn test_legend_extends_view_box() {
+        let palette = ColourTheme::default().palette();
+        let document = create_graphic(
+            &_single_cross(),
+            &HashMap::new(),
+            None,
+            &palette,
+            &SvgOptions::default(),
+        )
+        .to_string();
+        assert!(!document.contains("id=\"legend\""));
+        assert!(document.contains("viewBox=\"0 0 600 600\""));
+
+        let with_legend = SvgOptions {
+            legend: true,
+            ..SvgOptions::default()
+        };
+        let document = create_graphic(
+            &_single_cross(),
+            &HashMap::new(),
+            None,
+            &palette,
+            &with_legend,
+        )
+        .to_string();
+        assert!(document.contains("id=\"legend\""));
+        assert!(document.contains("Valid sequence"));
+        assert!(!document.contains("viewBox=\"0 0 600 600\""));
+    }
+
+    /// Place the sequence numbers for the stitches, and check that none of them overlap
+    /// each other or any line.
+    fn _assert_labels_are_clear(cells: &[GridCell]) {
+        let options = SvgOptions::default();
+        let stitches = re_centre_stitches(&HalfStitch::convert_grid_cells(
+            cells.iter(),
+            StartingStitchCorner::BottomLeft,
+            StartingStitchCorner::BottomRight,
+        ));
+        let max_x = stitches.iter().map(|s| s.cell().x + 1).max().unwrap();
+        let max_y = stitches.iter().map(|s| s.cell().y + 1).max().unwrap();
+        let view_width = max_x as f64 * options.dot_spacing + 2.0 * options.dot_radius;
+        let view_height = max_y as f64 * options.dot_spacing + 2.0 * options.dot_radius;
+
+        let (bottom, top) = split_into_layers(&stitches);
+        let layers = [
+            draw_stitches(&bottom, "blue", "arrow-bottom", &options, view_height),
+            draw_inter_stitch_movement(
+                &travel_segments(&stitches, &next_on_thread(&stitches, &HashMap::new(), None)),
+                "green",
+                &options,
+                view_height,
+            ),
+            draw_stitches(&top, "red", "arrow-top", &options, view_height),
+        ];
+        let segments = layers
+            .iter()
+            .flat_map(|layer| layer.segments.iter().copied())
+            .collect_vec();
+        let boxes = layers
+            .iter()
+            .flat_map(|layer| layer.labels.iter())
+            .map(|label| {
+                let (x, y) = label.position;
+                LabelBox::for_text(&label.number.to_string(), x, y, options.font_size)
+            })
+            .collect_vec();
+        let placed = place_labels(&boxes, &segments, (view_width, view_height))
+            .iter()
+            .zip(&boxes)
+            .map(|(placement, label_box)| LabelBox {
+                x: placement.x,
+                y: placement.y,
+                ..*label_box
+            })
+            .collect_vec();
+
+        assert_eq!(placed.len(), stitches.len() * 2 - 1);
+        for (i, label_box) in placed.iter().enumerate() {
+            assert!(
+                !segments.iter().any(|segment| label_box.intersects(segment)),
+                "label {} is on a line",
+                i
+            );
+            assert!(
+                !placed[i + 1..]
+                    .iter()
+                    .any(|other| label_box.overlaps(other)),
+                "label {} overlaps another label",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn test_labels_clear_on_dense_three_by_three() {
+        let cells = (0..3)
+            .flat_map(|y| (0..3).flat_map(move |x| [GridCell::new(x, y); 2]))
+            .collect_vec();
+        _assert_labels_are_clear(&cells);
+    }
+
+    #[test]
+    fn test_labels_clear_on_single_row() {
+        let cells = (0..6)
+            .map(|x| GridCell::new(x, 0))
+            .chain((0..6).rev().map(|x| GridCell::new(x, 0)))
+            .collect_vec();
+        _assert_labels_are_clear(&cells);
+    }
+
+    #[test]
+    fn test_confetti_is_shaded() {
+        let cells = [
+            GridCell::new(0, 0),
+            GridCell::new(1, 0),
+            GridCell::new(2, 0),
+            GridCell::new(1, 1),
+            GridCell::new(2, 1),
+            GridCell::new(9, 0),
+        ];
+        let stitches = HalfStitch::convert_grid_cells(
+            cells.iter(),
+            StartingStitchCorner::BottomLeft,
+            StartingStitchCorner::BottomRight,
+        );
+        let palette = ColourTheme::default().palette();
+        let plain = create_graphic(
+            &stitches,
+            &HashMap::new(),
+            None,
+            &palette,
+            &SvgOptions::default(),
+        )
+        .to_string();
+        assert!(!plain.contains("class=\"confetti\""));
+
+        let options = SvgOptions {
+            confetti: true,
+            legend: true,
+            ..SvgOptions::default()
+        };
+        let document =
+            create_graphic(&stitches, &HashMap::new(), None, &palette, &options).to_string();
+        let confetti = document.split("class=\"confetti\"").nth(1).unwrap();
+        let confetti = &confetti[..confetti.find("</g>").unwrap()];
+        // The block of five cells is too big to be confetti, so only the single cell is shaded.
+        assert_eq!(confetti.matches("<rect").count(), 1);
+        let (x, y) = hole_position(
+            GridCell::new(9, 1),
+            &options,
+            view_size(GridCell::new(10, 2), &options).1,
+        );
+        assert!(confetti.contains(&format!("x=\"{}\"", x)));
+        assert!(confetti.contains(&format!("y=\"{}\"", y)));
+        assert!(document.contains("Confetti: 1 isolated cell, 0 small islands"));
+    }
 }
@@ -0,0 +1,83 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fmt::Formatter;
+
+pub const DOT_SPACING: f64 = 500.0;
+pub const DOT_RADIUS: f64 = DOT_SPACING / 10.0;
+const LINE_WIDTH: f64 = DOT_RADIUS / 5.0;
+const FONT_SIZE: f64 = DOT_RADIUS;
+pub const DEFAULT_TILE_SIZE: usize = 10;
+
+/// The shape drawn at the end of each line to show its direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub enum ArrowStyle {
+    #[default]
+    Filled,
+    Open,
+    None,
+}
+
+impl ArrowStyle {
+    pub const ALL: [ArrowStyle; 3] = [ArrowStyle::Filled, ArrowStyle::Open, ArrowStyle::None];
+}
+
+impl fmt::Display for ArrowStyle {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ArrowStyle::Filled => "Filled",
+            ArrowStyle::Open => "Open",
+            ArrowStyle::None => "None",
+        })
+    }
+}
+
+/// How the exported SVG is drawn.
+/// Lengths are in the units of the SVG view box, apart from the physical size of a cell.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SvgOptions {
+    /// Distance between neighbouring holes.
+    pub dot_spacing: f64,
+    pub dot_radius: f64,
+    pub line_width: f64,
+    pub font_family: String,
+    pub font_size: f64,
+    pub arrow_style: ArrowStyle,
+
+    /// Whether bottom stitches are cut away where the top stitch crosses them.
+    pub intersection_mask: bool,
+    pub sequence_numbers: bool,
+    pub travel_lines: bool,
+
+    /// Whether to add a key for the line styles and a summary of the sequence under the diagram.
+    pub legend: bool,
+
+    /// The printed size of a cell in millimetres, or `None` to leave the size to the viewer.
+    pub cell_size_mm: Option<f64>,
+
+    /// The width and height in cells of each tile, or `None` to draw the design in one document.
+    pub tile_size: Option<usize>,
+
+    /// Whether isolated cells and small islands are shaded.
+    pub confetti: bool,
+}
+
+impl Default for SvgOptions {
+    fn default() -> Self {
+        Self {
+            dot_spacing: DOT_SPACING,
+            dot_radius: DOT_RADIUS,
+            line_width: LINE_WIDTH,
+            font_family: "monospace".to_string(),
+            font_size: FONT_SIZE,
+            arrow_style: ArrowStyle::default(),
+            intersection_mask: true,
+            sequence_numbers: true,
+            travel_lines: true,
+            legend: false,
+            cell_size_mm: None,
+            tile_size: None,
+            confetti: false,
+        }
+    }
+}
@@ -0,0 +1,553 @@
+use crate::confetti::ConfettiReport;
+use crate::grid_cell::GridCell;
+use crate::parking::next_on_thread;
+use crate::stitch::{HalfStitch, RestartPolicy};
+use crate::svg_output::html_export::escape_html;
+use crate::svg_output::line_segment::LineSegment;
+use crate::svg_output::svg_construction::{
+    centring_offset, draw_diagram, furthest_hole, hole_position, re_centre_stitches, view_size,
+    Diagram,
+};
+use crate::svg_output::svg_options::SvgOptions;
+use crate::theme::{svg_colour, Palette};
+use itertools::Itertools;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt::Write;
+use svg::node::element::{Anchor, Group, Line, Rectangle, Text};
+use svg::Document;
+
+/// How many view units each cell takes up in the index.
+const INDEX_CELL_SIZE: f64 = 20.0;
+
+/// A square of the design, drawn in its own document.
+pub struct Tile {
+    pub file_name: String,
+    pub document: Document,
+}
+
+/// A design split into tiles, with an overview of the whole design that links to each of them.
+pub struct TiledGraphic {
+    pub tiles: Vec<Tile>,
+    pub index_svg: Document,
+    pub index_html: String,
+}
+
+/// Where a tile is in the design, counting columns from the left and rows from the bottom.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+struct TilePosition {
+    column: isize,
+    row: isize,
+}
+
+/// How the design is cut up into tiles.
+struct Layout {
+    tile_size: isize,
+    columns: isize,
+    rows: isize,
+
+    /// The top right hole of the whole design.
+    extent: GridCell,
+}
+
+impl Layout {
+    fn new(extent: GridCell, tile_size: usize) -> Self {
+        let tile_size = tile_size.max(1) as isize;
+        Self {
+            tile_size,
+            columns: (extent.x + tile_size - 1) / tile_size,
+            rows: (extent.y + tile_size - 1) / tile_size,
+            extent,
+        }
+    }
+
+    fn position(&self, cell: GridCell) -> TilePosition {
+        TilePosition {
+            column: cell.x / self.tile_size,
+            row: cell.y / self.tile_size,
+        }
+    }
+
+    /// The bottom left hole of the tile.
+    fn origin(&self, position: TilePosition) -> GridCell {
+        GridCell::new(
+            position.column * self.tile_size,
+            position.row * self.tile_size,
+        )
+    }
+
+    /// The top right hole of the tile, from its origin.
+    /// Tiles on the top and right edges stop at the edge of the design.
+    fn tile_extent(&self, position: TilePosition) -> GridCell {
+        let origin = self.origin(position);
+        GridCell::new(
+            self.tile_size.min(self.extent.x - origin.x),
+            self.tile_size.min(self.extent.y - origin.y),
+        )
+    }
+
+    /// Name the tile by its row and column, counting from the top left like a printed chart.
+    fn name(&self, position: TilePosition) -> String {
+        format!("r{}-c{}", self.rows - position.row, position.column + 1)
+    }
+}
+
+/// Split the design into tiles of the given number of cells across,
+/// each numbered from its own first stitch and marked where the thread crosses into another tile.
+/// The tiles are named after the file stem, so the index can link to them.
+pub fn create_tiles(
+    stitches: &[HalfStitch],
+    colours: &HashMap<GridCell, usize>,
+    restart_policy: Option<RestartPolicy>,
+    palette: &Palette,
+    options: &SvgOptions,
+    tile_size: usize,
+    file_stem: &str,
+) -> TiledGraphic {
+    // The threads are found before re-centring, as the colours are keyed by the original cells.
+    let next = next_on_thread(stitches, colours, restart_policy);
+    let mut previous = vec![None; next.len()];
+    for (i, j) in next.iter().enumerate() {
+        if let Some(j) = j {
+            previous[*j] = Some(i);
+        }
+    }
+    let confetti = match options.confetti {
+        true => {
+            let offset = centring_offset(stitches);
+            ConfettiReport::analyse(stitches, colours, restart_policy)
+                .cells()
+                .map(|cell| *cell - offset)
+                .collect_vec()
+        }
+        false => vec![],
+    };
+    let stitches = re_centre_stitches(stitches);
+    let layout = Layout::new(furthest_hole(&stitches), tile_size);
+    let positions = stitches
+        .iter()
+        .map(|stitch| layout.position(stitch.cell()))
+        .collect_vec();
+    let file_name = |position: TilePosition| format!("{}-{}.svg", file_stem, layout.name(position));
+
+    let tile_stitches = (0..stitches.len())
+        .into_group_map_by(|&i| positions[i])
+        .into_iter()
+        .sorted_by_key(|(position, _)| (Reverse(position.row), position.column))
+        .collect_vec();
+
+    let travel_colour = svg_colour(palette.svg_travel);
+    let mut tiles = Vec::new();
+    for (position, indices) in &tile_stitches {
+        let origin = layout.origin(*position);
+        let local_stitches = indices
+            .iter()
+            .enumerate()
+            .map(|(k, &i)| HalfStitch {
+                start: stitches[i].start - origin,
+                order: 2 * k + 1,
+                ..stitches[i]
+            })
+            .collect_vec();
+
+        // Only travel between stitches on the same thread that are both in the tile is drawn,
+        // numbered by the stitch before the one it leads to as in the whole design.
+        let local: HashMap<usize, usize> =
+            indices.iter().enumerate().map(|(k, &i)| (i, k)).collect();
+        let travel = indices
+            .iter()
+            .enumerate()
+            .filter_map(|(k, &i)| {
+                let l = *local.get(&next[i]?)?;
+                Some(LineSegment::new(
+                    local_stitches[k].get_end_location(),
+                    local_stitches[l].start,
+                    l - 1,
+                ))
+            })
+            .collect_vec();
+
+        let mut diagram = Diagram {
+            stitches: vec![],
+            travel,
+            thread_starts: vec![],
+            thread_ends: vec![],
+            extent: layout.tile_extent(*position),
+            summary: vec![],
+            confetti: confetti
+                .iter()
+                .filter(|cell| layout.position(**cell) == *position)
+                .map(|cell| *cell - origin)
+                .collect(),
+        };
+        let mut crossings = Vec::new();
+        for (stitch, &i) in local_stitches.iter().zip(indices) {
+            match previous[i] {
+                None => diagram.thread_starts.push(stitch.start),
+                Some(p) if positions[p] != *position => {
+                    crossings.push((stitch.start, format!("from {}", layout.name(positions[p]))))
+                }
+                Some(_) => {}
+            }
+            match next[i] {
+                None => diagram.thread_ends.push(stitch.get_end_location()),
+                Some(n) if positions[n] != *position => crossings.push((
+                    stitch.get_end_location(),
+                    format!("to {}", layout.name(positions[n])),
+                )),
+                Some(_) => {}
+            }
+        }
+        diagram.summary = vec![
+            format!(
+                "Tile {} of {} by {}",
+                layout.name(*position),
+                layout.columns,
+                layout.rows
+            ),
+            format!(
+                "{} half stitch{}, numbered within the tile",
+                indices.len(),
+                if indices.len() == 1 { "" } else { "es" }
+            ),
+            format!("Border crossings: {}", crossings.len()),
+        ];
+        diagram.stitches = local_stitches;
+
+        let document = draw_diagram(&diagram, palette, options).add(draw_border_crossings(
+            &crossings,
+            &travel_colour,
+            options,
+            view_size(diagram.extent, options),
+        ));
+        tiles.push(Tile {
+            file_name: file_name(*position),
+            document,
+        });
+    }
+
+    let tile_counts = tile_stitches
+        .iter()
+        .map(|(position, indices)| (*position, indices.len()))
+        .collect_vec();
+    TiledGraphic {
+        tiles,
+        index_svg: draw_index(&stitches, &layout, &tile_counts, &file_name, palette),
+        index_html: write_index_html(&layout, &tile_counts, &file_name, file_stem),
+    }
+}
+
+/// Mark the holes where the thread comes in from, or goes out to, another tile.
+/// The labels are kept inside the view, as the holes are often on its edge.
+fn draw_border_crossings(
+    crossings: &[(GridCell, String)],
+    colour: &str,
+    options: &SvgOptions,
+    (view_width, view_height): (f64, f64),
+) -> Group {
+    let size = options.dot_radius * 3.0;
+    let mut group = Group::new().set("id", "border-crossings");
+    for (hole, text) in crossings {
+        let (x, y) = hole_position(*hole, options, view_height);
+        // Monospace characters are about 0.6 of the font size wide.
+        let text_width = text.chars().count() as f64 * options.font_size * 0.6;
+        let (text_x, anchor) = if x + size / 2.0 + text_width > view_width {
+            (x - size / 2.0, "end")
+        } else {
+            (x + size / 2.0, "start")
+        };
+        let text_y = if y - size / 2.0 - options.font_size < 0.0 {
+            y + size / 2.0 + options.font_size
+        } else {
+            y - size / 2.0
+        };
+        group = group
+            .add(
+                Rectangle::new()
+                    .set("x", x - size / 2.0)
+                    .set("y", y - size / 2.0)
+                    .set("width", size)
+                    .set("height", size)
+                    .set("fill", "none")
+                    .set("stroke", colour)
+                    .set("stroke-width", options.line_width),
+            )
+            .add(
+                Text::new(text.as_str())
+                    .set("x", text_x)
+                    .set("y", text_y)
+                    .set("text-anchor", anchor)
+                    .set("font-size", options.font_size)
+                    .set("font-family", options.font_family.as_str())
+                    .set("fill", colour),
+            );
+    }
+    group
+}
+
+/// Draw the whole design small, with each tile outlined and linked to its file.
+fn draw_index(
+    stitches: &[HalfStitch],
+    layout: &Layout,
+    tile_counts: &[(TilePosition, usize)],
+    file_name: &dyn Fn(TilePosition) -> String,
+    palette: &Palette,
+) -> Document {
+    let (width, height) = (
+        layout.extent.x as f64 * INDEX_CELL_SIZE,
+        layout.extent.y as f64 * INDEX_CELL_SIZE,
+    );
+    let point = |hole: GridCell| {
+        (
+            hole.x as f64 * INDEX_CELL_SIZE,
+            height - hole.y as f64 * INDEX_CELL_SIZE,
+        )
+    };
+
+    let mut stitch_group = Group::new()
+        .set("stroke", svg_colour(palette.svg_top_stitch))
+        .set("stroke-width", INDEX_CELL_SIZE / 10.0);
+    for stitch in stitches {
+        let ((x1, y1), (x2, y2)) = (point(stitch.start), point(stitch.get_end_location()));
+        stitch_group = stitch_group.add(
+            Line::new()
+                .set("x1", x1)
+                .set("y1", y1)
+                .set("x2", x2)
+                .set("y2", y2),
+        );
+    }
+
+    let mut document = Document::new()
+        .set("viewBox", (0, 0, width, height))
+        .add(stitch_group);
+    let font_size = INDEX_CELL_SIZE * 1.5;
+    for (position, count) in tile_counts {
+        let extent = layout.tile_extent(*position);
+        let (left, bottom) = point(layout.origin(*position));
+        let tile_height = extent.y as f64 * INDEX_CELL_SIZE;
+        let link = Anchor::new()
+            .set("href", file_name(*position))
+            .add(
+                Rectangle::new()
+                    .set("x", left)
+                    .set("y", bottom - tile_height)
+                    .set("width", extent.x as f64 * INDEX_CELL_SIZE)
+                    .set("height", tile_height)
+                    .set("fill", "transparent")
+                    .set("stroke", svg_colour(palette.svg_dots))
+                    .set("stroke-width", INDEX_CELL_SIZE / 5.0),
+            )
+            .add(
+                Text::new(format!("{} ({})", layout.name(*position), count))
+                    .set("x", left + font_size / 3.0)
+                    .set("y", bottom - tile_height + font_size)
+                    .set("font-size", font_size)
+                    .set("font-family", "monospace")
+                    .set("fill", svg_colour(palette.svg_travel)),
+            );
+        document = document.add(link);
+    }
+    document
+}
+
+/// Write a page with a table of links to the tiles, laid out as they are in the design.
+fn write_index_html(
+    layout: &Layout,
+    tile_counts: &[(TilePosition, usize)],
+    file_name: &dyn Fn(TilePosition) -> String,
+    file_stem: &str,
+) -> String {
+    let title = escape_html(file_stem);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n\
+         <style>table {{ border-collapse: collapse; }} \
+         td {{ border: 1px solid #888; padding: 0.5em; text-align: center; }}</style>\n\
+         </head>\n<body>\n<h1>{title}</h1>\n\
+         <p><a href=\"{title}.svg\">Whole design</a></p>\n<table>\n"
+    );
+    for row in (0..layout.rows).rev() {
+        html.push_str("<tr>");
+        for column in 0..layout.columns {
+            let position = TilePosition { column, row };
+            match tile_counts.iter().find(|(p, _)| *p == position) {
+                Some((_, count)) => {
+                    let _ = write!(
+                        html,
+                        "<td><a href=\"{}\">{}</a><br>{} half stitch{}</td>",
+                        escape_html(&file_name(position)),
+                        layout.name(position),
+                        count,
+                        if *count == 1 { "" } else { "es" }
+                    );
+                }
+                None => html.push_str("<td></td>"),
+            }
+        }
+        html.push_str("</tr>\n");
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stitch::StartingStitchCorner;
+    use crate::theme::ColourTheme;
+
+    fn _row_of_stitches(length: isize) -> Vec<HalfStitch> {
+        let cells = (0..length).map(|x| GridCell::new(x, 0)).collect_vec();
+        HalfStitch::convert_grid_cells(
+            cells.iter(),
+            StartingStitchCorner::BottomLeft,
+            StartingStitchCorner::BottomRight,
+        )
+    }
+
+    /// The contents of every text element in the document.
+    fn _texts(document: &Document) -> Vec<String> {
+        document
+            .to_string()
+            .split("<text")
+            .skip(1)
+            .filter_map(|text| {
+                let (_, contents) = text.split_once('>')?;
+                let (contents, _) = contents.split_once("</text>")?;
+                Some(contents.trim().to_string())
+            })
+            .collect()
+    }
+
+    fn _tiles(stitches: &[HalfStitch], restart_policy: Option<RestartPolicy>) -> TiledGraphic {
+        create_tiles(
+            stitches,
+            &HashMap::new(),
+            restart_policy,
+            &ColourTheme::default().palette(),
+            &SvgOptions::default(),
+            10,
+            "design",
+        )
+    }
+
+    #[test]
+    fn test_tiles_cover_the_design() {
+        let tiled = _tiles(&_row_of_stitches(25), None);
+        let names = tiled.tiles.iter().map(|tile| &tile.file_name).collect_vec();
+        assert_eq!(
+            names,
+            ["design-r1-c1.svg", "design-r1-c2.svg", "design-r1-c3.svg"]
+        );
+        // The last tile only reaches the edge of the design.
+        let last = tiled.tiles[2].document.to_string();
+        assert!(last.contains("viewBox=\"0 0 2600"));
+    }
+
+    #[test]
+    fn test_tiles_mark_border_crossings() {
+        let tiled = _tiles(&_row_of_stitches(25), None);
+        let crossings = tiled
+            .tiles
+            .iter()
+            .map(|tile| {
+                _texts(&tile.document)
+                    .into_iter()
+                    .filter(|text| text.starts_with("from ") || text.starts_with("to "))
+                    .collect_vec()
+            })
+            .collect_vec();
+        assert_eq!(crossings[0], ["to r1-c2"]);
+        assert_eq!(crossings[1], ["from r1-c1", "to r1-c3"]);
+        assert_eq!(crossings[2], ["from r1-c2"]);
+    }
+
+    #[test]
+    fn test_parked_colour_does_not_cross_tiles() {
+        // The second colour is in another tile, between two stitches in the first colour.
+        let cells = [
+            GridCell::new(0, 0),
+            GridCell::new(12, 0),
+            GridCell::new(1, 0),
+        ];
+        let stitches = HalfStitch::convert_grid_cells(
+            cells.iter(),
+            StartingStitchCorner::BottomLeft,
+            StartingStitchCorner::BottomRight,
+        );
+        let colours = HashMap::from([(GridCell::new(12, 0), 2)]);
+        let tiled = create_tiles(
+            &stitches,
+            &colours,
+            None,
+            &ColourTheme::default().palette(),
+            &SvgOptions::default(),
+            10,
+            "design",
+        );
+        for tile in &tiled.tiles {
+            assert!(!_texts(&tile.document)
+                .iter()
+                .any(|text| text.starts_with("from ") || text.starts_with("to ")));
+        }
+        // Two stitches and the travel between them in the first tile.
+        let numbers = _texts(&tiled.tiles[0].document)
+            .iter()
+            .filter_map(|text| text.parse::<usize>().ok())
+            .sorted()
+            .collect_vec();
+        assert_eq!(numbers, [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_tiles_number_their_own_stitches() {
+        let tiled = _tiles(&_row_of_stitches(25), None);
+        let texts = _texts(&tiled.tiles[1].document);
+        // Ten stitches with travel between them, numbered from one.
+        let numbers = texts
+            .iter()
+            .filter_map(|text| text.parse::<usize>().ok())
+            .sorted()
+            .collect_vec();
+        assert_eq!(numbers, (1..=19).collect_vec());
+    }
+
+    #[test]
+    fn test_restart_between_tiles_is_not_a_crossing() {
+        let stitches = [0, 1, 15, 16]
+            .iter()
+            .map(|&x| {
+                HalfStitch::new_in_cell(GridCell::new(x, 0), StartingStitchCorner::BottomLeft, 0)
+            })
+            .collect_vec();
+        let tiled = _tiles(&stitches, Some(RestartPolicy::default()));
+        assert_eq!(tiled.tiles.len(), 2);
+        for tile in &tiled.tiles {
+            assert!(!_texts(&tile.document)
+                .iter()
+                .any(|text| text.starts_with("from ") || text.starts_with("to ")));
+        }
+    }
+
+    #[test]
+    fn test_index_links_every_tile() {
+        let cells = [GridCell::new(0, 0), GridCell::new(12, 12)];
+        let stitches = HalfStitch::convert_grid_cells(
+            cells.iter(),
+            StartingStitchCorner::BottomLeft,
+            StartingStitchCorner::BottomRight,
+        );
+        let tiled = _tiles(&stitches, None);
+        assert_eq!(tiled.tiles.len(), 2);
+        let index_svg = tiled.index_svg.to_string();
+        for tile in &tiled.tiles {
+            assert!(index_svg.contains(&format!("href=\"{}\"", tile.file_name)));
+            assert!(tiled
+                .index_html
+                .contains(&format!("href=\"{}\"", tile.file_name)));
+        }
+        // The top right tile comes first, and the empty tiles are left blank.
+        assert_eq!(tiled.tiles[0].file_name, "design-r1-c2.svg");
+        assert_eq!(tiled.index_html.matches("<td></td>").count(), 2);
+    }
+}
@@ -1,13 +1,22 @@
 use crate::grid_cell::GridCell;
+use cached::proc_macro::cached;
+use num_bigint::BigInt;
+use num_traits::Zero;
 use prime_factorization::Factorization;
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::fmt;
 use std::fmt::{Debug, Formatter};
+use std::ops::{Add, Mul, Neg, Sub};
 
-#[derive(Debug, Clone, Default)]
+/// An exact sum of an integer and integer multiples of square roots.
+/// The square roots are kept in their simplest form, so every key is square-free,
+/// and no coefficient is ever zero. Together, this means two sums are equal
+/// exactly when their terms are equal.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct SymbolicSum {
-    constant: usize,
-    square_root_terms: HashMap<usize, usize>,
+    constant: isize,
+    square_root_terms: HashMap<usize, isize>,
 }
 
 impl fmt::Display for SymbolicSum {
@@ -18,10 +27,11 @@ impl fmt::Display for SymbolicSum {
         keys.sort();
         for &k in &keys {
             if let Some(&coefficient) = self.square_root_terms.get(k) {
-                if coefficient > 1 {
-                    write!(f, " + {}√{}", coefficient, k)?;
+                let sign = if coefficient < 0 { "-" } else { "+" };
+                if coefficient.abs() > 1 {
+                    write!(f, " {} {}√{}", sign, coefficient.abs(), k)?;
                 } else {
-                    write!(f, " + √{}", k)?;
+                    write!(f, " {} √{}", sign, k)?;
                 }
             }
         }
@@ -32,47 +42,250 @@ impl fmt::Display for SymbolicSum {
 impl SymbolicSum {
     pub fn add_distance(&mut self, first: GridCell, second: GridCell) {
         let squared_distance = first.euclidean_distance_squared(&second);
-        let mut decomp_irrationals = SymbolicSum::decompose(squared_distance);
-        self.constant += decomp_irrationals.remove(&1).unwrap_or(0);
-        for (key, value) in decomp_irrationals {
-            *self.square_root_terms.entry(key).or_insert(0) += value;
+        if squared_distance == 0 {
+            return;
+        }
+        for (key, value) in SymbolicSum::decompose(squared_distance) {
+            self.add_term(key, value as isize);
         }
     }
 
-    fn decompose(squared_number: usize) -> HashMap<usize, usize> {
-        let factors = Factorization::<u64>::run(squared_number as u64);
+    /// The exact distance between two cells.
+    pub fn from_distance(first: GridCell, second: GridCell) -> SymbolicSum {
+        let mut sum = SymbolicSum::default();
+        sum.add_distance(first, second);
+        sum
+    }
 
-        // Count the exponent of each prime factor.
-        let mut factor_counts = HashMap::new();
-        for factor in factors.factors {
-            *factor_counts.entry(factor as usize).or_insert(0) += 1;
+    pub fn add_constant(&mut self, constant: usize) {
+        self.constant += constant as isize;
+    }
+
+    /// Add a multiple of the square root of a square-free number, where the root of 1 is the constant.
+    fn add_term(&mut self, square_free: usize, coefficient: isize) {
+        if square_free == 1 {
+            self.constant += coefficient;
+            return;
+        }
+        let entry = self.square_root_terms.entry(square_free).or_insert(0);
+        *entry += coefficient;
+        if *entry == 0 {
+            self.square_root_terms.remove(&square_free);
         }
+    }
+
+    /// The closest floating point value to the sum.
+    pub fn to_f64(&self) -> f64 {
+        self.constant as f64
+            + self
+                .square_root_terms
+                .iter()
+                .map(|(&k, &c)| c as f64 * (k as f64).sqrt())
+                .sum::<f64>()
+    }
 
-        // Calculate largest square divisor and remainder factor.
-        let (largest_square_divisor, remainder_factor) =
-            factor_counts
+    /// Determine whether the sum is negative, zero or positive.
+    pub fn signum(&self) -> Ordering {
+        // Most sums are far enough from zero that the floating point value decides the sign.
+        // The bound is generous for the rounding of each root, product and addition.
+        let magnitude = self.constant.unsigned_abs() as f64
+            + self
+                .square_root_terms
                 .iter()
-                .fold((1, 1), |(square, rem), (&prime, &exp)| {
-                    let square_power = exp / 2;
-                    let remainder = exp % 2;
-                    (
-                        square * prime.pow(square_power as u32),
-                        rem * if remainder > 0 { prime } else { 1 },
-                    )
-                });
-
-        // Construct decomposition map
-        let mut decomposition = HashMap::new();
-        decomposition.insert(
-            if remainder_factor == 1 {
-                1
-            } else {
-                remainder_factor
-            },
-            largest_square_divisor,
-        );
-
-        decomposition
+                .map(|(&k, &c)| c.unsigned_abs() as f64 * (k as f64).sqrt())
+                .sum::<f64>();
+        let error_bound =
+            magnitude * f64::EPSILON * 4.0 * (self.square_root_terms.len() + 2) as f64;
+        let estimate = self.to_f64();
+        if estimate.abs() > error_bound {
+            return estimate.partial_cmp(&0.0).unwrap();
+        }
+
+        let mut terms: HashMap<usize, BigInt> = self
+            .square_root_terms
+            .iter()
+            .map(|(&k, &c)| (k, BigInt::from(c)))
+            .collect();
+        if self.constant != 0 {
+            terms.insert(1, BigInt::from(self.constant));
+        }
+        exact_signum(terms)
+    }
+
+    fn decompose(squared_number: usize) -> HashMap<usize, usize> {
+        decompose_squared_distance(squared_number)
+    }
+}
+
+/// Split a squared distance into the multiple of the square root of a square-free number.
+/// The same distances come up again and again, so the factorisation is memoised.
+#[cached]
+fn decompose_squared_distance(squared_number: usize) -> HashMap<usize, usize> {
+    let factors = Factorization::<u64>::run(squared_number as u64);
+
+    // Count the exponent of each prime factor.
+    let mut factor_counts = HashMap::new();
+    for factor in factors.factors {
+        *factor_counts.entry(factor as usize).or_insert(0) += 1;
+    }
+
+    // Calculate largest square divisor and remainder factor.
+    let (largest_square_divisor, remainder_factor) =
+        factor_counts
+            .iter()
+            .fold((1, 1), |(square, rem), (&prime, &exp)| {
+                let square_power = exp / 2;
+                let remainder = exp % 2;
+                (
+                    square * prime.pow(square_power as u32),
+                    rem * if remainder > 0 { prime } else { 1 },
+                )
+            });
+
+    // Construct decomposition map
+    let mut decomposition = HashMap::new();
+    decomposition.insert(
+        if remainder_factor == 1 {
+            1
+        } else {
+            remainder_factor
+        },
+        largest_square_divisor,
+    );
+
+    decomposition
+}
+
+impl Add for SymbolicSum {
+    type Output = Self;
+
+    fn add(mut self, rhs: Self) -> Self::Output {
+        self.constant += rhs.constant;
+        for (key, coefficient) in rhs.square_root_terms {
+            self.add_term(key, coefficient);
+        }
+        self
+    }
+}
+
+impl Neg for SymbolicSum {
+    type Output = Self;
+
+    fn neg(self) -> Self::Output {
+        self * -1
+    }
+}
+
+impl Sub for SymbolicSum {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        self + -rhs
+    }
+}
+
+impl Mul<isize> for SymbolicSum {
+    type Output = Self;
+
+    fn mul(self, rhs: isize) -> Self::Output {
+        if rhs == 0 {
+            return SymbolicSum::default();
+        }
+        SymbolicSum {
+            constant: self.constant * rhs,
+            square_root_terms: self
+                .square_root_terms
+                .into_iter()
+                .map(|(k, c)| (k, c * rhs))
+                .collect(),
+        }
+    }
+}
+
+impl PartialOrd for SymbolicSum {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SymbolicSum {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.clone() - other.clone()).signum()
+    }
+}
+
+/// Exactly determine the sign of a sum of multiples of square roots of square-free numbers.
+///
+/// The largest prime appearing under a root is split out, writing the sum as `X + Y√p`,
+/// where neither `X` nor `Y` contains `√p`.
+/// When the signs of `X` and `Y` differ, the sign is decided by comparing `X²` with `pY²`,
+/// which also do not contain `√p`, so each step removes a prime until only integers remain.
+fn exact_signum(mut terms: HashMap<usize, BigInt>) -> Ordering {
+    terms.retain(|_, c| !c.is_zero());
+    let signs: Vec<Ordering> = terms.values().map(|c| c.cmp(&BigInt::zero())).collect();
+    if signs.is_empty() {
+        return Ordering::Equal;
+    }
+    if signs.iter().all(|&s| s == signs[0]) {
+        return signs[0];
+    }
+
+    let prime = terms
+        .keys()
+        .filter(|&&k| k > 1)
+        .flat_map(|&k| Factorization::<u64>::run(k as u64).factors)
+        .max()
+        .unwrap() as usize;
+    let (with_prime, without_prime): (HashMap<usize, BigInt>, HashMap<usize, BigInt>) =
+        terms.into_iter().partition(|(k, _)| k % prime == 0);
+    let rational_part = without_prime;
+    let root_part: HashMap<usize, BigInt> = with_prime
+        .into_iter()
+        .map(|(k, c)| (k / prime, c))
+        .collect();
+
+    let rational_sign = exact_signum(rational_part.clone());
+    let root_sign = exact_signum(root_part.clone());
+    if root_sign == Ordering::Equal || rational_sign == root_sign {
+        return rational_sign;
+    }
+    if rational_sign == Ordering::Equal {
+        return root_sign;
+    }
+
+    let mut difference = square_terms(&rational_part);
+    for (k, c) in square_terms(&root_part) {
+        *difference.entry(k).or_default() -= c * prime;
+    }
+    let squares_sign = exact_signum(difference);
+    if rational_sign == Ordering::Greater {
+        squares_sign
+    } else {
+        squares_sign.reverse()
+    }
+}
+
+/// Square a sum of multiples of square roots of square-free numbers,
+/// keeping each resulting root in its simplest form.
+fn square_terms(terms: &HashMap<usize, BigInt>) -> HashMap<usize, BigInt> {
+    let mut squared: HashMap<usize, BigInt> = HashMap::new();
+    for (&first_key, first_coefficient) in terms {
+        for (&second_key, second_coefficient) in terms {
+            // √a√b = g√((a/g)(b/g)), where g is the greatest common divisor of a and b.
+            let divisor = greatest_common_divisor(first_key, second_key);
+            *squared
+                .entry((first_key / divisor) * (second_key / divisor))
+                .or_default() += first_coefficient * second_coefficient * divisor;
+        }
+    }
+    squared
+}
+
+fn greatest_common_divisor(first: usize, second: usize) -> usize {
+    if second == 0 {
+        first
+    } else {
+        greatest_common_divisor(second, first % second)
     }
 }
 
@@ -124,8 +337,10 @@ mod tests {
 
     #[test]
     fn test_symbolic_sum_string_constant() {
-        let mut sum = SymbolicSum::default();
-        sum.constant = 10;
+        let sum = SymbolicSum {
+            constant: 10,
+            ..Default::default()
+        };
         assert_eq!(sum.to_string(), "10");
     }
 
@@ -149,4 +364,93 @@ mod tests {
         sum.add_distance(GridCell { x: 1, y: 1 }, GridCell { x: 2, y: 0 });
         assert_eq!(sum.to_string(), "0 + √2");
     }
+
+    fn _sum(constant: isize, roots: &[(usize, isize)]) -> SymbolicSum {
+        let mut sum = SymbolicSum::default()
+            + SymbolicSum {
+                constant,
+                ..Default::default()
+            };
+        for &(key, coefficient) in roots {
+            sum.add_term(key, coefficient);
+        }
+        sum
+    }
+
+    #[test]
+    fn test_symbolic_sum_string_negative_irrational() {
+        let sum = _sum(3, &[(2, -1), (5, -2)]);
+        assert_eq!(sum.to_string(), "3 - √2 - 2√5");
+    }
+
+    #[test]
+    fn test_symbolic_sum_add() {
+        let result = _sum(1, &[(2, 1)]) + _sum(2, &[(2, 2), (3, 1)]);
+        assert_eq!(result, _sum(3, &[(2, 3), (3, 1)]));
+    }
+
+    #[test]
+    fn test_symbolic_sum_sub_removes_cancelled_terms() {
+        let result = _sum(3, &[(2, 1)]) - _sum(1, &[(2, 1)]);
+        assert_eq!(result, _sum(2, &[]));
+        assert_eq!(result.to_string(), "2");
+    }
+
+    #[test]
+    fn test_symbolic_sum_scalar_multiplication() {
+        let result = _sum(1, &[(2, 1)]) * 3;
+        assert_eq!(result, _sum(3, &[(2, 3)]));
+        let result = _sum(1, &[(2, 1)]) * -2;
+        assert_eq!(result, _sum(-2, &[(2, -2)]));
+    }
+
+    #[test]
+    fn test_symbolic_sum_compare_constant_and_root() {
+        // 3 + √2 ≈ 4.414
+        assert!(_sum(3, &[(2, 1)]) > _sum(4, &[]));
+        // 2 + √2 ≈ 3.414
+        assert!(_sum(2, &[(2, 1)]) < _sum(4, &[]));
+    }
+
+    #[test]
+    fn test_symbolic_sum_compare_equal() {
+        assert_eq!(_sum(1, &[(2, 2)]).cmp(&_sum(1, &[(2, 2)])), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_exact_signum_close_values() {
+        // √2 + √3 ≈ 3.14626 and √10 ≈ 3.16228, which need several squarings to separate.
+        let terms = HashMap::from([
+            (2, BigInt::from(1)),
+            (3, BigInt::from(1)),
+            (10, BigInt::from(-1)),
+        ]);
+        assert_eq!(exact_signum(terms), Ordering::Less);
+    }
+
+    #[test]
+    fn test_exact_signum_mixed_signs() {
+        // 5 - 2√6 ≈ 0.101
+        let terms = HashMap::from([(1, BigInt::from(5)), (6, BigInt::from(-2))]);
+        assert_eq!(exact_signum(terms), Ordering::Greater);
+        // 2√6 - 5
+        let terms = HashMap::from([(1, BigInt::from(-5)), (6, BigInt::from(2))]);
+        assert_eq!(exact_signum(terms), Ordering::Less);
+    }
+
+    #[test]
+    fn test_exact_signum_agrees_with_float() {
+        for a in -5..=5 {
+            for b in -5..=5 {
+                let sum = _sum(a, &[(2, b), (3, 1)]);
+                let mut terms: HashMap<usize, BigInt> = sum
+                    .square_root_terms
+                    .iter()
+                    .map(|(&k, &c)| (k, BigInt::from(c)))
+                    .collect();
+                terms.insert(1, BigInt::from(a));
+                assert_eq!(exact_signum(terms), sum.to_f64().partial_cmp(&0.0).unwrap());
+            }
+        }
+    }
 }
@@ -0,0 +1,227 @@
+use iced::Color;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fmt::Formatter;
+
+/// A named set of colours used for the grid and the exported SVG.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub enum ColourTheme {
+    Light,
+    #[default]
+    Dark,
+    HighContrast,
+    ColourBlindSafe,
+}
+
+/// The colours for each part of the grid and the exported SVG.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Palette {
+    pub background: Color,
+    pub grid_lines: Color,
+    pub cell: Color,
+    pub invalid_cell: Color,
+    pub stitch: Color,
+    pub cursor: Color,
+    pub text: Color,
+
+    /// Markers for the holes where a thread starts and ends.
+    pub thread_start: Color,
+    pub thread_end: Color,
+
+    /// Outline of isolated stitches and small islands.
+    pub confetti: Color,
+
+    /// Fills for cells stitched in the colours after the first, which uses `cell`.
+    /// These are pale so the stitches drawn over them stay clear.
+    pub thread_cells: [Color; THREAD_COLOURS - 1],
+
+    /// Colours of the SVG, which is drawn on a white page.
+    pub svg_dots: Color,
+    pub svg_bottom_stitch: Color,
+    pub svg_top_stitch: Color,
+    pub svg_travel: Color,
+    pub svg_confetti: Color,
+}
+
+/// The number of thread colours that can be told apart on the grid.
+pub const THREAD_COLOURS: usize = 6;
+
+impl Palette {
+    /// The fill for a cell stitched in the given colour, counting from 1.
+    pub fn thread_cell(&self, colour: usize) -> Color {
+        match colour {
+            0 | 1 => self.cell,
+            _ => self.thread_cells[(colour - 2).min(THREAD_COLOURS - 2)],
+        }
+    }
+}
+
+impl ColourTheme {
+    pub const ALL: [ColourTheme; 4] = [
+        ColourTheme::Light,
+        ColourTheme::Dark,
+        ColourTheme::HighContrast,
+        ColourTheme::ColourBlindSafe,
+    ];
+
+    pub fn palette(&self) -> Palette {
+        match self {
+            ColourTheme::Light => Palette {
+                background: Color::from_rgb8(0xF6, 0xF8, 0xFA),
+                grid_lines: Color::from_rgb8(0xD0, 0xD7, 0xDE),
+                cell: Color::from_rgb8(0xDD, 0xF4, 0xFF),
+                invalid_cell: Color::from_rgb8(0xFF, 0x81, 0x82),
+                stitch: Color::BLACK,
+                cursor: Color::from_rgb8(0xBF, 0x87, 0x00),
+                text: Color::from_rgb8(0x1F, 0x23, 0x28),
+                thread_start: Color::from_rgb8(0x1A, 0x7F, 0x37),
+                thread_end: Color::from_rgb8(0xCF, 0x22, 0x2E),
+                confetti: Color::from_rgb8(0x82, 0x50, 0xDF),
+                thread_cells: [
+                    Color::from_rgb8(0xFF, 0xD8, 0xA8),
+                    Color::from_rgb8(0xC3, 0xF0, 0xC8),
+                    Color::from_rgb8(0xF8, 0xC8, 0xDC),
+                    Color::from_rgb8(0xE5, 0xDB, 0xFF),
+                    Color::from_rgb8(0xFF, 0xF3, 0xA3),
+                ],
+                svg_dots: Color::BLACK,
+                svg_bottom_stitch: Color::from_rgb8(0x00, 0x00, 0xFF),
+                svg_top_stitch: Color::from_rgb8(0xFF, 0x00, 0x00),
+                svg_travel: Color::from_rgb8(0x00, 0x80, 0x00),
+                svg_confetti: Color::from_rgb8(0x82, 0x50, 0xDF),
+            },
+            ColourTheme::Dark => Palette {
+                background: Color::from_rgb8(0x40, 0x44, 0x4B),
+                grid_lines: Color::from_rgb8(70, 74, 83),
+                cell: Color::WHITE,
+                invalid_cell: Color::from_rgb(1.0, 0.0, 0.0),
+                stitch: Color::BLACK,
+                cursor: Color::from_rgb8(0xE3, 0xB3, 0x41),
+                text: Color::WHITE,
+                thread_start: Color::from_rgb8(0x2E, 0xA0, 0x43),
+                thread_end: Color::from_rgb8(0xD0, 0x3A, 0x3A),
+                confetti: Color::from_rgb8(0xA3, 0x71, 0xF7),
+                thread_cells: [
+                    Color::from_rgb8(0xFF, 0xD8, 0xA8),
+                    Color::from_rgb8(0xC3, 0xF0, 0xC8),
+                    Color::from_rgb8(0xF8, 0xC8, 0xDC),
+                    Color::from_rgb8(0xE5, 0xDB, 0xFF),
+                    Color::from_rgb8(0xFF, 0xF3, 0xA3),
+                ],
+                svg_dots: Color::BLACK,
+                svg_bottom_stitch: Color::from_rgb8(0x00, 0x00, 0xFF),
+                svg_top_stitch: Color::from_rgb8(0xFF, 0x00, 0x00),
+                svg_travel: Color::from_rgb8(0x00, 0x80, 0x00),
+                svg_confetti: Color::from_rgb8(0x82, 0x50, 0xDF),
+            },
+            ColourTheme::HighContrast => Palette {
+                background: Color::BLACK,
+                grid_lines: Color::from_rgb8(0x80, 0x80, 0x80),
+                cell: Color::WHITE,
+                invalid_cell: Color::from_rgb8(0xFF, 0x00, 0x00),
+                stitch: Color::BLACK,
+                cursor: Color::from_rgb8(0xFF, 0xFF, 0x00),
+                text: Color::WHITE,
+                thread_start: Color::from_rgb8(0x00, 0xFF, 0x00),
+                thread_end: Color::from_rgb8(0xFF, 0x00, 0xFF),
+                confetti: Color::from_rgb8(0x00, 0xFF, 0xFF),
+                thread_cells: [
+                    Color::from_rgb8(0xFF, 0xFF, 0x00),
+                    Color::from_rgb8(0x00, 0xFF, 0xFF),
+                    Color::from_rgb8(0xFF, 0x80, 0xFF),
+                    Color::from_rgb8(0x80, 0xFF, 0x80),
+                    Color::from_rgb8(0xFF, 0xB0, 0x40),
+                ],
+                svg_dots: Color::BLACK,
+                svg_bottom_stitch: Color::from_rgb8(0x00, 0x00, 0xCC),
+                svg_top_stitch: Color::from_rgb8(0xCC, 0x00, 0x00),
+                svg_travel: Color::from_rgb8(0x00, 0x66, 0x00),
+                svg_confetti: Color::from_rgb8(0x99, 0x00, 0x99),
+            },
+            // Based on the Okabe-Ito palette, which stays distinct for all common colour vision deficiencies.
+            ColourTheme::ColourBlindSafe => Palette {
+                background: Color::from_rgb8(0x40, 0x44, 0x4B),
+                grid_lines: Color::from_rgb8(70, 74, 83),
+                cell: Color::WHITE,
+                invalid_cell: Color::from_rgb8(0xD5, 0x5E, 0x00),
+                stitch: Color::BLACK,
+                cursor: Color::from_rgb8(0xF0, 0xE4, 0x42),
+                text: Color::WHITE,
+                thread_start: Color::from_rgb8(0x56, 0xB4, 0xE9),
+                thread_end: Color::from_rgb8(0xE6, 0x9F, 0x00),
+                confetti: Color::from_rgb8(0xCC, 0x79, 0xA7),
+                thread_cells: [
+                    Color::from_rgb8(0xE6, 0x9F, 0x00),
+                    Color::from_rgb8(0x56, 0xB4, 0xE9),
+                    Color::from_rgb8(0xF0, 0xE4, 0x42),
+                    Color::from_rgb8(0xCC, 0x79, 0xA7),
+                    Color::from_rgb8(0x00, 0x9E, 0x73),
+                ],
+                svg_dots: Color::BLACK,
+                svg_bottom_stitch: Color::from_rgb8(0x00, 0x72, 0xB2),
+                svg_top_stitch: Color::from_rgb8(0xD5, 0x5E, 0x00),
+                svg_travel: Color::from_rgb8(0x00, 0x9E, 0x73),
+                svg_confetti: Color::from_rgb8(0xCC, 0x79, 0xA7),
+            },
+        }
+    }
+
+    /// The theme for the widgets around the grid.
+    pub fn iced_theme(&self) -> iced::Theme {
+        match self {
+            ColourTheme::Light => iced::Theme::Light,
+            _ => iced::Theme::Dark,
+        }
+    }
+}
+
+impl fmt::Display for ColourTheme {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ColourTheme::Light => "Light",
+            ColourTheme::Dark => "Dark",
+            ColourTheme::HighContrast => "High Contrast",
+            ColourTheme::ColourBlindSafe => "Colour-Blind Safe",
+        })
+    }
+}
+
+/// Format a colour as a hex code, for use in SVG attributes.
+pub fn svg_colour(colour: Color) -> String {
+    let [r, g, b, _] = colour.into_rgba8();
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_svg_colour_hex() {
+        assert_eq!(svg_colour(Color::from_rgb8(0x2E, 0xA0, 0x43)), "#2ea043");
+        assert_eq!(svg_colour(Color::BLACK), "#000000");
+    }
+
+    #[test]
+    fn test_svg_stitch_colours_are_distinct() {
+        for theme in ColourTheme::ALL {
+            let palette = theme.palette();
+            assert_ne!(palette.svg_bottom_stitch, palette.svg_top_stitch);
+            assert_ne!(palette.svg_bottom_stitch, palette.svg_travel);
+            assert_ne!(palette.svg_top_stitch, palette.svg_travel);
+        }
+    }
+
+    #[test]
+    fn test_thread_cells_are_distinct() {
+        for theme in ColourTheme::ALL {
+            let palette = theme.palette();
+            let fills: Vec<Color> = (1..=THREAD_COLOURS)
+                .map(|colour| palette.thread_cell(colour))
+                .collect();
+            for (i, fill) in fills.iter().enumerate() {
+                assert!(!fills[i + 1..].contains(fill));
+            }
+        }
+    }
+}
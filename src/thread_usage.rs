@@ -0,0 +1,372 @@
+use crate::stitch::HalfStitch;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::f64::consts::SQRT_2;
+use std::fmt;
+use std::fmt::Formatter;
+
+/// Length of a standard skein of stranded cotton, in centimetres.
+const SKEIN_LENGTH_CM: f64 = 800.0;
+
+/// Number of strands that make up a skein of stranded cotton.
+pub const STRANDS_PER_SKEIN: usize = 6;
+
+/// Length of thread left at the back to secure a thread when starting or stopping, in centimetres.
+const TAIL_LENGTH_CM: f64 = 3.0;
+
+const CENTIMETRES_PER_INCH: f64 = 2.54;
+
+/// The fabric being stitched on, which sets the physical size of a cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub enum Fabric {
+    Aida11,
+    #[default]
+    Aida14,
+    Aida16,
+    Aida18,
+    #[serde(alias = "Evenweave28OverTwo")]
+    Evenweave28,
+    #[serde(alias = "Evenweave32OverTwo")]
+    Evenweave32,
+    Linen36,
+}
+
+impl Fabric {
+    pub const ALL: [Fabric; 7] = [
+        Fabric::Aida11,
+        Fabric::Aida14,
+        Fabric::Aida16,
+        Fabric::Aida18,
+        Fabric::Evenweave28,
+        Fabric::Evenweave32,
+        Fabric::Linen36,
+    ];
+
+    /// The number of fabric threads per inch.
+    pub fn thread_count(&self) -> usize {
+        match self {
+            Fabric::Aida11 => 11,
+            Fabric::Aida14 => 14,
+            Fabric::Aida16 => 16,
+            Fabric::Aida18 => 18,
+            Fabric::Evenweave28 => 28,
+            Fabric::Evenweave32 => 32,
+            Fabric::Linen36 => 36,
+        }
+    }
+
+    /// Whether the fabric is woven in blocks, which are always stitched over one block.
+    pub fn is_aida(&self) -> bool {
+        matches!(
+            self,
+            Fabric::Aida11 | Fabric::Aida14 | Fabric::Aida16 | Fabric::Aida18
+        )
+    }
+}
+
+impl fmt::Display for Fabric {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Fabric::Evenweave28 | Fabric::Evenweave32 => {
+                write!(f, "Evenweave {}", self.thread_count())
+            }
+            Fabric::Linen36 => write!(f, "Linen {}", self.thread_count()),
+            _ => write!(f, "Aida {}", self.thread_count()),
+        }
+    }
+}
+
+/// How the cells of a design sit on the threads of the fabric.
+/// On evenweave and linen each stitch crosses a number of threads, usually two.
+///
+/// The corners of a `GridCell` are the fabric holes its stitches use, every `over` threads.
+/// Stitches only go from corner to corner, so every hole the thread uses is a cell corner,
+/// and `get_end_location`, the same-hole check and the travel cost all work in cells as they do
+/// on Aida. The over count only changes the size of a cell, and the fabric threads drawn in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FabricGrid {
+    pub fabric: Fabric,
+    over: usize,
+}
+
+impl FabricGrid {
+    pub const MAX_OVER: usize = 4;
+
+    /// Stitch over the given number of threads, which is always one on Aida.
+    pub fn new(fabric: Fabric, over: usize) -> Self {
+        let over = if fabric.is_aida() {
+            1
+        } else {
+            over.clamp(1, Self::MAX_OVER)
+        };
+        Self { fabric, over }
+    }
+
+    /// The number of fabric threads that a single cell spans.
+    pub fn threads_per_cell(&self) -> usize {
+        self.over
+    }
+
+    /// The length of one side of a cell, in centimetres.
+    pub fn cell_size_cm(&self) -> f64 {
+        CENTIMETRES_PER_INCH / self.fabric.thread_count() as f64 * self.over as f64
+    }
+
+    /// The printed size of a cell on the fabric, in millimetres.
+    pub fn cell_size_mm(&self) -> f64 {
+        self.cell_size_cm() * 10.0
+    }
+}
+
+impl fmt::Display for FabricGrid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.fabric.is_aida() {
+            write!(f, "{}", self.fabric)
+        } else {
+            write!(f, "{} over {}", self.fabric, self.over)
+        }
+    }
+}
+
+/// An estimate of how much thread a sequence of stitches consumes.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ThreadEstimate {
+    /// Length of the stitches on the front of the fabric, in centimetres.
+    pub front_cm: f64,
+
+    /// Length of the travel between stitches on the back of the fabric, in centimetres.
+    pub back_cm: f64,
+
+    /// Length of the tails used to start and stop each thread, in centimetres.
+    pub tails_cm: f64,
+
+    /// How many strands are stitched with at once.
+    pub strands: usize,
+}
+
+impl ThreadEstimate {
+    /// Estimate the thread needed to stitch a single colour.
+    /// Each run is stitched with a separate thread, so has its own tails
+    /// and no travel to the next run.
+    pub fn calculate(
+        thread_runs: &[&[HalfStitch]],
+        fabric: FabricGrid,
+        strands: usize,
+    ) -> ThreadEstimate {
+        let stitch_count: usize = thread_runs.iter().map(|run| run.len()).sum();
+        let travel: f64 = thread_runs
+            .iter()
+            .map(|run| HalfStitch::calculate_cost_float(run))
+            .sum();
+        ThreadEstimate::new(stitch_count, travel, thread_runs.len(), fabric, strands)
+    }
+
+    /// Estimate the thread needed from the totals for a sequence,
+    /// where the travel is in cells and excludes any travel between separate threads.
+    pub fn new(
+        stitch_count: usize,
+        travel: f64,
+        thread_runs: usize,
+        fabric: FabricGrid,
+        strands: usize,
+    ) -> ThreadEstimate {
+        let cell_size = fabric.cell_size_cm();
+        ThreadEstimate {
+            front_cm: stitch_count as f64 * SQRT_2 * cell_size,
+            back_cm: travel * cell_size,
+            tails_cm: (2 * thread_runs) as f64 * TAIL_LENGTH_CM,
+            strands,
+        }
+    }
+
+    /// Length of a single thread needed, in centimetres.
+    pub fn thread_length_cm(&self) -> f64 {
+        self.front_cm + self.back_cm + self.tails_cm
+    }
+
+    /// Total length of strands pulled from the skein, in centimetres.
+    pub fn strand_length_cm(&self) -> f64 {
+        self.thread_length_cm() * self.strands as f64
+    }
+
+    /// The fraction of skeins used, given that each strand is separated from a skein.
+    pub fn skeins(&self) -> f64 {
+        self.strand_length_cm() / (SKEIN_LENGTH_CM * STRANDS_PER_SKEIN as f64)
+    }
+}
+
+impl fmt::Display for ThreadEstimate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let length = self.thread_length_cm();
+        if length >= 100.0 {
+            write!(f, "{:.2} m", length / 100.0)?;
+        } else {
+            write!(f, "{:.1} cm", length)?;
+        }
+        write!(
+            f,
+            " of thread ({} strand{}, {:.2} skeins)",
+            self.strands,
+            if self.strands == 1 { "" } else { "s" },
+            self.skeins()
+        )
+    }
+}
+
+/// Describe the thread needed on the fabric, with a line for each colour when there is more than one,
+/// such as "Colour 2: 1.20 m of thread (2 strands, 0.05 skeins) on Aida 14".
+pub fn describe_thread_estimates(
+    estimates: &BTreeMap<usize, ThreadEstimate>,
+    fabric: FabricGrid,
+) -> Vec<String> {
+    match estimates.len() {
+        0 => vec![format!("No thread needed on {}", fabric)],
+        1 => estimates
+            .values()
+            .map(|estimate| format!("{} on {}", estimate, fabric))
+            .collect(),
+        _ => estimates
+            .iter()
+            .map(|(colour, estimate)| format!("Colour {}: {} on {}", colour, estimate, fabric))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid_cell::GridCell;
+    use crate::stitch::StartingStitchCorner;
+
+    const AIDA_14: FabricGrid = FabricGrid {
+        fabric: Fabric::Aida14,
+        over: 1,
+    };
+
+    fn _round_float(number: f64) -> f64 {
+        (number * 1000.0).round() / 1000.0
+    }
+
+    #[test]
+    fn test_cell_size_aida_14() {
+        assert_eq!(_round_float(AIDA_14.cell_size_cm()), 0.181);
+    }
+
+    #[test]
+    fn test_cell_size_evenweave_28_matches_aida_14() {
+        assert_eq!(
+            FabricGrid::new(Fabric::Evenweave28, 2).cell_size_cm(),
+            AIDA_14.cell_size_cm()
+        );
+    }
+
+    #[test]
+    fn test_aida_is_always_over_one() {
+        assert_eq!(FabricGrid::new(Fabric::Aida14, 2), AIDA_14);
+        assert_eq!(FabricGrid::new(Fabric::Linen36, 9).threads_per_cell(), 4);
+    }
+
+    #[test]
+    fn test_fabric_display() {
+        assert_eq!(FabricGrid::new(Fabric::Aida16, 2).to_string(), "Aida 16");
+        assert_eq!(
+            FabricGrid::new(Fabric::Evenweave32, 2).to_string(),
+            "Evenweave 32 over 2"
+        );
+        assert_eq!(
+            FabricGrid::new(Fabric::Linen36, 1).to_string(),
+            "Linen 36 over 1"
+        );
+    }
+
+    #[test]
+    fn test_evenweave_travel_matches_aida_of_same_cell_size() {
+        let stitches = HalfStitch::convert_grid_cells(
+            [GridCell::new(0, 0), GridCell::new(3, 1)].iter(),
+            StartingStitchCorner::BottomLeft,
+            StartingStitchCorner::BottomRight,
+        );
+        let evenweave = FabricGrid::new(Fabric::Evenweave28, 2);
+        let on_evenweave = ThreadEstimate::calculate(&[&stitches], evenweave, 2);
+        let on_aida = ThreadEstimate::calculate(&[&stitches], AIDA_14, 2);
+        assert_eq!(
+            _round_float(on_evenweave.back_cm),
+            _round_float(on_aida.back_cm)
+        );
+    }
+
+    #[test]
+    fn test_estimate_empty_sequence() {
+        let result = ThreadEstimate::calculate(&[], AIDA_14, 2);
+        assert_eq!(result.thread_length_cm(), 0.0);
+    }
+
+    #[test]
+    fn test_estimate_single_full_stitch() {
+        let stitches = HalfStitch::convert_grid_cells(
+            [GridCell::new(0, 0), GridCell::new(0, 0)].iter(),
+            StartingStitchCorner::BottomLeft,
+            StartingStitchCorner::BottomRight,
+        );
+        let cell_size = AIDA_14.cell_size_cm();
+        let result = ThreadEstimate::calculate(&[&stitches], AIDA_14, 2);
+        assert_eq!(
+            _round_float(result.front_cm),
+            _round_float(2.0 * SQRT_2 * cell_size)
+        );
+        assert_eq!(_round_float(result.back_cm), _round_float(cell_size));
+        assert_eq!(result.tails_cm, 2.0 * TAIL_LENGTH_CM);
+        assert_eq!(
+            _round_float(result.strand_length_cm()),
+            _round_float(2.0 * result.thread_length_cm())
+        );
+    }
+
+    #[test]
+    fn test_estimate_two_runs_have_no_travel_between() {
+        let stitches = HalfStitch::convert_grid_cells(
+            [GridCell::new(0, 0), GridCell::new(10, 0)].iter(),
+            StartingStitchCorner::BottomLeft,
+            StartingStitchCorner::BottomRight,
+        );
+        let result = ThreadEstimate::calculate(&[&stitches[..1], &stitches[1..]], AIDA_14, 1);
+        assert_eq!(result.back_cm, 0.0);
+        assert_eq!(result.tails_cm, 4.0 * TAIL_LENGTH_CM);
+    }
+
+    #[test]
+    fn test_describe_estimates_by_colour() {
+        let estimate = ThreadEstimate {
+            front_cm: 150.0,
+            back_cm: 0.0,
+            tails_cm: 0.0,
+            strands: 2,
+        };
+        assert_eq!(
+            describe_thread_estimates(&BTreeMap::from([(1, estimate)]), AIDA_14),
+            ["1.50 m of thread (2 strands, 0.06 skeins) on Aida 14"]
+        );
+        assert_eq!(
+            describe_thread_estimates(&BTreeMap::from([(1, estimate), (3, estimate)]), AIDA_14),
+            [
+                "Colour 1: 1.50 m of thread (2 strands, 0.06 skeins) on Aida 14",
+                "Colour 3: 1.50 m of thread (2 strands, 0.06 skeins) on Aida 14"
+            ]
+        );
+        assert_eq!(
+            describe_thread_estimates(&BTreeMap::new(), AIDA_14),
+            ["No thread needed on Aida 14"]
+        );
+    }
+
+    #[test]
+    fn test_estimate_skeins() {
+        let estimate = ThreadEstimate {
+            front_cm: SKEIN_LENGTH_CM,
+            back_cm: 0.0,
+            tails_cm: 0.0,
+            strands: 3,
+        };
+        assert_eq!(estimate.skeins(), 0.5);
+    }
+}